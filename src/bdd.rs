@@ -0,0 +1,419 @@
+use program::*;
+
+use std::hashmap::HashMap;
+use std::vec;
+
+// A reduced ordered binary decision diagram over the 64 bits of a
+// program's input, as an index into a BddManager. Bit 0 is at the top,
+// which keeps the carry chains of plus small.
+pub type Bdd = uint;
+
+pub static FALSE: Bdd = 0;
+pub static TRUE: Bdd = 1;
+
+// A symbolic u64: one BDD per bit, least significant first.
+pub type Word = ~[Bdd];
+
+// the "variable" of the terminals, below every real one
+static TERMINAL_VAR: uint = 64;
+
+static DEFAULT_MAX_NODES: uint = 1 << 20;
+
+pub struct BddManager {
+    // (var, lo, hi): lo is the function when the input bit is 0
+    nodes: ~[(uint, Bdd, Bdd)],
+    unique: HashMap<(uint, Bdd, Bdd), Bdd>,
+    ite_cache: HashMap<(Bdd, Bdd, Bdd), Bdd>,
+    max_nodes: uint,
+    // set once we've run out of nodes; everything built since is junk
+    overflowed: bool,
+}
+
+pub enum Verdict {
+    Equivalent,
+    // an input the programs disagree on
+    Differ(u64),
+    // the BDDs got too big to say
+    Unknown,
+}
+
+impl BddManager {
+    pub fn new() -> BddManager {
+        BddManager::with_max_nodes(DEFAULT_MAX_NODES)
+    }
+
+    pub fn with_max_nodes(max_nodes: uint) -> BddManager {
+        BddManager {
+            nodes: ~[(TERMINAL_VAR, FALSE, FALSE), (TERMINAL_VAR, TRUE, TRUE)],
+            unique: HashMap::new(),
+            ite_cache: HashMap::new(),
+            max_nodes: max_nodes,
+            overflowed: false,
+        }
+    }
+
+    fn var_of(&self, f: Bdd) -> uint {
+        let (var, _, _) = self.nodes[f];
+        var
+    }
+
+    // the cofactors of `f` with respect to `var`
+    fn split(&self, f: Bdd, var: uint) -> (Bdd, Bdd) {
+        let (v, lo, hi) = self.nodes[f];
+        if v == var { (lo, hi) } else { (f, f) }
+    }
+
+    fn mk(&mut self, var: uint, lo: Bdd, hi: Bdd) -> Bdd {
+        if lo == hi {
+            return lo;
+        }
+        match self.unique.find(&(var, lo, hi)) {
+            Some(&f) => return f,
+            None => {}
+        }
+        if self.nodes.len() >= self.max_nodes {
+            self.overflowed = true;
+            return FALSE;
+        }
+        let f = self.nodes.len();
+        self.nodes.push((var, lo, hi));
+        self.unique.insert((var, lo, hi), f);
+        f
+    }
+
+    pub fn var(&mut self, bit: uint) -> Bdd {
+        self.mk(bit, FALSE, TRUE)
+    }
+
+    // if f then g else h, the one operation everything else is built on
+    pub fn ite(&mut self, f: Bdd, g: Bdd, h: Bdd) -> Bdd {
+        if f == TRUE || g == h { return g; }
+        if f == FALSE { return h; }
+        if g == TRUE && h == FALSE { return f; }
+        if self.overflowed { return FALSE; }
+
+        match self.ite_cache.find(&(f, g, h)) {
+            Some(&r) => return r,
+            None => {}
+        }
+
+        let mut var = self.var_of(f);
+        let g_var = self.var_of(g);
+        let h_var = self.var_of(h);
+        if g_var < var { var = g_var; }
+        if h_var < var { var = h_var; }
+
+        let (f0, f1) = self.split(f, var);
+        let (g0, g1) = self.split(g, var);
+        let (h0, h1) = self.split(h, var);
+        let lo = self.ite(f0, g0, h0);
+        let hi = self.ite(f1, g1, h1);
+        let r = self.mk(var, lo, hi);
+
+        self.ite_cache.insert((f, g, h), r);
+        r
+    }
+
+    pub fn not(&mut self, f: Bdd) -> Bdd { self.ite(f, FALSE, TRUE) }
+    pub fn and(&mut self, f: Bdd, g: Bdd) -> Bdd { self.ite(f, g, FALSE) }
+    pub fn or(&mut self, f: Bdd, g: Bdd) -> Bdd { self.ite(f, TRUE, g) }
+    pub fn xor(&mut self, f: Bdd, g: Bdd) -> Bdd {
+        let not_g = self.not(g);
+        self.ite(f, not_g, g)
+    }
+
+    // The value of `f` when the input is `x`.
+    pub fn eval(&self, mut f: Bdd, x: u64) -> bool {
+        while f != FALSE && f != TRUE {
+            let (var, lo, hi) = self.nodes[f];
+            f = if x & (1 << var) == 0 { lo } else { hi };
+        }
+        f == TRUE
+    }
+
+    // Some input making `f` true, if there is one.
+    pub fn satisfy(&self, mut f: Bdd) -> Option<u64> {
+        if f == FALSE {
+            return None;
+        }
+        // in a reduced BDD every node other than FALSE has a path to TRUE
+        let mut x = 0;
+        while f != TRUE {
+            let (var, lo, hi) = self.nodes[f];
+            if lo != FALSE {
+                f = lo;
+            } else {
+                x |= 1 << var;
+                f = hi;
+            }
+        }
+        Some(x)
+    }
+
+    // A mask of the input bits `f` depends on.
+    pub fn support(&self, f: Bdd) -> u64 {
+        let mut seen = HashMap::new();
+        self.support_inner(f, &mut seen)
+    }
+
+    fn support_inner(&self, f: Bdd, seen: &mut HashMap<Bdd, u64>) -> u64 {
+        if f == FALSE || f == TRUE {
+            return 0;
+        }
+        match seen.find(&f) {
+            Some(&mask) => return mask,
+            None => {}
+        }
+        let (var, lo, hi) = self.nodes[f];
+        let mask = (1 << var) | self.support_inner(lo, seen) | self.support_inner(hi, seen);
+        seen.insert(f, mask);
+        mask
+    }
+
+    fn constant(&self, x: u64) -> Word {
+        vec::from_fn(64, |i| if x & (1 << i) == 0 { FALSE } else { TRUE })
+    }
+
+    fn input(&mut self) -> Word {
+        let mut word = ~[];
+        for i in range(0u, 64) {
+            word.push(self.var(i));
+        }
+        word
+    }
+
+    // Each output bit of `p` as a function of the input bits, or None if
+    // that's too big to build.
+    pub fn symbolic_eval(&mut self, p: &Program) -> Option<Word> {
+        let input = self.input();
+        let mut env = ~[(p.id, input)];
+        let word = self.eval_expr(p.expr, &mut env);
+        if self.overflowed { None } else { Some(word) }
+    }
+
+    fn eval_expr(&mut self, expr: &Expr, env: &mut ~[(Id, Word)]) -> Word {
+        match *expr {
+            Zero => self.constant(0),
+            One => self.constant(1),
+            Ident(id) => {
+                for &(var, ref word) in env.rev_iter() {
+                    if var == id {
+                        return word.clone();
+                    }
+                }
+                fail!("ident %s not found", id.to_str())
+            }
+            If0(~ref cond, ~ref then, ~ref els) => {
+                let cond = self.eval_expr(cond, env);
+                let mut nonzero = FALSE;
+                for &b in cond.iter() {
+                    nonzero = self.or(nonzero, b);
+                }
+
+                if nonzero == FALSE {
+                    self.eval_expr(then, env)
+                } else if nonzero == TRUE {
+                    self.eval_expr(els, env)
+                } else {
+                    let then = self.eval_expr(then, env);
+                    let els = self.eval_expr(els, env);
+                    let mut out = ~[];
+                    for i in range(0, 64) {
+                        out.push(self.ite(nonzero, els[i], then[i]));
+                    }
+                    out
+                }
+            }
+            Op1(op, ~ref expr) => {
+                let e = self.eval_expr(expr, env);
+
+                match op {
+                    Not => {
+                        let mut out = ~[];
+                        for &b in e.iter() {
+                            out.push(self.not(b));
+                        }
+                        out
+                    }
+                    Shl1 => shift_left(e, 1),
+                    Shr1 => shift_right(e, 1),
+                    Shr4 => shift_right(e, 4),
+                    Shr16 => shift_right(e, 16),
+                }
+            }
+            Op2(op, ~ref e1, ~ref e2) => {
+                let e1 = self.eval_expr(e1, env);
+                let e2 = self.eval_expr(e2, env);
+                let mut out = ~[];
+
+                match op {
+                    And => for i in range(0, 64) { out.push(self.and(e1[i], e2[i])); },
+                    Or => for i in range(0, 64) { out.push(self.or(e1[i], e2[i])); },
+                    Xor => for i in range(0, 64) { out.push(self.xor(e1[i], e2[i])); },
+                    Plus => {
+                        // ripple carry
+                        let mut carry = FALSE;
+                        for i in range(0, 64) {
+                            let x = self.xor(e1[i], e2[i]);
+                            out.push(self.xor(x, carry));
+                            let both = self.and(e1[i], e2[i]);
+                            let carried = self.and(carry, x);
+                            carry = self.or(both, carried);
+                        }
+                    }
+                }
+                out
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id: next_id, accum_id: accum_id,
+                body: ~ref body
+            } => {
+                // unrolled over the eight bytes
+                let foldee = self.eval_expr(foldee, env);
+                let mut accum = self.eval_expr(init, env);
+
+                for byte in range(0u, 8) {
+                    let mut b = foldee.slice(byte * 8, byte * 8 + 8).to_owned();
+                    b.grow(56, &FALSE);
+
+                    env.push((next_id, b));
+                    env.push((accum_id, accum));
+                    accum = self.eval_expr(body, env);
+                    env.pop();
+                    env.pop();
+                }
+
+                accum
+            }
+        }
+    }
+}
+
+fn shift_left(e: Word, n: uint) -> Word {
+    let mut out = vec::from_elem(n, FALSE);
+    out.push_all(e.slice(0, 64 - n));
+    out
+}
+
+fn shift_right(e: Word, n: uint) -> Word {
+    let mut out = e.slice(n, 64).to_owned();
+    out.grow(n, &FALSE);
+    out
+}
+
+// Do `p1` and `p2` compute the same function, and if not, where do they
+// differ?
+pub fn compare(p1: &Program, p2: &Program) -> Verdict {
+    let mut m = BddManager::new();
+    let (w1, w2) = match (m.symbolic_eval(p1), m.symbolic_eval(p2)) {
+        (Some(w1), Some(w2)) => (w1, w2),
+        _ => return Unknown,
+    };
+
+    // canonical, so equal functions are the same node
+    let mut differ = FALSE;
+    for i in range(0, 64) {
+        let d = m.xor(w1[i], w2[i]);
+        differ = m.or(differ, d);
+    }
+    if m.overflowed {
+        return Unknown;
+    }
+    match m.satisfy(differ) {
+        Some(x) => Differ(x),
+        None => Equivalent,
+    }
+}
+
+// Does bit `out_bit` of the output of `p` depend on bit `in_bit` of its
+// input? None if the program is too big to tell.
+pub fn depends_on(p: &Program, out_bit: uint, in_bit: uint) -> Option<bool> {
+    let mut m = BddManager::new();
+    do m.symbolic_eval(p).map |word| {
+        m.support(word[out_bit]) & (1 << in_bit) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use eval::Eval;
+    use std::rand;
+
+    fn fold_prog() -> Program {
+        Program::new(0, ~Fold {
+            foldee: ~Ident(0),
+            init: ~Op1(Shr4, ~Ident(0)),
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Xor, ~Ident(1), ~Op2(Plus, ~Ident(2), ~Ident(2)))
+        })
+    }
+
+    #[test]
+    fn test_symbolic_eval() {
+        let progs = ~[
+            Program::new(0, ~Op2(Plus, ~Ident(0), ~Op1(Not, ~Op1(Shr1, ~Ident(0))))),
+            Program::new(0, ~If0(~Op2(And, ~Ident(0), ~One), ~Op1(Shr16, ~Ident(0)), ~One)),
+            fold_prog(),
+        ];
+        let mut m = BddManager::new();
+
+        for p in progs.iter() {
+            let word = m.symbolic_eval(p).expect("too big");
+            for _ in range(0, 100) {
+                let x: u64 = rand::random();
+                let mut out = 0;
+                for i in range(0, 64) {
+                    if m.eval(word[i], x) {
+                        out |= 1 << i;
+                    }
+                }
+                assert_eq!(out, p.eval(x));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let double = Program::new(0, ~Op2(Plus, ~Ident(0), ~Ident(0)));
+        let shl1 = Program::new(3, ~Op1(Shl1, ~Ident(3)));
+        match compare(&double, &shl1) {
+            Equivalent => {}
+            _ => fail!("x + x should be shl1 x"),
+        }
+
+        let round_trip = Program::new(0, ~Op1(Shr1, ~Op1(Shl1, ~Ident(0))));
+        let ident = Program::new(0, ~Ident(0));
+        match compare(&round_trip, &ident) {
+            Differ(x) => assert!(round_trip.eval(x) != ident.eval(x)),
+            _ => fail!("shr1 (shl1 x) loses the top bit"),
+        }
+
+        match compare(&fold_prog(), &fold_prog()) {
+            Equivalent => {}
+            _ => fail!("a fold isn't equivalent to itself"),
+        }
+    }
+
+    #[test]
+    fn test_depends_on() {
+        let p = Program::new(0, ~Op1(Shr4, ~Ident(0)));
+        assert_eq!(depends_on(&p, 0, 4), Some(true));
+        assert_eq!(depends_on(&p, 0, 0), Some(false));
+        assert_eq!(depends_on(&p, 60, 63), Some(false));
+
+        // carries flow upwards only
+        let p = Program::new(0, ~Op2(Plus, ~Ident(0), ~One));
+        assert_eq!(depends_on(&p, 10, 3), Some(true));
+        assert_eq!(depends_on(&p, 3, 10), Some(false));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut m = BddManager::with_max_nodes(100);
+        assert!(m.symbolic_eval(&fold_prog()).is_none());
+    }
+}
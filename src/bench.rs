@@ -0,0 +1,236 @@
+// Results of solving a corpus (see corpus.rs) offline, once per PAR value
+// and strategy, and what they add up to.
+
+use gen::Strategy;
+
+use std::io;
+use std::io::WriterUtil;
+use std::path::Path;
+use extra::json::{Json, ToJson};
+use extra::sort;
+use extra::treemap::TreeMap;
+
+// One problem solved (or not) with one setting.
+#[deriving(Clone)]
+pub struct Run {
+    id: ~str,
+    size: u8,
+    par: uint,
+    strategy: Strategy,
+    solved: bool,
+    timed_out: bool,
+    secs: float,
+    // requests made for it
+    evals: uint,
+    guesses: uint,
+}
+
+// Every run with one setting.
+#[deriving(Clone)]
+pub struct Summary {
+    par: uint,
+    strategy: Strategy,
+    problems: uint,
+    solved: uint,
+    timeouts: uint,
+    median_secs: float,
+    p90_secs: float,
+    max_secs: float,
+    evals_per_problem: float,
+    guesses_per_problem: float,
+    // PAR=1's total time with the same strategy over this one's, if
+    // there was a PAR=1 run
+    speedup: Option<float>,
+}
+
+// The `p`th percentile of `sorted`, by nearest rank.
+pub fn percentile(sorted: &[float], p: float) -> float {
+    if sorted.is_empty() {
+        return 0f;
+    }
+    let rank = (p * sorted.len() as float).ceil() as uint;
+    sorted[if rank == 0 { 0 } else { rank - 1 }]
+}
+
+// A summary per setting, in the order they were first run.
+pub fn summarize(runs: &[Run]) -> ~[Summary] {
+    let mut settings: ~[(uint, Strategy)] = ~[];
+    for r in runs.iter() {
+        if !settings.iter().any(|&(par, strategy)| par == r.par && strategy == r.strategy) {
+            settings.push((r.par, r.strategy));
+        }
+    }
+
+    let total_secs = |par: uint, strategy: Strategy| -> float {
+        runs.iter()
+            .filter(|r| r.par == par && r.strategy == strategy)
+            .fold(0f, |sum, r| sum + r.secs)
+    };
+
+    do settings.iter().transform |&(par, strategy)| {
+        let these: ~[&Run] = runs.iter()
+            .filter(|r| r.par == par && r.strategy == strategy)
+            .collect();
+        let n = these.len();
+        let mut times: ~[float] = these.iter().transform(|r| r.secs).collect();
+        sort::quick_sort3(times);
+
+        let has_single = settings.iter().any(|&(p, s)| p == 1 && s == strategy);
+        let total = total_secs(par, strategy);
+        let speedup = if has_single && total > 0f {
+            Some(total_secs(1, strategy) / total)
+        } else {
+            None
+        };
+
+        Summary {
+            par: par,
+            strategy: strategy,
+            problems: n,
+            solved: these.iter().count(|r| r.solved),
+            timeouts: these.iter().count(|r| r.timed_out),
+            median_secs: percentile(times, 0.5),
+            p90_secs: percentile(times, 0.9),
+            max_secs: percentile(times, 1f),
+            evals_per_problem: per_problem(these.iter().fold(0u, |sum, r| sum + r.evals), n),
+            guesses_per_problem: per_problem(these.iter().fold(0u, |sum, r| sum + r.guesses), n),
+            speedup: speedup,
+        }
+    }.collect()
+}
+
+fn per_problem(total: uint, n: uint) -> float {
+    if n == 0 { 0f } else { total as float / n as float }
+}
+
+pub fn report(summaries: &[Summary]) {
+    println("BENCH: par strategy solved/problems timeouts median p90 max evals guesses speedup");
+    for s in summaries.iter() {
+        let speedup = s.speedup.map_default(~"-", |x| fmt!("%.2fx", *x));
+        printfln!("BENCH: %u %s %u/%u %u %.1fs %.1fs %.1fs %.1f %.1f %s",
+                  s.par, s.strategy.to_str(), s.solved, s.problems, s.timeouts,
+                  s.median_secs, s.p90_secs, s.max_secs,
+                  s.evals_per_problem, s.guesses_per_problem, speedup);
+    }
+}
+
+// One line per run.
+pub fn to_csv(runs: &[Run]) -> ~str {
+    let mut csv = ~"id,size,par,strategy,solved,timed_out,secs,evals,guesses\n";
+    for r in runs.iter() {
+        csv.push_str(fmt!("\"%s\",%u,%u,%s,%b,%b,%.3f,%u,%u\n",
+                          r.id, r.size as uint, r.par, r.strategy.to_str(),
+                          r.solved, r.timed_out, r.secs, r.evals, r.guesses));
+    }
+    csv
+}
+
+impl ToJson for Run {
+    fn to_json(&self) -> Json {
+        let mut obj = TreeMap::new();
+        obj.insert(~"id", self.id.to_json());
+        obj.insert(~"size", (self.size as float).to_json());
+        obj.insert(~"par", (self.par as float).to_json());
+        obj.insert(~"strategy", self.strategy.to_str().to_json());
+        obj.insert(~"solved", self.solved.to_json());
+        obj.insert(~"timedOut", self.timed_out.to_json());
+        obj.insert(~"secs", self.secs.to_json());
+        obj.insert(~"evals", (self.evals as float).to_json());
+        obj.insert(~"guesses", (self.guesses as float).to_json());
+        obj.to_json()
+    }
+}
+
+impl ToJson for Summary {
+    fn to_json(&self) -> Json {
+        let mut obj = TreeMap::new();
+        obj.insert(~"par", (self.par as float).to_json());
+        obj.insert(~"strategy", self.strategy.to_str().to_json());
+        obj.insert(~"problems", (self.problems as float).to_json());
+        obj.insert(~"solved", (self.solved as float).to_json());
+        obj.insert(~"timeouts", (self.timeouts as float).to_json());
+        obj.insert(~"medianSecs", self.median_secs.to_json());
+        obj.insert(~"p90Secs", self.p90_secs.to_json());
+        obj.insert(~"maxSecs", self.max_secs.to_json());
+        obj.insert(~"evalsPerProblem", self.evals_per_problem.to_json());
+        obj.insert(~"guessesPerProblem", self.guesses_per_problem.to_json());
+        obj.insert(~"speedup", self.speedup.to_json());
+        obj.to_json()
+    }
+}
+
+// Write PREFIX.csv with every run, and PREFIX.json with the summaries
+// too.
+pub fn save(prefix: &str, runs: &[Run], summaries: &[Summary]) -> Result<(), ~str> {
+    let mut obj = TreeMap::new();
+    obj.insert(~"summaries", summaries.to_owned().to_json());
+    obj.insert(~"runs", runs.to_owned().to_json());
+
+    let files = [(fmt!("%s.csv", prefix), to_csv(runs)),
+                 (fmt!("%s.json", prefix), obj.to_json().to_str())];
+    for &(ref file, ref contents) in files.iter() {
+        match io::file_writer(&Path(file.as_slice()), [io::Create, io::Truncate]) {
+            Ok(w) => w.write_str(contents.as_slice()),
+            Err(e) => return Err(fmt!("%s: %s", *file, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gen::{Strategy, Covering, Uniform};
+
+    fn run(par: uint, strategy: Strategy, solved: bool, secs: float) -> Run {
+        Run {
+            id: ~"p",
+            size: 5,
+            par: par,
+            strategy: strategy,
+            solved: solved,
+            timed_out: !solved,
+            secs: secs,
+            evals: 2,
+            guesses: if solved { 1 } else { 3 },
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let xs = [1f, 2f, 3f, 4f, 5f, 6f, 7f, 8f, 9f, 10f];
+        assert_eq!(percentile(xs, 0.5), 5f);
+        assert_eq!(percentile(xs, 0.9), 9f);
+        assert_eq!(percentile(xs, 1f), 10f);
+        assert_eq!(percentile([], 0.5), 0f);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let runs = ~[
+            run(1, Covering, true, 4f),
+            run(1, Covering, false, 8f),
+            run(4, Covering, true, 1f),
+            run(4, Covering, true, 5f),
+            run(4, Uniform, true, 2f),
+        ];
+        let summaries = summarize(runs);
+        assert_eq!(summaries.len(), 3);
+
+        let s = &summaries[0];
+        assert_eq!((s.par, s.problems, s.solved, s.timeouts), (1, 2, 1, 1));
+        assert_eq!(s.median_secs, 4f);
+        assert_eq!(s.max_secs, 8f);
+        assert_eq!(s.guesses_per_problem, 2f);
+        assert_eq!(s.speedup, Some(1f));
+
+        assert_eq!(summaries[1].speedup, Some(2f));
+        // no single-task run to compare against
+        assert_eq!(summaries[2].strategy, Uniform);
+        assert_eq!(summaries[2].speedup, None);
+
+        let csv = to_csv(runs);
+        let lines: ~[&str] = csv.line_iter().collect();
+        assert_eq!(lines.len(), 6);
+    }
+}
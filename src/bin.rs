@@ -5,26 +5,62 @@
 extern mod std;
 extern mod extra;
 
-use eval::Eval;
+use eval::{Eval, ValidProgram};
 use gen::*;
 use webapi::*;
+use store::Store;
 
+use std::cell::Cell;
+use std::cmp;
+use std::comm;
 use std::hashmap::HashMap;
 use std::io;
 use std::io::WriterUtil;
+use std::num::FromStrRadix;
 use std::os;
 use std::path::Path;
 use std::rand::{Rng, RngUtil};
+use std::task;
 use std::vec;
-use extra::sort;
 use extra::time;
 
+pub mod bitslice;
 pub mod eval;
 pub mod gen;
+pub mod knownbits;
 pub mod parse;
 pub mod program;
 pub mod webapi;
 pub mod compile;
+pub mod dag;
+pub mod bdd;
+pub mod pretty;
+pub mod query;
+pub mod store;
+pub mod ratelimit;
+pub mod session;
+pub mod corpus;
+pub mod bench;
+
+// the server gives each problem this long from the first request about it
+static PROBLEM_TIME_LIMIT: float = 300f;
+// stop generating this long before a problem's deadline, so that there's
+// still time to get a guess in
+static GUESS_RESERVE_NS: u64 = 5_000_000_000;
+// a problem in flight makes about this many requests per request window:
+// an eval and a guess
+static REQUESTS_PER_PROBLEM: float = 2f;
+// and uses about this many seconds of the server's CPU window
+static CPU_PER_PROBLEM: float = 1f;
+// showprobs refetches the problem list once the stored one is this old
+static PROBLEM_LIST_MAX_AGE: float = 60f;
+// how many times to try a request that keeps failing
+static API_TRIES: uint = 3;
+// random inputs evaluated before the first candidate, unless solve says
+// otherwise
+static INITIAL_INPUTS: uint = 50;
+// how wide eval and trace print programs with --indent
+static INDENT_WIDTH: uint = 80;
 
 fn main() {
     let args = os::args();
@@ -61,16 +97,72 @@ fn main() {
             } else {
                 false
             };
-            let prog = {
-                use parse::Parse;
-                if args.len() == 4 {
-                    args[3].parse()
-                } else {
-                    args[2].parse()
+            let src = if args.len() == 4 { args[3].clone() } else { args[2].clone() };
+            match parse::parse_program(src) {
+                Ok(prog) => faketrain(FakeApi::new(~[prog]), Some(bonus)),
+                Err(e) => printfln!("error: bad program: %s", e),
+            }
+        }
+        ~"corpustrain" => {
+            if args.len() < 3 {
+                println("usage: squiggle corpustrain FILE");
+                return;
+            }
+            match corpus::load(&Path(args[2])) {
+                Ok(entries) => faketrain(corpus::fake_api(entries), None),
+                Err(e) => printfln!("error: bad corpus: %s", e),
+            }
+        }
+        ~"bench" => {
+            if args.len() < 3 {
+                println("usage: squiggle bench CORPUS [PARS [STRATEGIES [SECS [OUT]]]]");
+                return;
+            }
+            let entries = match corpus::load(&Path(args[2])) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    printfln!("error: bad corpus: %s", e);
+                    return;
                 }
             };
-
-            faketrain(prog, bonus)
+            let pars: Option<~[uint]> = list_arg(args, 3, "1,2,4");
+            let strategies: Option<~[Strategy]> = list_arg(args, 4, "covering,uniform");
+            let secs: Option<float> = if args.len() > 5 {
+                FromStr::from_str(args[5])
+            } else {
+                Some(PROBLEM_TIME_LIMIT)
+            };
+            let out = if args.len() > 6 { args[6].clone() } else { ~"bench" };
+            match (pars, strategies, secs) {
+                (Some(pars), Some(strategies), Some(secs)) => {
+                    bench(entries, pars, strategies, secs, out)
+                }
+                (None, _, _) => println("error: bad PAR values"),
+                (_, None, _) => println("error: bad strategies"),
+                (_, _, None) => println("error: bad time limit"),
+            }
+        }
+        ~"solve" => {
+            if args.len() < 3 {
+                println("usage: squiggle solve PROBLEM_ID [--strategy STRATEGY] [--par N] \
+                         [--timeout SECS] [--inputs N] [--max-guesses N]");
+                return;
+            }
+            match SolveOptions::parse(args.slice_from(3)) {
+                Ok(opts) => solve(args[2], opts),
+                Err(e) => printfln!("error: %s", e),
+            }
+        }
+        ~"gencorpus" => {
+            if args.len() < 4 {
+                println("usage: squiggle gencorpus COUNT FILE");
+                return;
+            }
+            let count: Option<uint> = FromStr::from_str(args[2]);
+            match count {
+                Some(n) => gencorpus(n, &Path(args[3])),
+                None => println("error: bad count"),
+            }
         }
         ~"localtrain" => {
             if args.len() < 3 {
@@ -99,47 +191,118 @@ fn main() {
             } else {
                 1_000_000_000 // run forever
             };
-            let filter = if args.len() >= 4 {
-                match args[3] {
-                    ~"fold" => Folded,
-                    ~"tfold" => Tfolded,
-                    ~"all" => All,
-                    ~"unfold" => Unfolded,
-                    ~"bonus" => Bonus,
-                    ~"nobonus" => NoBonus,
-                    ~"noif0" => NoIf0,
-                    ~"foldnoif0" => FoldNoIf0,
-                    ~"tfoldnoif0" => TfoldNoIf0,
-                    _ => {
-                        println("error: bad filter value, using no filter");
-                        All
-                    }
-                }
-            } else {
-                All
-            };
+            let filter = query_arg(args, 3);
             let min_size = if args.len() >= 5 {
                 FromStr::from_str(args[4]).expect("bad min size")
             } else {
                 0
             };
-            problems(count, filter, min_size)
+            let sort_keys = sort_arg(args, 5, ~[(query::BySize, false)]);
+            problems(count, filter, min_size, sort_keys)
+        }
+        ~"showprobs" => {
+            let filter = query_arg(args, 2);
+            let sort_keys = sort_arg(args, 3, ~[(query::BySize, false)]);
+            show_problems(filter, sort_keys)
         }
-        ~"showprobs" => show_problems(),
         ~"eval" => {
-            let prog = {
-                use parse::Parse;
-                args[2].parse()
-            };
-            eval(prog);
+            let (layout, args) = layout_arg(args);
+            if args.len() < 3 {
+                println("usage: squiggle eval [--indent] PROGRAM");
+                return;
+            }
+            match parse::parse_program_names(args[2]) {
+                Ok((prog, names)) => eval(prog, pretty::Printer::with_names(names, layout)),
+                Err(e) => printfln!("error: bad program: %s", e),
+            }
+        }
+        ~"trace" => {
+            let (layout, args) = layout_arg(args);
+            if args.len() < 4 {
+                println("usage: squiggle trace [--indent] PROGRAM INPUT");
+                return;
+            }
+            match (parse::parse_program_names(args[2]), parse_u64(args[3])) {
+                (Ok((prog, names)), Some(input)) => {
+                    trace(prog, pretty::Printer::with_names(names, layout), input)
+                }
+                (Err(e), _) => printfln!("error: bad program: %s", e),
+                (_, None) => println("error: bad input"),
+            }
         }
         _ => println("error: unknown command"),
     }
 }
 
+// A problem query (see query.rs) from args[n], if it's there.
+fn query_arg(args: &[~str], n: uint) -> query::Query {
+    if args.len() <= n {
+        return query::QTrue;
+    }
+    match query::Query::parse(args[n]) {
+        Ok(q) => q,
+        Err(e) => {
+            printfln!("error: bad filter value (%s), using no filter", e);
+            query::QTrue
+        }
+    }
+}
+
+fn sort_arg(args: &[~str], n: uint,
+            default: ~[(query::SortKey, bool)]) -> ~[(query::SortKey, bool)] {
+    if args.len() <= n {
+        return default;
+    }
+    match query::parse_sort(args[n]) {
+        Ok(keys) => keys,
+        Err(e) => {
+            printfln!("error: bad sort keys (%s), using the default", e);
+            default
+        }
+    }
+}
+
+// A comma-separated list from args[n], or from `default` if it isn't
+// there.
+fn list_arg<T: FromStr>(args: &[~str], n: uint, default: &str) -> Option<~[T]> {
+    let s = if args.len() > n { args[n].as_slice() } else { default };
+    let mut items = ~[];
+    for item in s.split_iter(',') {
+        match FromStr::from_str(item) {
+            Some(x) => items.push(x),
+            None => return None,
+        }
+    }
+    Some(items)
+}
+
+// Takes out an --indent flag: programs are printed indented to fit
+// INDENT_WIDTH columns if it's there, and on one line if not.
+fn layout_arg(args: &[~str]) -> (pretty::Layout, ~[~str]) {
+    let rest: ~[~str] = args.iter().filter(|a| a.as_slice() != "--indent")
+        .transform(|a| a.clone()).collect();
+    let layout = if rest.len() < args.len() {
+        pretty::Indented(INDENT_WIDTH)
+    } else {
+        pretty::Compact
+    };
+    (layout, rest)
+}
+
+// Decimal, or hex with a leading 0x.
+fn parse_u64(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        FromStrRadix::from_str_radix(s.slice_from(2), 16)
+    } else {
+        FromStr::from_str(s)
+    }
+}
+
 fn status() {
-    let status = WebApi::new().get_status_blocking();
-    printfln!("%?", status);
+    match WebApi::new().get_status_blocking() {
+        Ok(status) => printfln!("%?", status),
+        Err(e) => printfln!("error: %s", e.to_str()),
+    }
 }
 
 fn train(size: u8, operator: TrainOperator, local: bool) {
@@ -147,9 +310,16 @@ fn train(size: u8, operator: TrainOperator, local: bool) {
     let mut local_api = FakeApi::new(~[]);
     let mut stats = Statistics::new();
     let mut gen = RandomGen::blank();
+    let store = Store::open();
 
     loop {
-        let prob = api.get_training_blocking(size, operator);
+        let prob = match api.get_training_blocking(size, operator) {
+            Ok(prob) => prob,
+            Err(e) => {
+                printfln!("error: couldn't get a training problem: %s", e.to_str());
+                loop;
+            }
+        };
         let mut s = ~"";
         s.push_str(fmt!("TRAIN: -- %u -- %s -- %s\n",
                         prob.problem.size as uint,
@@ -162,104 +332,454 @@ fn train(size: u8, operator: TrainOperator, local: bool) {
 
         if local {
             println("solving locally");
-            local_api.add_prog(prob.problem.id, {
-                use parse::Parse;
-                prob.challenge.parse()
-            });
-            solve_problem(prob.problem, &mut local_api, &mut stats, &mut gen);
+            match parse::parse_program(prob.challenge) {
+                Ok(prog) => local_api.add_prog(prob.problem.id, prog),
+                Err(e) => {
+                    printfln!("error: bad challenge: %s", e);
+                    loop;
+                }
+            }
+            let deadline = api_deadline(&local_api, PROBLEM_TIME_LIMIT);
+            solve_problem(prob.problem, deadline,
+                          &mut local_api, &mut stats, &mut gen, &store, &Limits::new());
         } else {
             println("solving remotely");
-            solve_problem(prob.problem, &mut api, &mut stats, &mut gen);
+            solve_problem(prob.problem, Deadline::in_secs(PROBLEM_TIME_LIMIT),
+                          &mut api, &mut stats, &mut gen, &store, &Limits::new());
         }
     }
 }
 
-fn faketrain(progs: program::Program, bonus: bool) {
-    let mut api = FakeApi::new(~[progs]);
+// Solve all of `api`'s training problems, with or without bonus if
+// `bonus` says which, otherwise as they're listed.
+fn faketrain(mut api: FakeApi, bonus: Option<bool>) {
     let mut stats = Statistics::new();
     let mut gen = RandomGen::blank();
+    let store = Store::open();
 
     while api.has_programs() {
         // the args are ignored anyway
-        let mut prob = api.get_training_blocking(0, Empty);
-        prob.problem.operators.bonus = bonus;
+        let mut prob = api.get_training_blocking(0, Empty).unwrap();
+        match bonus {
+            Some(true) => prob.problem.operators.insert(program::OpBonus),
+            Some(false) => prob.problem.operators.remove(program::OpBonus),
+            None => {}
+        }
 
         printfln!("FAKETRAIN: -- %u -- %s -- %s",
                   prob.problem.size as uint,
                   prob.problem.operators.to_str(),
                   prob.problem.id);
 
-        solve_problem(prob.problem, &mut api, &mut stats, &mut gen);
+        let deadline = api_deadline(&api, PROBLEM_TIME_LIMIT);
+        solve_problem(prob.problem, deadline,
+                      &mut api, &mut stats, &mut gen, &store, &Limits::new());
+    }
+    printfln!("FAKETRAIN: %s", api.stats().to_str());
+}
+
+// Solve the whole corpus once per PAR value and strategy, each time
+// through a fresh FakeApi, giving each problem `secs`.
+fn bench(entries: ~[corpus::Entry], pars: ~[uint], strategies: ~[Strategy], secs: float,
+         out: &str) {
+    let store = Store::open();
+    let mut runs = ~[];
+
+    for &strategy in strategies.iter() {
+        for &par in pars.iter() {
+            let mut config = GenConfig::from_env();
+            config.parallelism = par;
+            config.strategy = strategy;
+            let mut gen = RandomGen::blank_with(config);
+            let mut stats = Statistics::new();
+            let mut api = corpus::fake_api(entries);
+
+            while api.has_programs() {
+                let prob = api.get_training_blocking(0, Empty).unwrap();
+                printfln!("BENCH: -- PAR %u -- %s -- %u -- %s",
+                          par, strategy.to_str(), prob.problem.size as uint, prob.problem.id);
+
+                // each problem gets the whole window, so that its time
+                // doesn't depend on what ran before it
+                api.fresh_window();
+                let before = api.stats();
+                // on the api's clock, so that time skipped while throttled
+                // counts, as it does towards the problem's deadline
+                let start_ns = api.now_ns();
+                let outcome = solve_problem(prob.problem.clone(), api_deadline(&api, secs),
+                                            &mut api, &mut stats, &mut gen, &store,
+                                            &Limits::new());
+                let elapsed_ns = api.now_ns() - start_ns;
+                let after = api.stats();
+
+                runs.push(bench::Run {
+                    id: prob.problem.id,
+                    size: prob.problem.size,
+                    par: par,
+                    strategy: strategy,
+                    solved: outcome == Solved,
+                    timed_out: outcome == OutOfTime,
+                    secs: elapsed_ns as float / 1_000_000_000f,
+                    evals: after.evals - before.evals,
+                    guesses: after.guesses - before.guesses,
+                });
+            }
+        }
+    }
+
+    let summaries = bench::summarize(runs);
+    bench::report(summaries);
+    match bench::save(out, runs, summaries) {
+        Ok(()) => printfln!("wrote %s.csv and %s.json", out, out),
+        Err(e) => printfln!("error: %s", e),
+    }
+}
+
+// The options to solve, after the problem id.
+struct SolveOptions {
+    config: GenConfig,
+    // give up after this long, if that's sooner than the server's deadline
+    timeout: Option<float>,
+    limits: Limits,
+}
+
+impl SolveOptions {
+    pub fn parse(args: &[~str]) -> Result<SolveOptions, ~str> {
+        let mut opts = SolveOptions {
+            config: GenConfig::from_env(),
+            timeout: None,
+            limits: Limits::new(),
+        };
+        let mut i = 0;
+        while i < args.len() {
+            if i + 1 == args.len() {
+                return Err(fmt!("%s needs a value", args[i]));
+            }
+            let value = args[i + 1].as_slice();
+            match args[i].as_slice() {
+                "--strategy" => {
+                    let strategy: Option<Strategy> = FromStr::from_str(value);
+                    match strategy {
+                        Some(s) => opts.config.strategy = s,
+                        None => return Err(fmt!("bad strategy %s", value)),
+                    }
+                }
+                "--par" => {
+                    let par: Option<uint> = FromStr::from_str(value);
+                    match par {
+                        Some(n) if n > 0 => opts.config.parallelism = n,
+                        _ => return Err(fmt!("bad parallelism %s", value)),
+                    }
+                }
+                "--timeout" => {
+                    let secs: Option<float> = FromStr::from_str(value);
+                    // no problem lasts longer, and anything that isn't a
+                    // positive number of seconds makes no deadline at all
+                    match secs {
+                        Some(secs) if secs > 0f && secs <= PROBLEM_TIME_LIMIT => {
+                            opts.timeout = Some(secs)
+                        }
+                        _ => return Err(fmt!("bad timeout %s", value)),
+                    }
+                }
+                "--inputs" => {
+                    let n: Option<uint> = FromStr::from_str(value);
+                    // with none there'd be nothing to check candidates on
+                    match n {
+                        Some(n) if n > 0 => opts.limits.inputs = n,
+                        _ => return Err(fmt!("bad input count %s", value)),
+                    }
+                }
+                "--max-guesses" => {
+                    let n: Option<uint> = FromStr::from_str(value);
+                    match n {
+                        Some(n) => opts.limits.max_guesses = Some(n),
+                        None => return Err(fmt!("bad guess count %s", value)),
+                    }
+                }
+                other => return Err(fmt!("unknown option %s", other)),
+            }
+            i += 2;
+        }
+        Ok(opts)
     }
 }
 
-fn problems(count: uint, filter: ProblemFilter, min_size: u8) {
+// Solve the one problem `id`, found in the problem list or, failing
+// that, in the store.
+fn solve(id: &str, opts: SolveOptions) {
+    let store = Store::open();
     let mut api = WebApi::new();
+
+    let listed = match recent_problems(&store, &mut api) {
+        Ok((probs, fetched_ns)) => {
+            match probs.iter().find_(|p| p.problem.id.as_slice() == id) {
+                Some(p) => Some((p.clone(), fetched_ns)),
+                None => None,
+            }
+        }
+        Err(e) => {
+            printfln!("warning: couldn't get the problem list: %s", e.to_str());
+            None
+        }
+    };
+    let (problem, deadline) = match listed {
+        Some((p, fetched_ns)) => {
+            if p.solved {
+                printfln!("%s is already solved", id);
+                return;
+            }
+            if p.time_left.map_default(false, |&secs| secs <= 0f) {
+                printfln!("%s has run out of time", id);
+                return;
+            }
+            (p.problem.clone(), problem_deadline(p.time_left, fetched_ns))
+        }
+        None => match store.load(id) {
+            // an earlier run knew it, though the list doesn't (yet)
+            Some(record) => (record.problem, Deadline::in_secs(PROBLEM_TIME_LIMIT)),
+            None => {
+                printfln!("error: unknown problem %s", id);
+                return;
+            }
+        },
+    };
+    let deadline = match opts.timeout {
+        Some(secs) => deadline.min(&Deadline::in_secs(secs)),
+        None => deadline,
+    };
+
+    printfln!("SOLVE: -- %u -- %s -- %s -- PAR %u -- %s",
+              problem.size as uint,
+              problem.operators.to_str(),
+              problem.id,
+              opts.config.parallelism,
+              opts.config.strategy.to_str());
+    let mut gen = RandomGen::blank_with(opts.config.clone());
     let mut stats = Statistics::new();
-    let mut gen = RandomGen::blank();
+    solve_problem(problem, deadline, &mut api, &mut stats, &mut gen, &store, &opts.limits);
+}
+
+fn gencorpus(count: uint, file: &Path) {
+    let entries = corpus::generate(count);
+    match corpus::save(file, entries) {
+        Ok(()) => printfln!("wrote %u problems to %s", count, file.to_str()),
+        Err(e) => printfln!("error: %s", e),
+    }
+}
+
+fn problems(count: uint, filter: query::Query, min_size: u8,
+            sort_keys: ~[(query::SortKey, bool)]) {
+    let mut api = WebApi::new();
+    let status = match api.get_status_blocking() {
+        Ok(status) => status,
+        Err(e) => {
+            printfln!("error: couldn't get the status: %s", e.to_str());
+            return;
+        }
+    };
+    let probs = match api.get_problems_blocking() {
+        Ok(probs) => probs,
+        Err(e) => {
+            printfln!("error: couldn't get the problems: %s", e.to_str());
+            return;
+        }
+    };
+    let fetched_ns = time::precise_time_ns();
+    Store::open().save_problem_list(probs);
+    // Problems an earlier run started have their clocks running, so they go
+    // first whatever the filter says, the closest to running out leading.
+    // Their stored constraints are picked up in solve_problem.
+    let (mut in_progress, rest) = probs.partition(|p| query::QInProgress.matches(p));
+    query::sort_problems(in_progress, [(query::ByTimeLeft, false)]);
+    for p in in_progress.iter() {
+        printfln!("resuming %s, %.1fs left", p.problem.id, p.time_left.unwrap_or_default(0f));
+    }
 
-    let probs = api.get_problems_blocking();
     // TODO filter problems by train operator.
-    let mut unsolved_probs: ~[RealProblem] = probs.consume_iter()
+    let mut unsolved_probs: ~[RealProblem] = rest.consume_iter()
         .filter(|p| !p.solved && p.time_left.map_default(true, |&n| n > 0.0))
-        .filter(|p| match filter {
-            All => true,
-            Tfolded => p.problem.operators.tfold,
-            Unfolded => (!p.problem.operators.fold &&
-                         !p.problem.operators.tfold &&
-                         !p.problem.operators.bonus),
-            Folded => p.problem.operators.fold,
-            Bonus => p.problem.operators.bonus,
-            NoBonus => !p.problem.operators.bonus,
-            NoIf0 => !p.problem.operators.if0,
-            FoldNoIf0 => !p.problem.operators.if0 && p.problem.operators.fold,
-            TfoldNoIf0 => !p.problem.operators.if0 && p.problem.operators.tfold,
-        })
+        .filter(|p| filter.matches(p))
         .filter(|p| p.problem.size >= min_size)
         .collect();
-    sort::tim_sort(unsolved_probs);
+    query::sort_problems(unsolved_probs, sort_keys);
+
+    let todo = in_progress.consume_iter().chain_(unsolved_probs.consume_iter())
+        .take_(count).collect();
+    solve_concurrently(todo, fetched_ns, api, concurrency_limit(&status));
+}
+
+// How many problems to have in flight at once: CONCURRENT (default 1),
+// but no more than what's left of the request and CPU windows has room
+// for.
+fn concurrency_limit(status: &StatusResponse) -> uint {
+    let wanted: uint = do os::getenv("CONCURRENT").chain |s| {
+        FromStr::from_str(s)
+    }.unwrap_or_default(1);
+    let requests = (remaining(&status.request_window) / REQUESTS_PER_PROBLEM) as uint;
+    let cpu = (remaining(&status.cpu_window) / CPU_PER_PROBLEM) as uint;
+
+    let limit = cmp::min(wanted, cmp::min(requests, cpu));
+    if limit == 0 { 1 } else { limit }
+}
+
+fn remaining(window: &Window) -> float {
+    if window.amount < window.limit { window.limit - window.amount } else { 0f }
+}
+
+// Solve `probs` in order, up to `limit` at a time, each with its own
+// generator; the requests all go through `api`, and so through its one
+// request window.
+fn solve_concurrently(probs: ~[RealProblem], fetched_ns: u64, api: WebApi, limit: uint) {
+    let (done_port, done_chan) = comm::stream();
+    let done_chan = comm::SharedChan::new(done_chan);
+    let mut in_flight = 0u;
+
+    for prob in probs.consume_iter() {
+        if in_flight >= limit {
+            let _: ~str = done_port.recv();
+            in_flight -= 1;
+        }
 
-    for prob in unsolved_probs.consume_iter().take_(count) {
         printfln!("PROBLEM: -- %u -- %s -- %s",
                   prob.problem.size as uint,
                   prob.problem.operators.to_str(),
                   prob.problem.id);
 
-        solve_problem(prob.problem, &mut api, &mut stats, &mut gen);
+        let deadline = problem_deadline(prob.time_left, fetched_ns);
+        let task_args = Cell::new((prob.problem, deadline, api.clone()));
+        let task_done = done_chan.clone();
+        do task::spawn {
+            let (problem, deadline, api) = task_args.take();
+            let id = problem.id.clone();
+
+            // one problem going wrong shouldn't take the others with it
+            let args = Cell::new((problem, deadline, api));
+            let result = do task::try {
+                let (problem, deadline, mut api) = args.take();
+                let mut stats = Statistics::new();
+                let mut gen = RandomGen::blank();
+                let store = Store::open();
+                solve_problem(problem, deadline, &mut api, &mut stats, &mut gen, &store,
+                              &Limits::new());
+            };
+            if result.is_err() {
+                printfln!("error: solving %s failed", id);
+            }
+            task_done.send(id);
+        }
+        in_flight += 1;
+    }
+
+    while in_flight > 0 {
+        let _: ~str = done_port.recv();
+        in_flight -= 1;
+    }
+}
+
+// When a problem listed at `fetched_ns` has to be solved by.
+// `secs` from now on `api`'s clock, which solve_problem's deadline is on.
+fn api_deadline<A: Api>(api: &A, secs: float) -> Deadline {
+    Deadline::at_ns(api.now_ns() + (secs * 1_000_000_000f) as u64)
+}
+
+fn problem_deadline(time_left: Option<float>, fetched_ns: u64) -> Deadline {
+    match time_left {
+        // already started, and the clock has been running since
+        Some(secs) => Deadline::at_ns(fetched_ns + (secs * 1_000_000_000f) as u64),
+        // starts when we first ask about it
+        None => Deadline::in_secs(PROBLEM_TIME_LIMIT),
+    }
+}
+
+// How solving a problem went.
+#[deriving(Eq)]
+enum Outcome {
+    Solved,
+    // ran out of time without a right guess
+    OutOfTime,
+    // made as many guesses as we were allowed
+    OutOfGuesses,
+    // the server wouldn't answer for it any more
+    GaveUp,
+}
+
+// How much solve_problem may spend on a problem, besides time.
+struct Limits {
+    // random inputs to evaluate before the first candidate
+    inputs: uint,
+    max_guesses: Option<uint>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            inputs: INITIAL_INPUTS,
+            max_guesses: None,
+        }
+    }
+
+    pub fn allows_guess(&self, made: uint) -> bool {
+        self.max_guesses.map_default(true, |&max| made < max)
     }
 }
 
-fn solve_problem<A: Api>(problem: Problem, api: &mut A, stats: &mut Statistics,
-                         gen: &mut RandomGen) {
-    let pairs = fetch_n_random_testcases(problem.clone(), 50, api);
+// `deadline` is on `api`'s clock (see Api::now_ns).
+fn solve_problem<A: Api>(problem: Problem, deadline: Deadline, api: &mut A,
+                         stats: &mut Statistics, gen: &mut RandomGen, store: &Store,
+                         limits: &Limits) -> Outcome {
+    // pick up from whatever an earlier run learned about it
+    let record = store.load_or_new(&problem);
+    match record.solution {
+        Some(ref s) => {
+            printfln!("already solved: %s", *s);
+            return Solved;
+        }
+        None => {}
+    }
+
+    let mut pairs = record.pairs.clone();
+    if pairs.len() < limits.inputs {
+        let wanted = limits.inputs - pairs.len();
+        let fetched = match fetch_n_random_testcases(problem.clone(), wanted, api) {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                println("giving up on this problem");
+                return refusal_outcome(&e);
+            }
+        };
+        store.add_pairs(&problem, fetched);
+        pairs.push_all(fetched);
+    } else {
+        printfln!("resuming with %u stored pairs", pairs.len());
+    }
 
     stats.start();
     gen.reset(problem.clone(), pairs);
 
+    let mut outcome = OutOfTime;
+    let mut guesses = 0u;
     loop {
-        match gen.next() {
-            Some(candidate) => {
-                println(candidate.to_str());
-                info!(candidate);
-                match api.guess_blocking(problem.clone(), candidate.to_str()) {
-                    Win => {
-                        println("win!");
+        let gen_deadline = deadline.on_clock(api.now_ns()).less_ns(GUESS_RESERVE_NS);
+        match gen.next(gen_deadline) {
+            Found(candidate) => {
+                if !limits.allows_guess(guesses) {
+                    println("Out of guesses :(");
+                    outcome = OutOfGuesses;
+                    break
+                }
+                guesses += 1;
+                match guess(&problem, candidate, api, gen, store) {
+                    Some(o) => {
+                        outcome = o;
                         break
                     }
-                    Mismatch(input, real, ours) => {
-                        printfln!("P(%?) == %? != %?", input, real, ours);
-
-                        let mut pairs = fetch_n_random_testcases(problem.clone(), 50, api);
-                        pairs.push((input, real));
-
-                        gen.more_constraints(pairs);
-                    }
-                    Error(s) => {
-                        printfln!("Error occured: %s", s);
-                    }
+                    None => {}
                 }
             }
-            None => {
+            TimedOut => {
+                // the generator only gives up at the deadline, and a near
+                // miss would fail a pair we already have, so that's it
                 println("Timed out :(");
                 break
             }
@@ -268,12 +788,104 @@ fn solve_problem<A: Api>(problem: Problem, api: &mut A, stats: &mut Statistics,
 
     stats.end();
     stats.report();
+    outcome
 }
 
-fn show_problems() {
-    let mut api = WebApi::new();
-    let mut probs: ~[RealProblem] = api.get_problems_blocking().consume_iter().collect();
-    sort::tim_sort(probs);
+// Submit a candidate, feeding any counterexample back to the generator.
+// Returns how the problem went if that's the end of it: it was right, or
+// the server won't take guesses for it any more.
+fn guess<A: Api>(problem: &Problem, candidate: ~program::Program, api: &mut A,
+                 gen: &mut RandomGen, store: &Store) -> Option<Outcome> {
+    println(candidate.to_str());
+    info!(candidate);
+    let result = match api.guess_blocking(problem.clone(), candidate.to_str()) {
+        Ok(result) => result,
+        Err(e) => {
+            // no verdict, so it may be worth guessing again
+            printfln!("error: guess failed: %s", e.to_str());
+            return if e.is_transient() { None } else { Some(refusal_outcome(&e)) };
+        }
+    };
+    store.add_guess(problem, candidate.to_str(), &result);
+    match result {
+        Win => {
+            println("win!");
+            Some(Solved)
+        }
+        Mismatch(input, real, ours) => {
+            printfln!("P(%?) == %? != %?", input, real, ours);
+
+            // the counterexample is worth having even if nothing else is
+            let mut pairs = match fetch_n_random_testcases(problem.clone(), 50, api) {
+                Ok(pairs) => pairs,
+                Err(_) => ~[],
+            };
+            pairs.push((input, real));
+            store.add_pairs(problem, pairs);
+
+            gen.more_constraints(pairs);
+            None
+        }
+        Error(s) => {
+            printfln!("Error occured: %s", s);
+            None
+        }
+    }
+}
+
+// The problem list, from the store if it's recent enough, and when it
+// was fetched.
+fn recent_problems(store: &Store, api: &mut WebApi) -> ApiResult<(~[RealProblem], u64)> {
+    match stored_problems(store) {
+        Some(listed) => Ok(listed),
+        None => fetch_problems(store, api),
+    }
+}
+
+// The stored problem list and when it was fetched, unless it's too old.
+fn stored_problems(store: &Store) -> Option<(~[RealProblem], u64)> {
+    match store.load_problem_list() {
+        Some((age, probs)) => {
+            if age < PROBLEM_LIST_MAX_AGE {
+                let now = time::precise_time_ns();
+                Some((probs, now - (age * 1_000_000_000f) as u64))
+            } else {
+                None
+            }
+        }
+        None => None,
+    }
+}
+
+fn fetch_problems(store: &Store, api: &mut WebApi) -> ApiResult<(~[RealProblem], u64)> {
+    let now = time::precise_time_ns();
+    match api.get_problems_blocking() {
+        Ok(probs) => {
+            store.save_problem_list(probs);
+            Ok((probs, now))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn show_problems(filter: query::Query, sort_keys: ~[(query::SortKey, bool)]) {
+    let store = Store::open();
+    // only start the request task if the stored list won't do
+    let listed = match stored_problems(&store) {
+        Some(listed) => Ok(listed),
+        None => fetch_problems(&store, &mut WebApi::new()),
+    };
+    let probs = match listed {
+        Ok((probs, _)) => probs,
+        Err(e) => {
+            printfln!("error: %s", e.to_str());
+            return;
+        }
+    };
+    let mut probs: ~[RealProblem] = probs.consume_iter()
+        .filter(|p| filter.matches(p))
+        .collect();
+    query::sort_problems(probs, sort_keys);
 
     let mut stats = HashMap::new::<uint,uint>();
     let mut failed = 0u;
@@ -281,17 +893,17 @@ fn show_problems() {
     let mut total = 0u;
 
     for prob in probs.iter() {
-        let status = match (prob.solved, prob.time_left) {
-            (true, _) => {
-                solved += 1;
-                "SOLVED"
-            }
-            (false, None) => "UNSOLVED",
-            (false, Some(0f)) => {
-                failed += 1;
-                "FAILED"
-            }
-            (false, Some(_)) => "IN PROGRESS",
+        // failed the same way `showprobs failed` means it
+        let status = if prob.solved {
+            solved += 1;
+            "SOLVED"
+        } else if query::QFailed.matches(prob) {
+            failed += 1;
+            "FAILED"
+        } else if prob.time_left.is_none() {
+            "UNSOLVED"
+        } else {
+            "IN PROGRESS"
         };
         total += 1;
         do stats.insert_or_update_with(prob.problem.size as uint, 1) |_, v| { *v += 1; };
@@ -308,22 +920,28 @@ fn show_problems() {
         printfln!("\tsize %u: %u", *k, *v);
     }
 
-
+    // a filter can leave nothing to take a percentage of
+    let percent = |n: uint| if total == 0 { 0 } else { n * 100 / total };
     printfln!("STATS: %u (%u%%) solved -- %u (%u%%) failed",
-              solved, ((solved as float) / (total as float) * 100f) as uint,
-              failed, ((failed as float) / (total as float) * 100f) as uint);
+              solved, percent(solved), failed, percent(failed));
 }
 
-fn eval(program: program::Program) {
+fn eval(program: ValidProgram, mut printer: pretty::Printer) {
     let mut api = WebApi::new();
 
-    printfln!("EVAL: -- %s", program.to_str());
+    printfln!("EVAL: -- %s", printer.print(program.get_ref()));
 
 
     let mut rng = std::rand::task_rng();
     let inputs: ~[u64] = std::vec::from_fn(50, |_| rng.gen());
     let local_outputs: ~[u64] = inputs.iter().transform(|&x| program.eval(x)).collect();
-    let remote_outputs = api.eval_program_blocking(program, inputs.clone()).unwrap();
+    let remote_outputs = match api.eval_program_blocking(program.unwrap(), inputs.clone()) {
+        Ok(outs) => outs,
+        Err(e) => {
+            printfln!("error: %s", e.to_str());
+            return;
+        }
+    };
 
     let mut all_match = true;
     for i in range(0, local_outputs.len()) {
@@ -342,16 +960,9 @@ fn eval(program: program::Program) {
     }
 }
 
-enum ProblemFilter {
-    All,
-    Tfolded,
-    Unfolded,
-    Folded,
-    Bonus,
-    NoBonus,
-    NoIf0,
-    FoldNoIf0,
-    TfoldNoIf0,
+fn trace(program: ValidProgram, mut printer: pretty::Printer, input: u64) {
+    printfln!("TRACE: -- %s", printer.print(program.get_ref()));
+    print(eval::trace(program.get_ref(), input).render(&mut printer));
 }
 
 struct Statistics {
@@ -398,11 +1009,78 @@ impl Statistics {
     }
 }
 
-fn fetch_n_random_testcases<A: Api>(p: Problem, n: uint, api: &mut A) -> ~[(u64, u64)] {
-    let mut rng = std::rand::task_rng();
-    let tests = std::vec::from_fn(n, |_| rng.gen());
+// The last error if the server still won't give them after a few tries,
+// or won't ever.
+fn fetch_n_random_testcases<A: Api>(p: Problem, n: uint,
+                                    api: &mut A) -> Result<~[(u64, u64)], ApiError> {
+    let mut tries = 0;
+    loop {
+        match api.random_testcases_blocking(p.clone(), n) {
+            Ok(pairs) => return Ok(pairs),
+            Err(e) => {
+                printfln!("error: couldn't eval tests: %s", e.to_str());
+                tries += 1;
+                if !e.is_transient() || tries == API_TRIES {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
 
-    let constraints = api.eval_blocking(p, tests.clone()).expect("coulnd't eval tests");
+// How a problem ends when the server won't answer for it any more: if
+// that's because its time is up, it ran out of time like any other.
+fn refusal_outcome(e: &ApiError) -> Outcome {
+    if e.is_expired() { OutOfTime } else { GaveUp }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SolveOptions, INITIAL_INPUTS, INDENT_WIDTH, layout_arg};
+    use gen::{Covering, Uniform};
+    use pretty::{Compact, Indented};
+
+    fn args(s: &str) -> ~[~str] {
+        s.split_iter(' ').filter(|a| !a.is_empty()).transform(|a| a.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_solve_options() {
+        let opts = SolveOptions::parse(args("")).unwrap();
+        assert_eq!(opts.timeout, None);
+        assert_eq!(opts.limits.inputs, INITIAL_INPUTS);
+        assert_eq!(opts.limits.max_guesses, None);
+
+        let all = ~"--strategy uniform --par 4 --timeout 30.5 " + "--inputs 256 --max-guesses 3";
+        let opts = SolveOptions::parse(args(all)).unwrap();
+        assert_eq!(opts.config.strategy, Uniform);
+        assert_eq!(opts.config.parallelism, 4);
+        assert_eq!(opts.timeout, Some(30.5f));
+        assert_eq!(opts.limits.inputs, 256);
+        assert_eq!(opts.limits.max_guesses, Some(3));
 
-    tests.consume_iter().zip(constraints.consume_iter()).collect()
+        let opts = SolveOptions::parse(args("--strategy covering")).unwrap();
+        assert_eq!(opts.config.strategy, Covering);
+
+        let bad = ["--strategy frob", "--par 0", "--par x", "--timeout soon",
+                   "--timeout 0", "--timeout -5", "--timeout NaN", "--timeout inf",
+                   "--timeout 1e9", "--inputs -1", "--inputs 0", "--max-guesses many",
+                   "--par", "--frob 1"];
+        for a in bad.iter() {
+            assert!(SolveOptions::parse(args(*a)).is_err());
+        }
+        assert_eq!(SolveOptions::parse(args("--par 2 --inputs")).unwrap_err(),
+                   ~"--inputs needs a value");
+    }
+
+    #[test]
+    fn test_layout_arg() {
+        let (layout, rest) = layout_arg(args("squiggle trace --indent (lambda (x) x) 3"));
+        assert!(match layout { Indented(w) => w == INDENT_WIDTH, Compact => false });
+        assert_eq!(rest, args("squiggle trace (lambda (x) x) 3"));
+
+        let (layout, rest) = layout_arg(args("squiggle eval (lambda (x) x)"));
+        assert!(match layout { Compact => true, Indented(_) => false });
+        assert_eq!(rest, args("squiggle eval (lambda (x) x)"));
+    }
 }
@@ -0,0 +1,287 @@
+use program::*;
+
+// A block of up to 64 values turned on its side: `planes[b]` holds bit
+// `b` of every value, one lane per value. Every operator then works on
+// all of the lanes at once.
+type Planes = [u64, ..64];
+
+static LANES: uint = 64;
+
+// Transpose a 64x64 bit matrix in place (Hacker's Delight, 7-3):
+// afterwards bit `63 - c` of row `r` is what bit `63 - r` of row `c`
+// was. It's its own inverse.
+fn transpose(a: &mut Planes) {
+    let mut j = 32;
+    let mut m = 0x0000_0000_ffff_ffffu64;
+    while j != 0 {
+        let mut k = 0;
+        while k < 64 {
+            let t = (a[k] ^ (a[k + j] >> j)) & m;
+            a[k] ^= t;
+            a[k + j] ^= t << j;
+            k = (k + j + 1) & !j;
+        }
+        j >>= 1;
+        m ^= m << j;
+    }
+}
+
+// Slice up to 64 values into bit planes; unused lanes repeat the first
+// value, so they never disagree with anything.
+fn slice(vals: &[u64]) -> Planes {
+    assert!(vals.len() > 0 && vals.len() <= LANES);
+    let mut rows = [0u64, ..64];
+    for i in range(0, LANES) {
+        rows[i] = if i < vals.len() { vals[i] } else { vals[0] };
+    }
+    transpose(&mut rows);
+
+    // row r now holds bit 63 - r of each value
+    let mut planes = [0u64, ..64];
+    for b in range(0, 64) {
+        planes[b] = rows[63 - b];
+    }
+    planes
+}
+
+fn unslice(planes: &Planes, n: uint) -> ~[u64] {
+    let mut rows = [0u64, ..64];
+    for b in range(0, 64) {
+        rows[63 - b] = planes[b];
+    }
+    transpose(&mut rows);
+    rows.slice(0, n).to_owned()
+}
+
+fn constant(x: u64) -> Planes {
+    let mut planes = [0u64, ..64];
+    for b in range(0, 64) {
+        if x & (1u64 << b) != 0 {
+            planes[b] = !0;
+        }
+    }
+    planes
+}
+
+struct SlicedScope {
+    vars: ~[(Id, Planes)],
+}
+
+impl SlicedScope {
+    fn lookup(&self, id: Id) -> Planes {
+        for &(var, val) in self.vars.rev_iter() {
+            if var == id {
+                return val;
+            }
+        }
+        fail!("ident %s not found", id.to_str())
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Planes {
+        match *expr {
+            Zero => [0u64, ..64],
+            One => constant(1),
+            Ident(id) => self.lookup(id),
+            If0(~ref cond, ~ref then, ~ref els) => {
+                let cond = self.eval(cond);
+                let mut nonzero = 0;
+                for b in range(0, 64) {
+                    nonzero |= cond[b];
+                }
+
+                // only evaluate the arms some lane needs
+                if nonzero == 0 {
+                    self.eval(then)
+                } else if nonzero == !0 {
+                    self.eval(els)
+                } else {
+                    let then = self.eval(then);
+                    let els = self.eval(els);
+                    let mut out = [0u64, ..64];
+                    for b in range(0, 64) {
+                        out[b] = (then[b] & !nonzero) | (els[b] & nonzero);
+                    }
+                    out
+                }
+            }
+            Op1(op, ~ref expr) => {
+                let e = self.eval(expr);
+                let mut out = [0u64, ..64];
+
+                match op {
+                    Not => { for b in range(0, 64) { out[b] = !e[b]; } }
+                    Shl1 => { for b in range(1, 64) { out[b] = e[b - 1]; } }
+                    Shr1 => { for b in range(0, 63) { out[b] = e[b + 1]; } }
+                    Shr4 => { for b in range(0, 60) { out[b] = e[b + 4]; } }
+                    Shr16 => { for b in range(0, 48) { out[b] = e[b + 16]; } }
+                }
+                out
+            }
+            Op2(op, ~ref e1, ~ref e2) => {
+                let e1 = self.eval(e1);
+                let e2 = self.eval(e2);
+                let mut out = [0u64, ..64];
+
+                match op {
+                    And => { for b in range(0, 64) { out[b] = e1[b] & e2[b]; } }
+                    Or => { for b in range(0, 64) { out[b] = e1[b] | e2[b]; } }
+                    Xor => { for b in range(0, 64) { out[b] = e1[b] ^ e2[b]; } }
+                    Plus => {
+                        // ripple carry, a bit of every lane at a time
+                        let mut carry = 0;
+                        for b in range(0, 64) {
+                            let x = e1[b] ^ e2[b];
+                            out[b] = x ^ carry;
+                            carry = (e1[b] & e2[b]) | (carry & x);
+                        }
+                    }
+                }
+                out
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id: next_id, accum_id: accum_id,
+                body: ~ref body
+            } => {
+                let foldee = self.eval(foldee);
+                let mut accum = self.eval(init);
+
+                for byte in range(0, 8) {
+                    let mut b = [0u64, ..64];
+                    for bit in range(0, 8) {
+                        b[bit] = foldee[byte * 8 + bit];
+                    }
+
+                    self.vars.push((next_id, b));
+                    self.vars.push((accum_id, accum));
+                    accum = self.eval(body);
+                    self.vars.pop();
+                    self.vars.pop();
+                }
+
+                accum
+            }
+        }
+    }
+}
+
+// Evaluate `p` on up to 64 inputs at once.
+pub fn eval_block(p: &Program, inputs: &[u64]) -> ~[u64] {
+    let mut scope = SlicedScope { vars: ~[(p.id, slice(inputs))] };
+    let out = scope.eval(p.expr);
+    unslice(&out, inputs.len())
+}
+
+// Does `p` map every input to its output? Works through the constraints
+// 64 at a time, stopping at the first block with a mismatch.
+pub fn check_constraints(p: &Program, constraints: &[(u64, u64)]) -> bool {
+    let mut start = 0;
+    while start < constraints.len() {
+        let end = if start + LANES < constraints.len() {
+            start + LANES
+        } else {
+            constraints.len()
+        };
+        let block = constraints.slice(start, end);
+
+        let inputs: ~[u64] = block.iter().transform(|&(x, _)| x).collect();
+        let outputs: ~[u64] = block.iter().transform(|&(_, y)| y).collect();
+
+        let mut scope = SlicedScope { vars: ~[(p.id, slice(inputs))] };
+        let got = scope.eval(p.expr);
+        let expected = slice(outputs);
+        for b in range(0, 64) {
+            if got[b] != expected[b] {
+                return false;
+            }
+        }
+
+        start = end;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{slice, unslice};
+    use program::*;
+    use eval::Eval;
+    use extra::test::BenchHarness;
+    use std::rand;
+    use std::vec;
+
+    fn test_progs() -> ~[Program] {
+        let fold_expr = ~Fold {
+            foldee: ~Ident(0),
+            init: ~Op1(Not, ~Ident(0)),
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Plus, ~Ident(1), ~Op1(Shl1, ~Ident(2)))
+        };
+        ~[
+            Program::new(0, ~Zero),
+            Program::new(0, ~One),
+            Program::new(0, ~Op1(Not, ~Ident(0))),
+            Program::new(0, ~Op1(Shl1, ~Ident(0))),
+            Program::new(0, ~Op1(Shr1, ~Ident(0))),
+            Program::new(0, ~Op1(Shr4, ~Ident(0))),
+            Program::new(0, ~Op1(Shr16, ~Ident(0))),
+            Program::new(0, ~Op2(And, ~Ident(0), ~Op1(Shr4, ~Ident(0)))),
+            Program::new(0, ~Op2(Or, ~Ident(0), ~One)),
+            Program::new(0, ~Op2(Xor, ~Ident(0), ~Op1(Shl1, ~Ident(0)))),
+            Program::new(0, ~Op2(Plus, ~Ident(0), ~Op1(Not, ~Op1(Shr1, ~Ident(0))))),
+            Program::new(0, ~If0(~Op2(And, ~Ident(0), ~One), ~Ident(0), ~Op1(Not, ~Ident(0)))),
+            Program::new(0, fold_expr),
+        ]
+    }
+
+    #[test]
+    fn test_slice_roundtrip() {
+        let vals: ~[u64] = vec::from_fn(64, |_| rand::random());
+        assert_eq!(unslice(&slice(vals), 64), vals.clone());
+        assert_eq!(unslice(&slice(vals.slice(0, 10)), 10), vals.slice(0, 10).to_owned());
+    }
+
+    #[test]
+    fn test_eval_block() {
+        let mut inputs: ~[u64] = vec::from_fn(61, |_| rand::random());
+        inputs.push_all([0, 1, -1]);
+
+        for p in test_progs().iter() {
+            let expected: ~[u64] = inputs.iter().transform(|&x| p.eval(x)).collect();
+            assert_eq!(eval_block(p, inputs), expected);
+        }
+    }
+
+    #[test]
+    fn test_check_constraints() {
+        for p in test_progs().iter() {
+            // more than one block, and a partial one
+            let mut constraints: ~[(u64, u64)] = vec::from_fn(100, |_| {
+                let x = rand::random();
+                (x, p.eval(x))
+            });
+            assert!(check_constraints(p, constraints));
+
+            let (x, y) = constraints[80];
+            constraints[80] = (x, y ^ 4);
+            assert!(!check_constraints(p, constraints));
+        }
+    }
+
+    #[bench]
+    fn bench_check_constraints(bh: &mut BenchHarness) {
+        let progs = test_progs();
+        let inputs: ~[u64] = vec::from_fn(64, |_| rand::random());
+        let constraints: ~[~[(u64, u64)]] = progs.iter().transform(|p| {
+            inputs.iter().transform(|&x| (x, p.eval(x))).collect()
+        }).collect();
+
+        do bh.iter {
+            for (p, cs) in progs.iter().zip(constraints.iter()) {
+                check_constraints(p, *cs);
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use program::*;
-use eval::Eval;
+use eval::{Eval, ValidProgram};
 use extra::smallintmap::SmallIntMap;
 
 type Compiled = ~fn(&mut SmallIntMap<u64>) -> u64;
@@ -15,6 +15,7 @@ fn compile(expr: &Expr) -> Compiled {
         Zero => c(|_| 0),
         One => c(|_| 1),
         Ident(id) => {
+            // bound, as the program's been validated
             do c |map| {
                 *map.find(&id).unwrap()
             }
@@ -81,7 +82,8 @@ fn compile(expr: &Expr) -> Compiled {
     }
 }
 
-pub fn compile_program(p: &Program) -> CompiledProgram {
+pub fn compile_program(p: &ValidProgram) -> CompiledProgram {
+    let p = p.get_ref();
     CompiledProgram {
         id: p.id,
         expr: compile(p.expr)
@@ -114,9 +116,9 @@ mod test {
     fn bench_eval(bh: &mut BenchHarness) {
         // collection of randomly generated programs
         let progs = [
-                     compile_program(&Program::new(0, ~Op1(Shr1, ~Ident(0)))),
-                     compile_program(&Program::new(0, ~Op1(Shl1, ~Ident(0)))),
-                     ];
+                     Program::new(0, ~Op1(Shr1, ~Ident(0))),
+                     Program::new(0, ~Op1(Shl1, ~Ident(0))),
+                     ].map(|p| compile_program(&p.clone().validated().unwrap()));
         let mut rng = rand::rng();
 
         do bh.iter {
@@ -136,7 +138,7 @@ mod test {
             accum_id: 2,
             body: ~Op2(Plus, ~Ident(1), ~Ident(2))
         };
-        let prog = compile_program(&Program::new(0, fold_expr));
+        let prog = compile_program(&Program::new(0, fold_expr).validated().unwrap());
         let mut rng = rand::rng();
 
         do bh.iter {
@@ -184,7 +186,7 @@ mod test {
 
         for (p, f) in progs_fn.consume_iter() {
             info!(p.to_str());
-            let compiled = compile_program(&p);
+            let compiled = compile_program(&p.validated().unwrap());
 
             // some "special cases", maybe.
             assert_eq!(compiled.eval(0), f(0));
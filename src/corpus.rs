@@ -0,0 +1,280 @@
+// Problem corpora: programs we know, each with the size and operators a
+// problem for it would list, for solving offline through FakeApi. One
+// problem per line:
+//
+//     5 not,shr1 (lambda (x_0) (not (shr1 x_0)))
+//
+// with `-` for no operators. Blank lines and #-comments are skipped.
+
+use eval::ValidProgram;
+use gen::random_program;
+use parse::parse_program;
+use program::*;
+use webapi::FakeApi;
+
+use std::from_str::FromStr;
+use std::hashmap::{HashMap, HashSet};
+use std::io;
+use std::io::WriterUtil;
+use std::path::Path;
+use std::rand;
+use std::rand::{Rng, RngUtil};
+
+static UNARY: [Operator, ..5] = [OpNot, OpShl1, OpShr1, OpShr4, OpShr16];
+static BINARY: [Operator, ..4] = [OpAnd, OpOr, OpXor, OpPlus];
+
+#[deriving(Clone, Eq)]
+pub struct Entry {
+    size: u8,
+    operators: OperatorSet,
+    program: ValidProgram,
+}
+
+impl Entry {
+    // Problems are named for their programs.
+    pub fn id(&self) -> ~str {
+        self.program.to_str()
+    }
+
+    pub fn parse(line: &str) -> Result<Entry, ~str> {
+        let (size, rest) = match split_word(line) {
+            Some(split) => split,
+            None => return Err(~"expected a size, operators and a program"),
+        };
+        let (ops, src) = match split_word(rest) {
+            Some(split) => split,
+            None => return Err(~"expected operators and a program"),
+        };
+
+        let size: u8 = match FromStr::from_str(size) {
+            Some(n) => n,
+            None => return Err(fmt!("bad size %s", size)),
+        };
+        let operators = if ops == "-" {
+            OperatorSet::new()
+        } else {
+            match FromStr::from_str(ops) {
+                Some(ops) => ops,
+                None => return Err(fmt!("bad operators %s", ops)),
+            }
+        };
+        let program = match parse_program(src) {
+            Ok(p) => p,
+            Err(e) => return Err(fmt!("bad program: %s", e)),
+        };
+
+        if program.get_ref().len() != size {
+            return Err(fmt!("the program is size %u, not %u",
+                            program.get_ref().len() as uint, size as uint));
+        }
+        let used = program.get_ref().operators();
+        if !operators.allows(&used) {
+            return Err(fmt!("the program uses %s", used.difference(&operators).to_str()));
+        }
+        Ok(Entry {
+            size: size,
+            operators: operators,
+            program: program,
+        })
+    }
+}
+
+impl ToStr for Entry {
+    fn to_str(&self) -> ~str {
+        let ops = if self.operators.len() == 0 { ~"-" } else { self.operators.to_str() };
+        fmt!("%u %s %s", self.size as uint, ops, self.program.to_str())
+    }
+}
+
+// The first word of `s`, and what's after it.
+fn split_word<'a>(s: &'a str) -> Option<(&'a str, &'a str)> {
+    let s = s.trim();
+    match s.find(' ') {
+        Some(i) => Some((s.slice_to(i), s.slice_from(i + 1).trim_left())),
+        None => None,
+    }
+}
+
+// Each program may only be listed once, as it's the problem's id.
+pub fn parse_corpus(src: &str) -> Result<~[Entry], ~str> {
+    let mut entries = ~[];
+    let mut lines = HashMap::new();
+    for (i, line) in src.line_iter().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            loop;
+        }
+        let e = match Entry::parse(line) {
+            Ok(e) => e,
+            Err(e) => return Err(fmt!("line %u: %s", i + 1, e)),
+        };
+        match lines.find(&e.id()) {
+            Some(&first) => return Err(fmt!("line %u: the same program as line %u",
+                                            i + 1, first)),
+            None => {}
+        }
+        lines.insert(e.id(), i + 1);
+        entries.push(e);
+    }
+    Ok(entries)
+}
+
+pub fn load(file: &Path) -> Result<~[Entry], ~str> {
+    match io::read_whole_file_str(file) {
+        Ok(src) => parse_corpus(src),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn save(file: &Path, entries: &[Entry]) -> Result<(), ~str> {
+    let w = match io::file_writer(file, [io::Create, io::Truncate]) {
+        Ok(w) => w,
+        Err(e) => return Err(e),
+    };
+    w.write_line("# size operators program");
+    for e in entries.iter() {
+        w.write_line(e.to_str());
+    }
+    Ok(())
+}
+
+// A FakeApi with the corpus as its problems, and its training problems.
+pub fn fake_api(entries: &[Entry]) -> FakeApi {
+    let mut api = FakeApi::new(~[]);
+    for e in entries.iter() {
+        api.add_problem(e.id(), e.program.clone(), e.operators.clone());
+    }
+    api
+}
+
+// `n` different random problems, shaped like the contest's (see
+// random_shape).
+pub fn generate(n: uint) -> ~[Entry] {
+    let mut rng = rand::task_rng();
+    let mut seen = HashSet::new();
+    let mut entries = ~[];
+    while entries.len() < n {
+        let (size, ops) = random_shape(&mut rng);
+        match random_entry(size, ops) {
+            Some(e) => {
+                if seen.insert(e.id()) {
+                    entries.push(e);
+                }
+            }
+            None => {}
+        }
+    }
+    entries
+}
+
+// A problem size and the operators it may use, in about the contest's
+// proportions: half plain problems of size 3 to 30, a fifth tfold and a
+// fifth fold problems of size 8 to 30, and a tenth bonus problems of
+// size 42.
+fn random_shape<R: Rng>(rng: &mut R) -> (u8, OperatorSet) {
+    let mut ops = OperatorSet::new();
+    // the generator needs at least one of each
+    ops.insert(UNARY[rng.gen_uint_range(0, UNARY.len())]);
+    ops.insert(BINARY[rng.gen_uint_range(0, BINARY.len())]);
+    for &op in UNARY.iter().chain_(BINARY.iter()) {
+        if rng.gen_weighted_bool(3) {
+            ops.insert(op);
+        }
+    }
+
+    let size = match rng.gen_uint_range(0, 10) {
+        0..4 => {
+            if rng.gen_weighted_bool(3) {
+                ops.insert(OpIf0);
+            }
+            rng.gen_uint_range(3, 31)
+        }
+        5..6 => {
+            ops.insert(OpTfold);
+            rng.gen_uint_range(8, 31)
+        }
+        7..8 => {
+            ops.insert(OpFold);
+            rng.gen_uint_range(8, 31)
+        }
+        _ => {
+            ops.insert(OpBonus);
+            ops.insert(OpIf0);
+            ops.insert(OpAnd);
+            42
+        }
+    };
+    (size as u8, ops)
+}
+
+// A program of that shape, if the generator manages one. As on the
+// server, the problem lists only the operators the program uses.
+fn random_entry(size: u8, ops: OperatorSet) -> Option<Entry> {
+    let program = match random_program(size, ops.clone()) {
+        Some(p) => p,
+        None => return None,
+    };
+    let program = match program.validated() {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+
+    let mut operators = program.get_ref().operators();
+    if ops.has(OpFold) && !operators.has(OpFold) {
+        // it isn't a fold problem after all
+        return None;
+    }
+    if ops.has(OpBonus) {
+        operators.insert(OpBonus);
+    }
+    Some(Entry {
+        size: program.get_ref().len(),
+        operators: operators,
+        program: program,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use webapi::{Api, Empty};
+
+    #[test]
+    fn test_parse() {
+        let e = Entry::parse("5 not,shr1 (lambda (x) (not (shr1 x)))").unwrap();
+        assert_eq!(e.size, 5);
+        assert_eq!(e.operators, OperatorSet::from_ops([OpNot, OpShr1]));
+        assert_eq!(Entry::parse(e.to_str()).unwrap().to_str(), e.to_str());
+
+        let e = Entry::parse("3 - (lambda (x) x)").unwrap_err();
+        assert!(e.contains("size 2"));
+        let e = Entry::parse("3 not (lambda (x) (shl1 x))").unwrap_err();
+        assert!(e.contains("shl1"));
+
+        let src = "# a comment\n\n3 not (lambda (x) (not x))\n3 not (lambda (x)\n";
+        assert!(parse_corpus(src).unwrap_err().starts_with("line 4:"));
+
+        let src = ~"3 not (lambda (x) (not x))\n" +
+            "3 shl1 (lambda (x) (shl1 x))\n" +
+            "3 not (lambda (y) (not y))\n";
+        assert_eq!(parse_corpus(src).unwrap_err(), ~"line 3: the same program as line 1");
+    }
+
+    #[test]
+    fn test_generate() {
+        let entries = generate(20);
+        assert_eq!(entries.len(), 20);
+        for e in entries.iter() {
+            assert_eq!(Entry::parse(e.to_str()).unwrap().to_str(), e.to_str());
+        }
+
+        let mut api = fake_api(entries);
+        for e in entries.iter() {
+            let prob = api.get_training_blocking(0, Empty).unwrap();
+            assert_eq!(prob.challenge, e.program.to_str());
+            assert_eq!(prob.problem.operators, e.operators);
+        }
+        assert!(!api.has_programs());
+    }
+}
@@ -0,0 +1,400 @@
+use program::*;
+
+use std::hashmap::HashMap;
+use std::vec;
+
+pub type NodeId = uint;
+
+// Roughly what a node costs besides its cached outputs: the node itself,
+// its hash table entry, its free variable list and its output slot.
+static NODE_BYTES: uint = 256;
+
+// A node of the shared expression graph. Identifiers are resolved when
+// interning, so the same node always means the same thing: `NInput` is
+// the program's argument wherever it appears, `NVar` is something bound
+// by an enclosing fold.
+#[deriving(Eq, Clone, IterBytes)]
+enum Node {
+    NZero,
+    NOne,
+    NInput,
+    NVar(Id),
+    NIf0(NodeId, NodeId, NodeId),
+    NOp1(UnaOp, NodeId),
+    NOp2(BinOp, NodeId, NodeId),
+    // foldee, init, next_id, accum_id, body
+    NFold(NodeId, NodeId, Id, Id, NodeId),
+}
+
+// Hash-consed expressions: every distinct subterm is stored once, and
+// subterms that only depend on the input carry their outputs on the
+// current inputs once they have been evaluated.
+pub struct ExprArena {
+    nodes: ~[Node],
+    ids: HashMap<Node, NodeId>,
+    // the free fold variables of each node; only nodes without any
+    // depend on nothing but the input, so only those can be cached.
+    free: ~[~[Id]],
+    inputs: ~[u64],
+    outputs: ~[Option<~[u64]>],
+    // an estimate of the memory all of the above take up
+    bytes: uint,
+}
+
+impl ExprArena {
+    pub fn new(inputs: ~[u64]) -> ExprArena {
+        ExprArena {
+            nodes: ~[],
+            ids: HashMap::new(),
+            free: ~[],
+            inputs: inputs,
+            outputs: ~[],
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.nodes.len()
+    }
+
+    // About how much memory the nodes and their cached outputs take.
+    pub fn bytes(&self) -> uint {
+        self.bytes
+    }
+
+    // Forget every node; all previously returned ids become invalid.
+    pub fn clear(&mut self) {
+        self.nodes = ~[];
+        self.ids = HashMap::new();
+        self.free = ~[];
+        self.outputs = ~[];
+        self.bytes = 0;
+    }
+
+    // Evaluate on a different set of inputs; the nodes stay, the cached
+    // outputs don't.
+    pub fn set_inputs(&mut self, inputs: ~[u64]) {
+        self.inputs = inputs;
+        self.outputs = vec::from_elem(self.nodes.len(), None);
+        self.bytes = self.nodes.len() * NODE_BYTES;
+    }
+
+    pub fn intern(&mut self, p: &Program) -> NodeId {
+        let mut bound = ~[];
+        self.intern_expr(p.expr, p.id, &mut bound)
+    }
+
+    fn intern_expr(&mut self, e: &Expr, arg: Id, bound: &mut ~[Id]) -> NodeId {
+        let node = match *e {
+            Zero => NZero,
+            One => NOne,
+            Ident(id) => {
+                // fold variables shadow the argument
+                if bound.iter().any(|&b| b == id) {
+                    NVar(id)
+                } else if id == arg {
+                    NInput
+                } else {
+                    NVar(id)
+                }
+            }
+            If0(~ref cond, ~ref then, ~ref els) => {
+                let cond = self.intern_expr(cond, arg, bound);
+                let then = self.intern_expr(then, arg, bound);
+                let els = self.intern_expr(els, arg, bound);
+                NIf0(cond, then, els)
+            }
+            Op1(op, ~ref expr) => NOp1(op, self.intern_expr(expr, arg, bound)),
+            Op2(op, ~ref e1, ~ref e2) => {
+                let e1 = self.intern_expr(e1, arg, bound);
+                let e2 = self.intern_expr(e2, arg, bound);
+                NOp2(op, e1, e2)
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id, accum_id,
+                body: ~ref body
+            } => {
+                let foldee = self.intern_expr(foldee, arg, bound);
+                let init = self.intern_expr(init, arg, bound);
+
+                bound.push(next_id);
+                bound.push(accum_id);
+                let body = self.intern_expr(body, arg, bound);
+                bound.pop();
+                bound.pop();
+
+                NFold(foldee, init, next_id, accum_id, body)
+            }
+        };
+        self.mk(node)
+    }
+
+    fn mk(&mut self, node: Node) -> NodeId {
+        match self.ids.find(&node) {
+            Some(&id) => return id,
+            None => {}
+        }
+
+        let mut free = ~[];
+        match node {
+            NZero | NOne | NInput => {}
+            NVar(v) => free.push(v),
+            NIf0(c, t, e) => {
+                self.add_free(&mut free, c);
+                self.add_free(&mut free, t);
+                self.add_free(&mut free, e);
+            }
+            NOp1(_, e) => self.add_free(&mut free, e),
+            NOp2(_, e1, e2) => {
+                self.add_free(&mut free, e1);
+                self.add_free(&mut free, e2);
+            }
+            NFold(foldee, init, next_id, accum_id, body) => {
+                self.add_free(&mut free, foldee);
+                self.add_free(&mut free, init);
+                for &v in self.free[body].iter() {
+                    if v != next_id && v != accum_id && !free.contains(&v) {
+                        free.push(v);
+                    }
+                }
+            }
+        }
+
+        let id = self.nodes.len();
+        self.nodes.push(node);
+        self.free.push(free);
+        self.outputs.push(None);
+        self.ids.insert(node, id);
+        self.bytes += NODE_BYTES;
+        id
+    }
+
+    fn add_free(&self, free: &mut ~[Id], id: NodeId) {
+        for &v in self.free[id].iter() {
+            if !free.contains(&v) {
+                free.push(v);
+            }
+        }
+    }
+
+    fn is_closed(&self, id: NodeId) -> bool {
+        self.free[id].is_empty()
+    }
+
+    // The outputs of a closed node on every input, computing and caching
+    // them if needed.
+    pub fn outputs<'a>(&'a mut self, id: NodeId) -> &'a [u64] {
+        assert!(self.is_closed(id));
+        self.ensure_outputs(id);
+        self.outputs[id].get_ref().as_slice()
+    }
+
+    fn ensure_outputs(&mut self, id: NodeId) {
+        if self.outputs[id].is_some() {
+            return;
+        }
+        let node = self.nodes[id];
+        let mut env = ~[];
+        let mut outs = vec::with_capacity(self.inputs.len());
+        for i in range(0, self.inputs.len()) {
+            outs.push(self.eval_node(node, i, &mut env));
+        }
+        self.outputs[id] = Some(outs);
+        self.bytes += self.inputs.len() * 8;
+    }
+
+    // The value of `id` on the `i`th input, with the fold variables in
+    // `env` (innermost last).
+    pub fn eval_at(&mut self, id: NodeId, i: uint, env: &mut ~[(Id, u64)]) -> u64 {
+        if self.is_closed(id) {
+            self.ensure_outputs(id);
+            self.outputs[id].get_ref()[i]
+        } else {
+            let node = self.nodes[id];
+            self.eval_node(node, i, env)
+        }
+    }
+
+    fn eval_node(&mut self, node: Node, i: uint, env: &mut ~[(Id, u64)]) -> u64 {
+        match node {
+            NZero => 0,
+            NOne => 1,
+            NInput => self.inputs[i],
+            NVar(v) => {
+                for &(var, val) in env.rev_iter() {
+                    if var == v {
+                        return val;
+                    }
+                }
+                fail!("ident %s not found", v.to_str())
+            }
+            NIf0(cond, then, els) => {
+                if self.eval_at(cond, i, env) == 0 {
+                    self.eval_at(then, i, env)
+                } else {
+                    self.eval_at(els, i, env)
+                }
+            }
+            NOp1(op, e) => {
+                let e = self.eval_at(e, i, env);
+
+                match op {
+                    Not => !e,
+                    Shl1 => e << 1,
+                    Shr1 => e >> 1,
+                    Shr4 => e >> 4,
+                    Shr16 => e >> 16
+                }
+            }
+            NOp2(op, e1, e2) => {
+                let e1 = self.eval_at(e1, i, env);
+                let e2 = self.eval_at(e2, i, env);
+
+                match op {
+                    And => e1 & e2,
+                    Or => e1 | e2,
+                    Xor => e1 ^ e2,
+                    Plus => e1 + e2
+                }
+            }
+            NFold(foldee, init, next_id, accum_id, body) => {
+                let mut foldee = self.eval_at(foldee, i, env);
+                let mut accum = self.eval_at(init, i, env);
+
+                for _ in range(0, 8) {
+                    let b = foldee & 0xff;
+                    foldee >>= 8;
+
+                    env.push((next_id, b));
+                    env.push((accum_id, accum));
+                    accum = self.eval_at(body, i, env);
+                    env.pop();
+                    env.pop();
+                }
+
+                accum
+            }
+        }
+    }
+
+    // How many of the leading inputs does the closed node `id` map to
+    // `expected`? The node itself is evaluated one input at a time,
+    // stopping at the first mismatch, but every closed subterm it reaches
+    // gets its outputs on all of the inputs computed and cached, since
+    // those are the bits that get shared. So a fresh candidate costs a
+    // full evaluation of whatever subterms are new to the arena, and
+    // little more than one input for the node itself.
+    pub fn matching_prefix(&mut self, id: NodeId, expected: &[u64]) -> uint {
+        assert!(expected.len() == self.inputs.len());
+        let node = self.nodes[id];
+        let cached = self.outputs[id].is_some();
+        let mut env = ~[];
+        for i in range(0, expected.len()) {
+            let out = if cached {
+                self.outputs[id].get_ref()[i]
+            } else {
+                self.eval_node(node, i, &mut env)
+            };
+            if out != expected[i] {
+                return i;
+            }
+        }
+        expected.len()
+    }
+
+    // Does the closed node `id` produce `expected` on the inputs?
+    pub fn matches(&mut self, id: NodeId, expected: &[u64]) -> bool {
+        self.matching_prefix(id, expected) == expected.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use eval::Eval;
+    use std::rand;
+
+    fn fold_prog() -> Program {
+        Program::new(0, ~Fold {
+            foldee: ~Ident(0),
+            init: ~Op1(Not, ~Ident(0)),
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Plus, ~Ident(1), ~Op1(Shr1, ~Ident(2)))
+        })
+    }
+
+    #[test]
+    fn test_sharing() {
+        let mut arena = ExprArena::new(~[1, 2, 3]);
+        let a = arena.intern(&Program::new(0, ~Op2(And, ~Op1(Not, ~Ident(0)), ~One)));
+        let len = arena.len();
+        let b = arena.intern(&Program::new(0, ~Op2(And, ~Op1(Not, ~Ident(0)), ~One)));
+        assert_eq!(a, b);
+        assert_eq!(arena.len(), len);
+
+        // only the new root is added
+        arena.intern(&Program::new(0, ~Op2(Or, ~Op1(Not, ~Ident(0)), ~One)));
+        assert_eq!(arena.len(), len + 1);
+
+        // the argument's name doesn't matter
+        let c = arena.intern(&Program::new(5, ~Op2(And, ~Op1(Not, ~Ident(5)), ~One)));
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn test_matches_eval() {
+        let inputs: ~[u64] = ~[0, 1, -1, rand::random(), rand::random(), rand::random()];
+        let progs = ~[
+            Program::new(0, ~If0(~Op2(And, ~Ident(0), ~One), ~Op1(Shl1, ~Ident(0)), ~Zero)),
+            Program::new(0, ~Op2(Xor, ~Op1(Shr16, ~Ident(0)), ~Op1(Shr4, ~Ident(0)))),
+            fold_prog(),
+        ];
+
+        let mut arena = ExprArena::new(inputs.clone());
+        for p in progs.iter() {
+            let expected: ~[u64] = inputs.iter().transform(|&x| p.eval(x)).collect();
+            let id = arena.intern(p);
+            assert!(arena.matches(id, expected));
+            assert_eq!(arena.outputs(id), expected.as_slice());
+
+            let mut wrong = expected.clone();
+            wrong[3] += 1;
+            assert!(!arena.matches(id, wrong));
+        }
+    }
+
+    #[test]
+    fn test_shadowed_input() {
+        // the fold's next_id is the same as the argument, so the
+        // reference in the body is to the byte, not the input
+        let p = Program::new(0, ~Fold {
+            foldee: ~Ident(0),
+            init: ~Zero,
+            next_id: 0,
+            accum_id: 1,
+            body: ~Op2(Plus, ~Ident(0), ~Ident(1))
+        });
+        let inputs: ~[u64] = ~[0x0102030405060708, rand::random()];
+        let expected: ~[u64] = inputs.iter().transform(|&x| p.eval(x)).collect();
+
+        let mut arena = ExprArena::new(inputs);
+        let id = arena.intern(&p);
+        assert!(arena.matches(id, expected));
+        assert_eq!(arena.outputs(id)[0], 36);
+    }
+
+    #[test]
+    fn test_set_inputs() {
+        let mut arena = ExprArena::new(~[1, 2]);
+        let id = arena.intern(&fold_prog());
+        let outs = arena.outputs(id).to_owned();
+
+        arena.set_inputs(~[3, 4]);
+        let p = fold_prog();
+        assert!(arena.matches(id, [p.eval(3), p.eval(4)]));
+        assert!(outs != arena.outputs(id).to_owned());
+    }
+}
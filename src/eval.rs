@@ -1,4 +1,9 @@
 use program::*;
+use pretty::{Printer, Compact};
+
+use std::num::ToStrRadix;
+use std::str;
+use std::vec;
 
 // immutable linked list, lifetime don't work well enough (yet; need
 // the ability for 2 lifetimes) for this to avoid @.
@@ -84,6 +89,250 @@ impl Scope {
             }
         }
     }
+
+    // Like eval, but recording the value of every node along the way.
+    fn trace(@self, expr: &Expr) -> TraceNode {
+        match *expr {
+            Zero => TraceNode::leaf(Text(~"0"), 0),
+            One => TraceNode::leaf(Text(~"1"), 1),
+            Ident(id) => TraceNode::leaf(Var(id), self.lookup(id)),
+            If0(~ref cond, ~ref then, ~ref els) => {
+                let cond = self.trace(cond);
+                // only the arm that's taken gets evaluated
+                let arm = if cond.value == 0 {
+                    self.trace(then)
+                } else {
+                    self.trace(els)
+                };
+                TraceNode {
+                    label: Text(~"if0"),
+                    value: arm.value,
+                    children: ~[cond, arm],
+                    steps: ~[],
+                }
+            }
+            Op1(op, ~ref expr) => {
+                let e = self.trace(expr);
+                let v = e.value;
+
+                TraceNode {
+                    label: Text(op.to_str()),
+                    value: match op {
+                        Not => !v,
+                        Shl1 => v << 1,
+                        Shr1 => v >> 1,
+                        Shr4 => v >> 4,
+                        Shr16 => v >> 16
+                    },
+                    children: ~[e],
+                    steps: ~[],
+                }
+            }
+            Op2(op, ~ref e1, ~ref e2) => {
+                let e1 = self.trace(e1);
+                let e2 = self.trace(e2);
+                let (v1, v2) = (e1.value, e2.value);
+
+                TraceNode {
+                    label: Text(op.to_str()),
+                    value: match op {
+                        And => v1 & v2,
+                        Or => v1 | v2,
+                        Xor => v1 ^ v2,
+                        Plus => v1 + v2
+                    },
+                    children: ~[e1, e2],
+                    steps: ~[],
+                }
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id: next_id, accum_id: accum_id,
+                body: ~ref body
+            } => {
+                let foldee_t = self.trace(foldee);
+                let init_t = self.trace(init);
+                let mut foldee = foldee_t.value;
+                let mut accum = init_t.value;
+
+                let mut steps = ~[];
+                for _ in range(0, 8) {
+                    let b = foldee & 0xff;
+                    foldee >>= 8;
+
+                    let scope = self.push(next_id, b);
+                    let scope = scope.push(accum_id, accum);
+
+                    let body_t = scope.trace(body);
+                    let next_accum = body_t.value;
+                    steps.push(FoldStep {
+                        next: (next_id, b),
+                        accum: (accum_id, accum),
+                        body: body_t,
+                    });
+                    accum = next_accum;
+                }
+
+                TraceNode {
+                    label: Text(~"fold"),
+                    value: accum,
+                    children: ~[foldee_t, init_t],
+                    steps: steps,
+                }
+            }
+        }
+    }
+}
+
+// What a node of a trace is: an identifier, named when it's rendered, or
+// anything else, which is shown as is.
+#[deriving(Eq)]
+pub enum Label {
+    Var(Id),
+    Text(~str),
+}
+
+// The value of a node of a program on some input, along with those of
+// everything under it that got evaluated.
+pub struct TraceNode {
+    label: Label,
+    value: u64,
+    children: ~[TraceNode],
+    // the iterations of a fold, in order
+    steps: ~[FoldStep],
+}
+
+pub struct FoldStep {
+    // the variables as bound for this iteration
+    next: (Id, u64),
+    accum: (Id, u64),
+    body: TraceNode,
+}
+
+impl TraceNode {
+    fn leaf(label: Label, value: u64) -> TraceNode {
+        TraceNode {
+            label: label,
+            value: value,
+            children: ~[],
+            steps: ~[],
+        }
+    }
+
+    // The tree, one node per line, with identifiers named by `printer`;
+    // print the program with the same printer to have the names agree.
+    pub fn render(&self, printer: &mut Printer) -> ~str {
+        let mut out = ~"";
+        self.render_at(0, printer, &mut out);
+        out
+    }
+
+    fn render_at(&self, depth: uint, printer: &mut Printer, out: &mut ~str) {
+        let indent = str::from_chars(vec::from_elem(depth * 2, ' '));
+        let label = match self.label {
+            Var(id) => printer.name(id),
+            Text(ref s) => s.clone(),
+        };
+        out.push_str(fmt!("%s%s = %s\n", indent, label, hex(self.value)));
+        for child in self.children.iter() {
+            child.render_at(depth + 1, printer, out);
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            let (next_id, next) = step.next;
+            let (accum_id, accum) = step.accum;
+            out.push_str(fmt!("%s  [byte %u: %s = %s, %s = %s]\n",
+                              indent, i,
+                              printer.name(next_id), hex(next),
+                              printer.name(accum_id), hex(accum)));
+            step.body.render_at(depth + 2, printer, out);
+        }
+    }
+}
+
+impl ToStr for TraceNode {
+    pub fn to_str(&self) -> ~str {
+        self.render(&mut Printer::new(Compact))
+    }
+}
+
+fn hex(v: u64) -> ~str {
+    ~"0x" + v.to_str_radix(16)
+}
+
+// Evaluate `p` on `val`, keeping hold of every intermediate value.
+pub fn trace(p: &Program, val: u64) -> TraceNode {
+    (@Scope {
+            id: p.id,
+            val: val,
+            parent: None
+        }).trace(p.expr)
+}
+
+#[deriving(Eq, Clone)]
+pub enum EvalError {
+    UnboundIdent(Id),
+}
+
+impl ToStr for EvalError {
+    pub fn to_str(&self) -> ~str {
+        match *self {
+            UnboundIdent(id) => fmt!("ident %s not found", id.to_str()),
+        }
+    }
+}
+
+// Check that evaluating `p` can't fail: every identifier is bound by the
+// lambda or an enclosing fold. Anything that passes can go down the
+// unchecked path.
+pub fn validate(p: &Program) -> Result<(), EvalError> {
+    let mut bound = ~[p.id];
+    validate_expr(p.expr, &mut bound)
+}
+
+fn validate_expr(expr: &Expr, bound: &mut ~[Id]) -> Result<(), EvalError> {
+    match *expr {
+        Zero | One => Ok(()),
+        Ident(id) => {
+            if bound.contains(&id) { Ok(()) } else { Err(UnboundIdent(id)) }
+        }
+        Op1(_, ~ref e) => validate_expr(e, bound),
+        Op2(_, ~ref e1, ~ref e2) => {
+            match validate_expr(e1, bound) {
+                Ok(()) => validate_expr(e2, bound),
+                err => err,
+            }
+        }
+        If0(~ref cond, ~ref then, ~ref els) => {
+            match validate_expr(cond, bound) {
+                Ok(()) => {}
+                err => return err,
+            }
+            match validate_expr(then, bound) {
+                Ok(()) => validate_expr(els, bound),
+                err => err,
+            }
+        }
+        Fold {
+            foldee: ~ref foldee, init: ~ref init,
+            next_id, accum_id,
+            body: ~ref body
+        } => {
+            match validate_expr(foldee, bound) {
+                Ok(()) => {}
+                err => return err,
+            }
+            match validate_expr(init, bound) {
+                Ok(()) => {}
+                err => return err,
+            }
+            bound.push(next_id);
+            bound.push(accum_id);
+            let res = validate_expr(body, bound);
+            bound.pop();
+            bound.pop();
+            res
+        }
+    }
 }
 
 pub trait Eval {
@@ -100,11 +349,60 @@ impl Eval for Program {
     }
 }
 
+// A program that's been through validate, so evaluating it can't fail.
+#[deriving(Clone, Eq)]
+pub struct ValidProgram {
+    priv program: Program
+}
+
+impl Program {
+    pub fn validated(self) -> Result<ValidProgram, EvalError> {
+        match validate(&self) {
+            Ok(()) => Ok(ValidProgram { program: self }),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Evaluate a program that hasn't been validated, getting an error
+    // instead of a failure if it can't be.
+    pub fn eval_checked(&self, val: u64) -> Result<u64, EvalError> {
+        match validate(self) {
+            Ok(()) => Ok(self.eval(val)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl ValidProgram {
+    pub fn get_ref<'a>(&'a self) -> &'a Program {
+        &self.program
+    }
+
+    pub fn unwrap(self) -> Program {
+        self.program
+    }
+}
+
+impl Eval for ValidProgram {
+    fn eval(&self, val: u64) -> u64 {
+        self.program.eval(val)
+    }
+}
+
+impl ToStr for ValidProgram {
+    pub fn to_str(&self) -> ~str {
+        self.program.to_str()
+    }
+}
+
 
 #[cfg(test)]
 mod test {
     use super::*;
     use program::*;
+use pretty::{Printer, Compact};
+    use parse::Parser;
+    use pretty::{Printer, Compact};
     use extra::test::BenchHarness;
     use std::rand;
 
@@ -195,4 +493,69 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_trace() {
+        let fold_expr = ~Fold {
+            foldee: ~Ident(0),
+            init: ~Zero,
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Plus, ~Ident(1), ~Ident(2))
+        };
+        let p = Program::new(0, ~If0(~Op2(And, ~Ident(0), ~One), ~One, fold_expr));
+
+        let t = trace(&p, 0x0403);
+        assert_eq!(t.value, p.eval(0x0403));
+        assert_eq!(t.label, Text(~"if0"));
+        assert_eq!(t.children[0].value, 1);
+
+        let fold_t = &t.children[1];
+        assert_eq!(fold_t.value, 7);
+        assert_eq!(fold_t.steps.len(), 8);
+        assert_eq!(fold_t.steps[1].next, (1, 4));
+        assert_eq!(fold_t.steps[1].accum, (2, 3));
+        assert_eq!(fold_t.steps[1].body.value, 7);
+
+        // the untaken arm isn't there
+        assert_eq!(trace(&p, 0x0402).children[1].label, Text(~"1"));
+    }
+
+    #[test]
+    fn test_trace_names() {
+        let mut parser = Parser::new("(lambda (x) (fold x 0 (lambda (y z) (plus y z))))");
+        let p = parser.parse().unwrap();
+        let mut printer = Printer::with_names(parser.names(), Compact);
+        let out = trace(&p, 0x0102).render(&mut printer);
+        assert!(out.contains("\n  x = 0x102\n"));
+        assert!(out.contains("[byte 1: y = 0x1, z = 0x2]"));
+    }
+
+    #[test]
+    fn test_validated() {
+        let fold_expr = ~Fold {
+            foldee: ~Ident(0),
+            init: ~Zero,
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Plus, ~Ident(1), ~Ident(2))
+        };
+        assert_eq!(Program::new(0, fold_expr).validated().unwrap().eval(0x0101), 2);
+
+        // the fold's variables aren't visible outside it
+        let fold_expr = ~Op2(Plus, ~Ident(1), ~Fold {
+            foldee: ~Ident(0),
+            init: ~Zero,
+            next_id: 1,
+            accum_id: 2,
+            body: ~Ident(2)
+        });
+        assert_eq!(Program::new(0, fold_expr).validated().unwrap_err(), UnboundIdent(1));
+
+        assert_eq!(Program::new(0, ~Op1(Not, ~Ident(3))).validated().unwrap_err(),
+                   UnboundIdent(3));
+        assert_eq!(Program::new(0, ~Op1(Not, ~Ident(3))).eval_checked(1),
+                   Err(UnboundIdent(3)));
+        assert_eq!(Program::new(0, ~Op1(Not, ~Ident(0))).eval_checked(1), Ok(!1));
+    }
 }
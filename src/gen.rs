@@ -1,44 +1,176 @@
 use webapi::*;
 use program::*;
+use dag::ExprArena;
+use bitslice;
 
 use std::cell::Cell;
+use std::cmp;
 use std::comm;
 use std::comm::{Port, Chan};
 use std::from_str::FromStr;
 use std::os;
 use std::rand::{Rng, RngUtil, XorShiftRng, task_rng};
 use std::task;
+use std::to_str::ToStr;
 use extra::arc;
 use extra::time;
 
 static DEFAULT_PARALLELISM: uint = 1;
 static CHECK_EVERY: uint = 16384;
 
-static DEFAULT_TIMEOUT: u64 = 60;
+
+// how much memory a task's expression arena may take up before it's
+// thrown away; there is one per worker task and one worker per PAR in
+// each of the CONCURRENT problems
+static MAX_ARENA_BYTES: uint = 32 << 20;
+// candidates are checked against this many constraints one at a time
+// before being checked against all of them in bulk
+static QUICK_CHECK: uint = 4;
+
+// A point in time (in precise_time_ns terms) by which something has to
+// be done.
+#[deriving(Clone)]
+pub struct Deadline {
+    at_ns: u64,
+}
+
+impl Deadline {
+    pub fn in_secs(secs: float) -> Deadline {
+        Deadline {
+            at_ns: time::precise_time_ns() + (secs * 1_000_000_000f) as u64
+        }
+    }
+
+    pub fn at_ns(ns: u64) -> Deadline {
+        Deadline { at_ns: ns }
+    }
+
+    pub fn remaining_ns(&self) -> u64 {
+        let now = time::precise_time_ns();
+        if now >= self.at_ns { 0 } else { self.at_ns - now }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining_ns() == 0
+    }
+
+    // This deadline brought forward by `ns`, e.g. to leave time for a
+    // request after it.
+    pub fn less_ns(&self, ns: u64) -> Deadline {
+        Deadline { at_ns: if self.at_ns > ns { self.at_ns - ns } else { 0 } }
+    }
+
+    // This deadline, given on another clock that reads `now_ns` (an
+    // Api's, which may skip ahead of ours), as the same time left on ours.
+    pub fn on_clock(&self, now_ns: u64) -> Deadline {
+        let left = if now_ns >= self.at_ns { 0 } else { self.at_ns - now_ns };
+        Deadline { at_ns: time::precise_time_ns() + left }
+    }
+
+    pub fn min(&self, other: &Deadline) -> Deadline {
+        Deadline { at_ns: if self.at_ns < other.at_ns { self.at_ns } else { other.at_ns } }
+    }
+}
+
+// How candidates choose their operators.
+#[deriving(Clone, Eq)]
+pub enum Strategy {
+    // prefer required operators not yet in the candidate, so that most
+    // candidates use every one the problem lists
+    Covering,
+    // any allowed operator, equally likely
+    Uniform,
+}
+
+impl FromStr for Strategy {
+    fn from_str(s: &str) -> Option<Strategy> {
+        match s {
+            "covering" => Some(Covering),
+            "uniform" => Some(Uniform),
+            _ => None,
+        }
+    }
+}
+
+impl ToStr for Strategy {
+    fn to_str(&self) -> ~str {
+        match *self {
+            Covering => ~"covering",
+            Uniform => ~"uniform",
+        }
+    }
+}
+
+// Settings read from the environment once, when the generator starts.
+#[deriving(Clone)]
+pub struct GenConfig {
+    parallelism: uint,
+    strategy: Strategy,
+}
+
+impl GenConfig {
+    pub fn from_env() -> GenConfig {
+        let parallelism: uint = do os::getenv("PAR").chain |s| {
+            FromStr::from_str(s)
+        }.unwrap_or_default(DEFAULT_PARALLELISM);
+        let strategy = do os::getenv("STRATEGY").chain |s| {
+            FromStr::from_str(s)
+        }.unwrap_or_default(Covering);
+
+        GenConfig {
+            parallelism: parallelism,
+            strategy: strategy,
+        }
+    }
+}
+
+pub enum GenResult {
+    Found(~Program),
+    // Nothing satisfying every constraint turned up before the deadline.
+    // (A near miss isn't worth guessing: it fails a pair we already have.)
+    TimedOut,
+}
 
 pub enum GenMsg {
-    Generate(Chan<Option<~Program>>),
+    Generate(Deadline, Chan<GenResult>),
     Reset(Problem, ~[(u64, u64)]),
     MoreConstraints(~[(u64, u64)]),
     Exit,
 }
 
+// what a worker task reports back
+enum TaskMsg {
+    TaskFound(~Program),
+    TaskTimedOut,
+}
+
 pub struct RandomGen(Chan<GenMsg>);
 
 impl RandomGen {
     pub fn new(problem: Problem, constraints: ~[(u64, u64)]) -> RandomGen {
+        RandomGen::with_config(GenConfig::from_env(), problem, constraints)
+    }
+
+    pub fn with_config(config: GenConfig, problem: Problem,
+                       constraints: ~[(u64, u64)]) -> RandomGen {
         let (port, chan) = comm::stream();
 
         let port = Cell::new(port);
         do task::spawn_sched(task::SingleThreaded) {
-            RandomGen::generate(problem.clone(), constraints.clone(), port.take());
+            RandomGen::generate(config.clone(), problem.clone(), constraints.clone(),
+                                port.take());
         }
 
         RandomGen(chan)
     }
 
     pub fn blank() -> RandomGen {
-        RandomGen::new(
+        RandomGen::blank_with(GenConfig::from_env())
+    }
+
+    pub fn blank_with(config: GenConfig) -> RandomGen {
+        RandomGen::with_config(
+            config,
             Problem {
                 size: 3,
                 operators: OperatorSet::new(),
@@ -51,9 +183,11 @@ impl RandomGen {
         (**self).send(Reset(problem, constraints));
     }
 
-    pub fn next(&mut self) -> Option<~Program> {
+    // Find a candidate satisfying every constraint so far, giving up at
+    // `deadline`.
+    pub fn next(&mut self, deadline: Deadline) -> GenResult {
         let (port, chan) = comm::stream();
-        (**self).send(Generate(chan));
+        (**self).send(Generate(deadline, chan));
         port.recv()
     }
 
@@ -61,8 +195,10 @@ impl RandomGen {
         (**self).send(MoreConstraints(cs));
     }
 
-    fn generate(mut problem: Problem, mut constraints: ~[(u64, u64)], port: Port<GenMsg>) {
+    fn generate(config: GenConfig, mut problem: Problem, mut constraints: ~[(u64, u64)],
+                port: Port<GenMsg>) {
         let mut gen = RandomGenState::new(problem.clone());
+        gen.covering = config.strategy == Covering;
         loop {
             match port.try_recv() {
                 None => break,
@@ -75,7 +211,7 @@ impl RandomGen {
                 Some(MoreConstraints(c)) => {
                     constraints.push_all_move(c)
                 }
-                Some(Generate(chan)) => {
+                Some(Generate(deadline, chan)) => {
                     let (inner_port, inner_chan) = stream();
                     let inner_chan = comm::SharedChan::new(inner_chan);
                     let stop_arc = arc::RWArc::new(false);
@@ -83,22 +219,24 @@ impl RandomGen {
 
                     let start_ns = time::precise_time_ns();
 
-                    let parallelism: uint = do os::getenv("PAR").chain |s| {
-                        FromStr::from_str(s)
-                    }.unwrap_or_default(DEFAULT_PARALLELISM);
-                    let timeout_ns: u64 = do os::getenv("TIMEOUT").chain |s| {
-                        FromStr::from_str(s)
-                    }.unwrap_or_default(DEFAULT_TIMEOUT) * 1_000_000_000;
-
-                    for task_num in range(0, parallelism) {
+                    for task_num in range(0, config.parallelism) {
                         let task_chan = inner_chan.clone();
                         let task_stop_arc = stop_arc.clone();
                         let task_gen = Cell::new(gen.clone());
                         let task_constraints = constraints.clone();
+                        let task_deadline = deadline.clone();
 
                         do spawn {
                             let mut task_gen = task_gen.take();
 
+                            // almost every candidate fails on the first constraint
+                            // or two, so those get a quick check on their own, in
+                            // which shared subterms are only evaluated once
+                            let quick = task_constraints.slice(0, cmp::min(QUICK_CHECK, task_constraints.len()));
+                            let inputs = quick.iter().transform(|&(x, _)| x).collect();
+                            let expected: ~[u64] = quick.iter().transform(|&(_, y)| y).collect();
+                            let mut arena = ExprArena::new(inputs);
+
                             let mut i = 0;
                             'newprog: loop {
                                 i += 1;
@@ -108,24 +246,37 @@ impl RandomGen {
                                         break
                                     }
 
+                                    if task_deadline.expired() {
+                                        printfln!("task %u timed out", task_num);
+                                        task_chan.send(TaskTimedOut);
+                                        break
+                                    }
+
                                     // required for any parallelism at all.
                                     task::yield();
                                 }
 
+                                if i % 1000000 == 0 {
+                                    let elapsed = time::precise_time_ns() - start_ns;
+                                    printfln!("gen stats: task %u: searched for %uMiter (%uns/iter)",
+                                              task_num, i / 1_000_000, (elapsed / (i as u64)) as uint);
+                                }
+
                                 let prog = task_gen.gen_program(problem_size);
                                 //println(prog.to_str());
 
-                                if task_constraints.iter().any(|&(x,y)| prog.eval(x) != y) {
-                                    if i % 1000000 == 0 {
-                                        let elapsed = time::precise_time_ns() - start_ns;
-                                        printfln!("gen stats: task %u: searched for %uMiter (%uns/iter)",
-                                                  task_num, i / 1_000_000, (elapsed / (i as u64)) as uint);
-                                        if elapsed > timeout_ns {
-                                            printfln!("task %u timed out", task_num);
-                                            task_chan.send(None);
-                                            break 'newprog;
-                                        }
-                                    }
+                                // no point evaluating something that can't be the answer
+                                if !task_gen.covers() {
+                                    loop 'newprog;
+                                }
+
+                                if arena.bytes() > MAX_ARENA_BYTES {
+                                    arena.clear();
+                                }
+                                let node = arena.intern(&prog);
+
+                                if arena.matching_prefix(node, expected) < expected.len() ||
+                                    !bitslice::check_constraints(&prog, task_constraints) {
                                     loop 'newprog;
                                 }
                                 if i > 1 {
@@ -134,16 +285,27 @@ impl RandomGen {
                                               task_num, i / 1000000, (elapsed / 1000000) as uint);
                                 }
 
-                                task_chan.send(Some(~prog));
+                                task_chan.send(TaskFound(~prog));
                                 break;
                             }
                         }
                     }
 
-                    let prog = inner_port.recv();
+                    // wait for a win, or for every task to give up
+                    let mut result = TimedOut;
+                    for _ in range(0, config.parallelism) {
+                        match inner_port.recv() {
+                            TaskFound(prog) => {
+                                result = Found(prog);
+                                break;
+                            }
+                            TaskTimedOut => {}
+                        }
+                    }
+                    // stop anything still running
                     stop_arc.write(|stop| *stop = true);
 
-                    chan.send(prog);
+                    chan.send(result);
                 }
             }
         }
@@ -153,6 +315,17 @@ impl RandomGen {
 struct RandomGenState {
     rng: XorShiftRng,
     operators: OperatorSet,
+    // the operators the problem lists, all of which must appear
+    required: OperatorSet,
+    // what the program currently being generated has yet to place of
+    // those: bitmasks of ops (by `op as uint`), and whether it still
+    // needs an if0 and a fold
+    unused_op1: uint,
+    unused_op2: uint,
+    unused_if0: bool,
+    unused_fold: bool,
+    // whether to prefer those
+    covering: bool,
     op1_len: uint,
     op1_choices: ~[UnaOp],
     op2_len: uint,
@@ -164,6 +337,12 @@ impl Clone for RandomGenState {
         RandomGenState {
             rng: seeded_rng(), // need a new rng
             operators: self.operators.clone(),
+            required: self.required.clone(),
+            unused_op1: self.unused_op1,
+            unused_op2: self.unused_op2,
+            unused_if0: self.unused_if0,
+            unused_fold: self.unused_fold,
+            covering: self.covering,
             op1_len: self.op1_len,
             op1_choices: self.op1_choices.clone(),
             op2_len: self.op2_len,
@@ -172,6 +351,29 @@ impl Clone for RandomGenState {
     }
 }
 
+// A random program of about `size`, from `operators`, drawn the way
+// candidates are; for making up problems to solve. None if the generator
+// can't fill that shape: it needs a unary and a binary op to fill slots
+// of any size, and room for the fixed parts of tfold and bonus programs.
+pub fn random_program(size: u8, operators: OperatorSet) -> Option<Program> {
+    let min_size = if operators.has(OpTfold) {
+        6
+    } else if operators.has(OpBonus) {
+        13
+    } else {
+        2
+    };
+    let mut state = RandomGenState::new(Problem {
+        id: ~"",
+        size: size,
+        operators: operators,
+    });
+    if state.op1_len == 0 || state.op2_len == 0 || (size as uint) < min_size {
+        return None;
+    }
+    Some(state.gen_program(size as uint))
+}
+
 fn seeded_rng() -> XorShiftRng {
     let mut seed_rng = task_rng();
     XorShiftRng::new_seeded(seed_rng.gen::<u32>(),
@@ -194,7 +396,13 @@ impl RandomGenState {
 
         RandomGenState {
             rng: rng,
+            required: problem.operators.clone(),
             operators: problem.operators,
+            unused_op1: 0,
+            unused_op2: 0,
+            unused_if0: false,
+            unused_fold: false,
+            covering: true,
             op1_len: op1_choices.len(),
             op1_choices: op1_choices,
             op2_len: op2_choices.len(),
@@ -210,6 +418,7 @@ impl RandomGenState {
             .filter(|o| o.in_ops(&problem.operators))
             .collect();
 
+        self.required = problem.operators.clone();
         self.operators = problem.operators;
         self.op1_len = op1_choices.len();
         self.op1_choices = op1_choices;
@@ -219,18 +428,24 @@ impl RandomGenState {
 
     fn gen_program(&mut self, size: uint) -> Program {
         //println("gen_program");
-        if self.operators.tfold {
+        self.unused_op1 = op_mask(self.op1_choices.iter().transform(|&o| o as uint));
+        self.unused_op2 = op_mask(self.op2_choices.iter().transform(|&o| o as uint));
+        self.unused_if0 = self.required.has(OpIf0);
+        self.unused_fold = self.required.has(OpFold) || self.required.has(OpTfold);
+
+        if self.operators.has(OpTfold) {
             self.gen_tfold(size)
-        } else if self.operators.bonus {
+        } else if self.operators.has(OpBonus) {
             self.gen_bonus(size)
         } else {
-            Program::new(0, ~self.gen_expr(size - 1, 1, self.operators.fold))
+            Program::new(0, ~self.gen_expr(size - 1, 1, self.operators.has(OpFold)))
         }
     }
 
     fn gen_tfold(&mut self, size: uint) -> Program {
         // remove the sizes of the program, fold, x and 0
         let body = ~self.gen_expr(size - 1 - 2 - 1 - 1, 2, false);
+        self.unused_fold = false;
 
         // use 2 here, because it won't be referred to in the body
         // ever. i.e. it's shadowed.
@@ -249,7 +464,7 @@ impl RandomGenState {
         let remaining = size - 1 - 1;
 
         // seems that only one if0 occurs, so disable it from here.
-        self.operators.if0 = false;
+        self.operators.remove(OpIf0);
 
         // the second arg to the conditional (and ..)
         let atomic = self.gen_expr(1, 1, false);
@@ -260,7 +475,7 @@ impl RandomGenState {
 
         // make partial_cond a binop
         if partial_cond_s < 3 { partial_cond_s = 3; }
-        let partial_cond_op = self.rng.choose(self.op2_choices);
+        let partial_cond_op = self.choose_op2();
         let pc_left_s = self.gen_size(partial_cond_s - 2, false);
         let pc_right_s = partial_cond_s - 1 - pc_left_s;
         //printfln!("pc size = %u, pc left = %u, pc right = %u", partial_cond_s, pc_left_s, pc_right_s);
@@ -271,6 +486,7 @@ impl RandomGenState {
 
         let partial_cond = Op2(partial_cond_op, ~pc_left, ~pc_right);
         let cond = Op2(And, ~partial_cond, ~atomic);
+        self.unused_op2 &= !(1 << And as uint);
 
         let cond_s = partial_cond_s + 2;
 
@@ -295,7 +511,7 @@ impl RandomGenState {
             //printfln!("arg1 = %u, arg2 = %u", left_arg1_s, left_arg2_s);
         
             //println("left arm arg1 is binop");
-            let left_op = self.rng.choose(self.op2_choices);
+            let left_op = self.choose_op2();
             //println("genning left arm arg1");
             let left_arg1 = self.gen_expr(left_arg1_s, 1, false);
             //println("genning left arm arg2");
@@ -309,7 +525,7 @@ impl RandomGenState {
             //printfln!("left arg1 = %u", left_arg1_s);
 
             //println("left arm arg1 is unaop");
-            let left_op = self.rng.choose(self.op1_choices);
+            let left_op = self.choose_op1();
             //println("genning left arm arg1");
             let left_arg1 = self.gen_expr(left_arg1_s, 1, false);
 
@@ -326,7 +542,7 @@ impl RandomGenState {
             let right_arg2_s = right_arm_s - 1 - right_arg1_s;
             assert!(right_arm_s == 1 + right_arg1_s + right_arg2_s);
 
-            let right_op = self.rng.choose(self.op2_choices);
+            let right_op = self.choose_op2();
             let right_arg1 = self.gen_expr(right_arg1_s, 1, false);
             let right_arg2 = self.gen_expr(right_arg2_s, 1, false);
 
@@ -335,13 +551,14 @@ impl RandomGenState {
             // leave room for op and for arg2
             let right_arg1_s = right_arm_s - 1;
 
-            let right_op = self.rng.choose(self.op1_choices);
+            let right_op = self.choose_op1();
             let right_arg1 = self.gen_expr(right_arg1_s, 1, false);
 
             Op1(right_op, ~right_arg1)
         };
 
         let expr = If0(~cond, ~left_arm, ~right_arm);
+        self.unused_if0 = false;
         let prog = Program::new(0, ~expr);
 
         prog
@@ -393,7 +610,7 @@ impl RandomGenState {
                 // UnaOp (op1_len)
                 assert!(self.op1_len > 0);
 
-                let op = self.rng.choose(self.op1_choices);
+                let op = self.choose_op1();
                 let expr = self.gen_expr(1, idents, foldable);
                 Op1(op, ~expr)
             }
@@ -406,7 +623,9 @@ impl RandomGenState {
                         assert!(self.op1_len > 0);
 
                         let expr = self.gen_expr(2, idents, foldable);
-                        Op1(self.op1_choices[n], ~expr)
+                        let op = self.op1_choices[n];
+                        let op = self.prefer_unused_op1(op);
+                        Op1(op, ~expr)
                     }
                     n => {
                         assert!(self.op2_len > 0);
@@ -415,7 +634,9 @@ impl RandomGenState {
                         let left = self.gen_expr(1, idents, foldable);
                         //println("genning right");
                         let right = self.gen_expr(1, idents, foldable);
-                        Op2(self.op2_choices[n - self.op1_len], ~left, ~right)
+                        let op = self.op2_choices[n - self.op1_len];
+                        let op = self.prefer_unused_op2(op);
+                        Op2(op, ~left, ~right)
                     }
                 }
             }
@@ -429,7 +650,7 @@ impl RandomGenState {
                 let gen_binop = self.op1_len > 0;
 
                 // If there are no unaops, then we must be able to place an if or fold
-                assert!(self.op1_len > 0 || self.operators.if0 || foldable);
+                assert!(self.op1_len > 0 || self.operators.has(OpIf0) || foldable);
 
                 let mut choices = self.op1_len;
                 let op1_end = self.op1_len;
@@ -438,7 +659,7 @@ impl RandomGenState {
                 }
                 let op2_end = choices;
 
-                if self.operators.if0 {
+                if self.operators.has(OpIf0) {
                     choices += 1;
                 }
                 let if_end = choices;
@@ -448,7 +669,9 @@ impl RandomGenState {
                         assert!(self.op1_len > 0);
 
                         let expr = self.gen_expr(3, idents, foldable);
-                        Op1(self.op1_choices[n], ~expr)
+                        let op = self.op1_choices[n];
+                        let op = self.prefer_unused_op1(op);
+                        Op1(op, ~expr)
                     }
                     n if n < op2_end => {
                         //println("genning binop");
@@ -464,12 +687,12 @@ impl RandomGenState {
                         let left = self.gen_expr(left_size, idents, foldable);
                         //println("genning right");
                         let right = self.gen_expr(right_size, idents, foldable);
-                        let op = self.rng.choose(self.op2_choices);
+                        let op = self.choose_op2();
                         Op2(op, ~left, ~right)
                     }
                     n if n < if_end => {
                         //println("genning if0");
-                        assert!(self.operators.if0);
+                        assert!(self.operators.has(OpIf0));
 
                         //println("genning test");
                         let test = self.gen_expr(1, idents, foldable);
@@ -477,6 +700,7 @@ impl RandomGenState {
                         let then = self.gen_expr(1, idents, foldable);
                         //println("genning other");
                         let other = self.gen_expr(1, idents, foldable);
+                        self.unused_if0 = false;
                         If0(~test, ~then, ~other)
                     }
                     _ => fail!("bad choice"),
@@ -494,7 +718,7 @@ impl RandomGenState {
                 let mut choices = self.op1_len;
 
                 let have_unaops = self.op1_len > 0;
-                let have_if0 = self.operators.if0;
+                let have_if0 = self.operators.has(OpIf0);
 
                 // we can't generate even sized binops without unaops or if
                 let gen_binop = have_unaops || size.is_odd() || have_if0;
@@ -539,7 +763,9 @@ impl RandomGenState {
                         assert!(self.op1_len > 0);
 
                         let expr = self.gen_expr(size - 1, idents, foldable);
-                        Op1(self.op1_choices[n], ~expr)
+                        let op = self.op1_choices[n];
+                        let op = self.prefer_unused_op1(op);
+                        Op1(op, ~expr)
                     }
                     n if n < op2_end => {
                         //println("genning binop");
@@ -563,12 +789,12 @@ impl RandomGenState {
                         let left = self.gen_expr(left_size, idents, foldable);
                         //println("genning right");
                         let right = self.gen_expr(right_size, idents, foldable);
-                        let op = self.rng.choose(self.op2_choices);
+                        let op = self.choose_op2();
                         Op2(op, ~left, ~right)
                     }
                     n if n < if_end => {
                         //printfln!("genning if size %u", size);
-                        assert!(self.operators.if0);
+                        assert!(self.operators.has(OpIf0));
                         assert!(self.op1_len > 0 || size != 5);
 
                         let size = size - 1; // acount for |if|
@@ -594,6 +820,7 @@ impl RandomGenState {
                         let then = self.gen_expr(then_size, idents, foldable);
                         //println("genning other");
                         let other = self.gen_expr(other_size, idents, foldable);
+                        self.unused_if0 = false;
                         If0(~test, ~then, ~other)
                     }
                     _ => {
@@ -627,6 +854,7 @@ impl RandomGenState {
                         let init = self.gen_expr(init_size, idents, false);
                         //println("genning body");
                         let body = self.gen_expr(body_size, idents + 2, false);
+                        self.unused_fold = false;
 
                         Fold {
                             foldee: ~foldee,
@@ -641,6 +869,49 @@ impl RandomGenState {
         }
     }
 
+    // Pick a random unary op, preferring a required one that hasn't been
+    // placed in the current program yet.
+    fn choose_op1(&mut self) -> UnaOp {
+        let op = self.rng.choose(self.op1_choices);
+        self.prefer_unused_op1(op)
+    }
+
+    fn choose_op2(&mut self) -> BinOp {
+        let op = self.rng.choose(self.op2_choices);
+        self.prefer_unused_op2(op)
+    }
+
+    // Replace `op` with a random still-unused required op (if there is
+    // one, and the strategy is Covering) and mark the result as used.
+    fn prefer_unused_op1(&mut self, op: UnaOp) -> UnaOp {
+        let op = if !self.covering || self.unused_op1 == 0 {
+            op
+        } else {
+            OP1_CHOICE[pick_bit(&mut self.rng, self.unused_op1)]
+        };
+        self.unused_op1 &= !(1 << op as uint);
+        op
+    }
+
+    fn prefer_unused_op2(&mut self, op: BinOp) -> BinOp {
+        let op = if !self.covering || self.unused_op2 == 0 {
+            op
+        } else {
+            OP2_CHOICE[pick_bit(&mut self.rng, self.unused_op2)]
+        };
+        self.unused_op2 &= !(1 << op as uint);
+        op
+    }
+
+    // Has the program just generated placed every operator the problem
+    // lists? Biasing the op choices gets most of the way there, but if0
+    // and fold are only placed structurally, so whatever is left over
+    // gets rejected here, long before it gets evaluated.
+    fn covers(&self) -> bool {
+        self.unused_op1 == 0 && self.unused_op2 == 0 &&
+            !self.unused_if0 && !self.unused_fold
+    }
+
     // Generate a size for a slot
     //
     // If we can use UnaOps, this is trivial, as all sizes are valid.
@@ -656,7 +927,7 @@ impl RandomGenState {
         assert!(space >= 1);
 
         let have_unaops = self.op1_len > 0;
-        let have_fold_or_if0 = foldable || self.operators.if0;
+        let have_fold_or_if0 = foldable || self.operators.has(OpIf0);
 
         let choice = self.rng.gen_uint_range(1, space + 1);
 
@@ -705,7 +976,7 @@ impl RandomGenState {
     fn check_size(&mut self, choice: uint, foldable: bool) -> bool {
         //printfln!("check_size(%u)", choice);
         let have_unaops = self.op1_len > 0;
-        let have_fold_or_if0 = foldable || self.operators.if0;
+        let have_fold_or_if0 = foldable || self.operators.has(OpIf0);
 
         if have_unaops { return true; }
         if !have_fold_or_if0 && choice.is_even() { return false; }
@@ -715,6 +986,35 @@ impl RandomGenState {
     }
 }
 
+fn op_mask<I: Iterator<uint>>(mut ops: I) -> uint {
+    let mut mask = 0;
+    for op in ops {
+        mask |= 1 << op;
+    }
+    mask
+}
+
+// The index of a random set bit of a non-empty `mask`.
+fn pick_bit<R: Rng>(rng: &mut R, mask: uint) -> uint {
+    let mut nth = rng.gen_uint_range(0, count_bits(mask));
+    let mut bit = 0;
+    loop {
+        if mask & (1 << bit) != 0 {
+            if nth == 0 { return bit; }
+            nth -= 1;
+        }
+        bit += 1;
+    }
+}
+
+fn count_bits(mut mask: uint) -> uint {
+    let mut n = 0;
+    while mask != 0 {
+        mask &= mask - 1;
+        n += 1;
+    }
+    n
+}
 
 #[cfg(test)]
 mod tests {
@@ -722,6 +1022,7 @@ mod tests {
     use super::*;
     use webapi::*;
     use program::*;
+    use std::from_str::FromStr;
 
     #[bench]
     fn bench_gen_prog(bh: &mut BenchHarness) {
@@ -734,7 +1035,7 @@ mod tests {
         };
         let mut gen = RandomGen::new(problem, ~[]);
         do bh.iter {
-            gen.next();
+            gen.next(Deadline::in_secs(60f));
         }
     }
 
@@ -749,10 +1050,81 @@ mod tests {
         };
         let mut gen = RandomGen::new(problem, ~[]);
         for _ in range(0, 10) {
-            gen.next();
+            gen.next(Deadline::in_secs(60f));
         }
     }
 
+    #[test]
+    fn generated_programs_cover_operators() {
+        let mut opset = OperatorSet::new();
+        opset.add(~[~"not", ~"shr4", ~"and", ~"plus", ~"if0"]);
+        let problem = Problem {
+            id: ~"generated_programs_cover_operators",
+            size: 14,
+            operators: opset.clone(),
+        };
+        let mut gen = RandomGen::new(problem, ~[]);
+        for _ in range(0, 10) {
+            match gen.next(Deadline::in_secs(60f)) {
+                Found(prog) => assert!(opset.covered_by(&prog.operators())),
+                TimedOut => fail!("no program generated"),
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_programs_cover_operators() {
+        let mut opset = OperatorSet::new();
+        opset.add(~[~"not", ~"shr4", ~"and", ~"plus"]);
+        let problem = Problem {
+            id: ~"uniform_programs_cover_operators",
+            size: 10,
+            operators: opset.clone(),
+        };
+        let mut config = GenConfig::from_env();
+        config.strategy = Uniform;
+        let mut gen = RandomGen::with_config(config, problem, ~[]);
+        for _ in range(0, 10) {
+            match gen.next(Deadline::in_secs(60f)) {
+                Found(prog) => assert!(opset.covered_by(&prog.operators())),
+                TimedOut => fail!("no program generated"),
+            }
+        }
+        let strategy: Option<Strategy> = FromStr::from_str("uniform");
+        assert_eq!(strategy, Some(Uniform));
+    }
+
+    #[test]
+    fn coverage_masks_match_the_program() {
+        let mut opset = OperatorSet::new();
+        opset.add(~[~"not", ~"shr4", ~"and", ~"plus", ~"if0", ~"fold"]);
+        let mut state = RandomGenState::new(Problem {
+            id: ~"coverage_masks_match_the_program",
+            size: 16,
+            operators: opset.clone(),
+        });
+        for _ in range(0, 1000) {
+            let prog = state.gen_program(16);
+            assert_eq!(state.covers(), opset.covered_by(&prog.operators()));
+        }
+    }
+
+    #[test]
+    fn random_programs_of_fillable_shapes() {
+        let mut opset = OperatorSet::new();
+        opset.add(~[~"not", ~"plus", ~"tfold"]);
+        let p = random_program(9, opset.clone()).unwrap();
+        assert_eq!(p.len(), 9);
+        assert!(p.is_tfold());
+        // too small for the fold around the body
+        assert!(random_program(5, opset).is_none());
+
+        // no unary ops to fill an even slot with
+        let mut opset = OperatorSet::new();
+        opset.add(~[~"and", ~"plus"]);
+        assert!(random_program(10, opset).is_none());
+    }
+
     #[test]
     fn no_unaops_noif_fold() {
         let mut opset = OperatorSet::new();
@@ -764,7 +1136,7 @@ mod tests {
         };
         let mut gen = RandomGen::new(problem, ~[]);
         for _ in range(0, 10) {
-            gen.next();
+            gen.next(Deadline::in_secs(60f));
         }
     }
 }
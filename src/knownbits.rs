@@ -0,0 +1,355 @@
+use program::*;
+
+// An abstract value: each bit of the concrete value is either known to be
+// 0 (set in `zeros`), known to be 1 (set in `ones`) or unknown (set in
+// neither). A bit is never set in both.
+#[deriving(Eq, Clone)]
+pub struct KnownBits {
+    zeros: u64,
+    ones: u64,
+}
+
+impl KnownBits {
+    pub fn unknown() -> KnownBits {
+        KnownBits { zeros: 0, ones: 0 }
+    }
+
+    pub fn constant(x: u64) -> KnownBits {
+        KnownBits { zeros: !x, ones: x }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        (self.zeros | self.ones) == !0
+    }
+
+    // Could a concrete value `x` be described by this?
+    pub fn admits(&self, x: u64) -> bool {
+        (x & self.zeros) == 0 && (!x & self.ones) == 0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.zeros == !0
+    }
+
+    pub fn is_nonzero(&self) -> bool {
+        self.ones != 0
+    }
+
+    // The knowledge common to both: what we know about a value that is
+    // one or the other.
+    pub fn join(&self, other: &KnownBits) -> KnownBits {
+        KnownBits {
+            zeros: self.zeros & other.zeros,
+            ones: self.ones & other.ones,
+        }
+    }
+
+    pub fn not(&self) -> KnownBits {
+        KnownBits { zeros: self.ones, ones: self.zeros }
+    }
+
+    // bits shifted in are known zeros
+    pub fn shl(&self, n: uint) -> KnownBits {
+        KnownBits {
+            zeros: (self.zeros << n) | !(!0u64 << n),
+            ones: self.ones << n,
+        }
+    }
+
+    pub fn shr(&self, n: uint) -> KnownBits {
+        KnownBits {
+            zeros: (self.zeros >> n) | !(!0u64 >> n),
+            ones: self.ones >> n,
+        }
+    }
+
+    pub fn and(&self, other: &KnownBits) -> KnownBits {
+        KnownBits {
+            zeros: self.zeros | other.zeros,
+            ones: self.ones & other.ones,
+        }
+    }
+
+    pub fn or(&self, other: &KnownBits) -> KnownBits {
+        KnownBits {
+            zeros: self.zeros & other.zeros,
+            ones: self.ones | other.ones,
+        }
+    }
+
+    pub fn xor(&self, other: &KnownBits) -> KnownBits {
+        KnownBits {
+            zeros: (self.zeros & other.zeros) | (self.ones & other.ones),
+            ones: (self.zeros & other.ones) | (self.ones & other.zeros),
+        }
+    }
+
+    // Addition with no carry in. The smallest possible sum (all unknowns
+    // 0) and the largest (all unknowns 1) tell us which carries are
+    // fixed; a sum bit is known when both inputs and the carry into it
+    // are.
+    pub fn plus(&self, other: &KnownBits) -> KnownBits {
+        let max_sum = !self.zeros + !other.zeros;
+        let min_sum = self.ones + other.ones;
+
+        let carry_zeros = !(max_sum ^ self.zeros ^ other.zeros);
+        let carry_ones = min_sum ^ self.ones ^ other.ones;
+
+        let known = (self.zeros | self.ones) & (other.zeros | other.ones) &
+            (carry_zeros | carry_ones);
+
+        KnownBits {
+            zeros: !max_sum & known,
+            ones: min_sum & known,
+        }
+    }
+
+    // The `n`th lowest byte, as fold hands it to its body.
+    pub fn byte(&self, n: uint) -> KnownBits {
+        let shifted = self.shr(n * 8);
+        KnownBits {
+            zeros: shifted.zeros | !0xff,
+            ones: shifted.ones & 0xff,
+        }
+    }
+}
+
+// A program body partway through top-down generation: finished subtrees
+// are plain Exprs, the rest are still holes. It is its own type so that
+// the evaluators, compilers and the arena never see a hole.
+pub enum Partial {
+    Hole,
+    Complete(~Expr),
+    PIf0(~Partial, ~Partial, ~Partial),
+    POp1(UnaOp, ~Partial),
+    POp2(BinOp, ~Partial, ~Partial),
+    PFold {
+        foldee: ~Partial,
+        init: ~Partial,
+        next_id: Id,
+        accum_id: Id,
+        body: ~Partial
+    },
+}
+
+pub struct PartialProgram {
+    id: Id,
+    body: ~Partial,
+}
+
+impl PartialProgram {
+    pub fn new(id: Id, body: ~Partial) -> PartialProgram {
+        PartialProgram { id: id, body: body }
+    }
+}
+
+// Bindings for the abstract evaluation; unlike eval's Scope this is
+// just a stack, since fold bodies are evaluated with the bindings pushed
+// and then popped again.
+struct AbstractScope {
+    vars: ~[(Id, KnownBits)],
+}
+
+// Something AbstractScope can evaluate: a finished Expr, or a Partial
+// with holes in it.
+trait Abstract {
+    fn abstract_eval(&self, scope: &mut AbstractScope) -> KnownBits;
+}
+
+impl Abstract for Expr {
+    fn abstract_eval(&self, scope: &mut AbstractScope) -> KnownBits {
+        match *self {
+            Zero => KnownBits::constant(0),
+            One => KnownBits::constant(1),
+            Ident(id) => scope.lookup(id),
+            If0(~ref cond, ~ref then, ~ref els) => scope.if0(cond, then, els),
+            Op1(op, ~ref e) => op1(op, &e.abstract_eval(scope)),
+            Op2(op, ~ref e1, ~ref e2) => {
+                let e1 = e1.abstract_eval(scope);
+                op2(op, &e1, &e2.abstract_eval(scope))
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id: next_id, accum_id: accum_id,
+                body: ~ref body
+            } => scope.fold(foldee, init, next_id, accum_id, body),
+        }
+    }
+}
+
+impl Abstract for Partial {
+    fn abstract_eval(&self, scope: &mut AbstractScope) -> KnownBits {
+        match *self {
+            Hole => KnownBits::unknown(),
+            Complete(~ref e) => e.abstract_eval(scope),
+            PIf0(~ref cond, ~ref then, ~ref els) => scope.if0(cond, then, els),
+            POp1(op, ~ref e) => op1(op, &e.abstract_eval(scope)),
+            POp2(op, ~ref e1, ~ref e2) => {
+                let e1 = e1.abstract_eval(scope);
+                op2(op, &e1, &e2.abstract_eval(scope))
+            }
+            PFold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id: next_id, accum_id: accum_id,
+                body: ~ref body
+            } => scope.fold(foldee, init, next_id, accum_id, body),
+        }
+    }
+}
+
+impl AbstractScope {
+    fn lookup(&self, id: Id) -> KnownBits {
+        for &(var, val) in self.vars.rev_iter() {
+            if var == id {
+                return val;
+            }
+        }
+        // be conservative about things we don't know about
+        KnownBits::unknown()
+    }
+
+    fn if0<T: Abstract>(&mut self, cond: &T, then: &T, els: &T) -> KnownBits {
+        let cond = cond.abstract_eval(self);
+        if cond.is_zero() {
+            then.abstract_eval(self)
+        } else if cond.is_nonzero() {
+            els.abstract_eval(self)
+        } else {
+            let then = then.abstract_eval(self);
+            then.join(&els.abstract_eval(self))
+        }
+    }
+
+    fn fold<T: Abstract>(&mut self, foldee: &T, init: &T, next_id: Id, accum_id: Id,
+                         body: &T) -> KnownBits {
+        let foldee = foldee.abstract_eval(self);
+        let mut accum = init.abstract_eval(self);
+
+        for i in range(0u, 8) {
+            self.vars.push((next_id, foldee.byte(i)));
+            self.vars.push((accum_id, accum));
+
+            accum = body.abstract_eval(self);
+
+            self.vars.pop();
+            self.vars.pop();
+        }
+
+        accum
+    }
+}
+
+fn op1(op: UnaOp, e: &KnownBits) -> KnownBits {
+    match op {
+        Not => e.not(),
+        Shl1 => e.shl(1),
+        Shr1 => e.shr(1),
+        Shr4 => e.shr(4),
+        Shr16 => e.shr(16),
+    }
+}
+
+fn op2(op: BinOp, e1: &KnownBits, e2: &KnownBits) -> KnownBits {
+    match op {
+        And => e1.and(e2),
+        Or => e1.or(e2),
+        Xor => e1.xor(e2),
+        Plus => e1.plus(e2),
+    }
+}
+
+// What is known about the output of `p` on `input`, whatever its holes
+// are filled with.
+pub fn abstract_eval(p: &PartialProgram, input: u64) -> KnownBits {
+    let mut scope = AbstractScope {
+        vars: ~[(p.id, KnownBits::constant(input))]
+    };
+    p.body.abstract_eval(&mut scope)
+}
+
+// Is there any completion of `p` that could map `input` to `output`? A
+// `false` is a proof that there isn't; `true` just means we don't know.
+pub fn may_produce(p: &PartialProgram, input: u64, output: u64) -> bool {
+    abstract_eval(p, input).admits(output)
+}
+
+// Could some completion of `p` satisfy all of the constraints?
+pub fn feasible(p: &PartialProgram, constraints: &[(u64, u64)]) -> bool {
+    constraints.iter().all(|&(x, y)| may_produce(p, x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use eval::Eval;
+    use std::rand;
+
+    // a random abstraction of `x`, knowing roughly 3/4 of its bits
+    fn forget_some(x: u64) -> KnownBits {
+        let known = rand::random::<u64>() | rand::random::<u64>();
+        KnownBits { zeros: !x & known, ones: x & known }
+    }
+
+    #[test]
+    fn test_plus_sound() {
+        for _ in range(0, 1000) {
+            let a: u64 = rand::random();
+            let b: u64 = rand::random();
+            let abs = forget_some(a).plus(&forget_some(b));
+            assert!(abs.admits(a + b));
+        }
+    }
+
+    #[test]
+    fn test_plus_constant() {
+        for _ in range(0, 100) {
+            let a: u64 = rand::random();
+            let b: u64 = rand::random();
+            let abs = KnownBits::constant(a).plus(&KnownBits::constant(b));
+            assert_eq!(abs, KnownBits::constant(a + b));
+        }
+    }
+
+    #[test]
+    fn test_complete_program_is_exact() {
+        let fold_expr = ~Fold {
+            foldee: ~Ident(0),
+            init: ~One,
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Xor, ~Op1(Shl1, ~Ident(2)), ~Ident(1))
+        };
+        let progs = ~[
+            Program::new(0, ~If0(~Op2(And, ~Ident(0), ~One), ~Op1(Not, ~Ident(0)), ~Zero)),
+            Program::new(0, ~Op2(Plus, ~Op1(Shr4, ~Ident(0)), ~Ident(0))),
+            Program::new(0, fold_expr),
+        ];
+
+        for p in progs.iter() {
+            let partial = PartialProgram::new(p.id, ~Complete(p.expr.clone()));
+            for _ in range(0, 100) {
+                let x: u64 = rand::random();
+                assert_eq!(abstract_eval(&partial, x), KnownBits::constant(p.eval(x)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_shr16_hole() {
+        let p = PartialProgram::new(0, ~POp1(Shr16, ~Hole));
+        assert!(may_produce(&p, 1, 0x0000_1234_5678_9abc));
+        assert!(!may_produce(&p, 1, 1 << 50));
+        assert!(!feasible(&p, [(0, 0), (1, 0xffff_0000_0000_0000)]));
+    }
+
+    #[test]
+    fn test_if0_hole() {
+        // the condition is known to be nonzero, so only the hole-free
+        // arm matters
+        let p = PartialProgram::new(0, ~PIf0(~POp2(Or, ~Hole, ~Complete(~One)), ~Hole,
+                                              ~Complete(~Op1(Shl1, ~Ident(0)))));
+        assert!(may_produce(&p, 3, 6));
+        assert!(!may_produce(&p, 3, 7));
+    }
+}
@@ -1,16 +1,40 @@
 use std::hashmap::HashMap;
 use program::*;
+use eval::ValidProgram;
 
 pub trait Parse {
-    pub fn parse(&self) -> Program;
+    pub fn parse(&self) -> Result<Program, ~str>;
 }
 
 impl<'self> Parse for &'self str {
-    pub fn parse(&self) -> Program {
+    pub fn parse(&self) -> Result<Program, ~str> {
         Parser::new(*self).parse()
     }
 }
 
+// Parse and validate a program from somewhere we don't trust (the
+// command line, a guess), reporting what's wrong with it rather than
+// failing.
+pub fn parse_program(src: &str) -> Result<ValidProgram, ~str> {
+    match parse_program_names(src) {
+        Ok((p, _)) => Ok(p),
+        Err(e) => Err(e),
+    }
+}
+
+// parse_program, along with the names the identifiers had (for a
+// pretty::Printer to print it back out with).
+pub fn parse_program_names(src: &str) -> Result<(ValidProgram, HashMap<Id, ~str>), ~str> {
+    let mut parser = Parser::new(src);
+    match parser.parse() {
+        Err(e) => Err(e),
+        Ok(p) => match p.validated() {
+            Ok(p) => Ok((p, parser.names())),
+            Err(e) => Err(e.to_str()),
+        }
+    }
+}
+
 pub struct Parser<'self> {
     src: &'self str,
     interned: HashMap<~str, uint>,
@@ -26,20 +50,41 @@ impl<'self> Parser<'self> {
         }
     }
 
+    // The name each identifier had in the source, for printing it back
+    // out the same way.
+    pub fn names(&self) -> HashMap<Id, ~str> {
+        let mut names = HashMap::new();
+        for (name, &id) in self.interned.iter() {
+            names.insert(id, name.clone());
+        }
+        names
+    }
 
-    pub fn parse(&mut self) -> Program {
+    pub fn parse(&mut self) -> Result<Program, ~str> {
         self.skip_ws();
-        self.skip_str("(");
-        self.skip_str("lambda");
-        self.skip_str("(");
-        let id = self.consume_id();
-        self.skip_str(")");
-
-        let expr = self.consume_expr();
+        match self.skip_lambda() {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        let id = match self.consume_id() {
+            Ok(id) => id,
+            Err(e) => return Err(e),
+        };
+        match self.skip_str(")") {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
 
-        Program {
-            id: id,
-            expr: expr
+        let expr = match self.consume_expr() {
+            Ok(expr) => expr,
+            Err(e) => return Err(e),
+        };
+        match self.skip_str(")") {
+            Ok(()) => Ok(Program {
+                id: id,
+                expr: expr
+            }),
+            Err(e) => Err(e),
         }
     }
 
@@ -61,23 +106,41 @@ impl<'self> Parser<'self> {
         self.src = self.src.slice_from(offset);
     }
 
-    pub fn skip_str(&mut self, expected: &str) {
+    pub fn skip_str(&mut self, expected: &str) -> Result<(), ~str> {
         if self.src.starts_with(expected) {
             self.src = self.src.slice_from(expected.len());
             self.skip_ws();
+            Ok(())
         } else {
-            fail!("expected: %s, found: %s", expected, self.src)
+            Err(fmt!("expected: %s, found: %s", expected, self.src))
+        }
+    }
+
+    // "(lambda (", up to the first parameter.
+    fn skip_lambda(&mut self) -> Result<(), ~str> {
+        for s in ["(", "lambda", "("].iter() {
+            match self.skip_str(*s) {
+                Ok(()) => {}
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
     }
 
-    pub fn consume_expr(&mut self) -> ~Expr {
+    pub fn consume_expr(&mut self) -> Result<~Expr, ~str> {
+        if self.src.is_empty() {
+            return Err(~"expected an expression, found the end");
+        }
         let ret = match self.src.char_at(0) {
             '0' => { self.bump(); ~Zero }
             '1' => { self.bump(); ~One }
             '(' => {
                 self.bump();
 
-                let s = self.consume_ident_str();
+                let s = match self.consume_ident_str() {
+                    Ok(s) => s,
+                    Err(e) => return Err(e),
+                };
                 let r = match s.as_slice() {
                     "not" => self.consume_op1(Not),
                     "shl1" => self.consume_op1(Shl1),
@@ -88,67 +151,123 @@ impl<'self> Parser<'self> {
                     "or" => self.consume_op2(Or),
                     "xor" => self.consume_op2(Xor),
                     "plus" =>  self.consume_op2(Plus),
-                    "if0" => {
-                        let cond = self.consume_expr();
-                        let then = self.consume_expr();
-                        let els = self.consume_expr();
-                        ~If0(cond, then, els)
-                    }
-                    "fold" => {
-                        let foldee = self.consume_expr();
-                        let init = self.consume_expr();
-
-                        self.skip_str("(");
-                        self.skip_str("lambda");
-                        self.skip_str("(");
-
-                        let next = self.consume_id();
-                        let accum = self.consume_id();
-                        self.skip_str(")");
-
-                        let body = self.consume_expr();
-                        ~Fold {
-                            foldee: foldee,
-                            init: init,
-                            next_id: next,
-                            accum_id: accum,
-                            body: body
-                        }
-                    }
-                     _ => fail!("unrecognised op %s", s)
+                    "if0" => self.consume_if0(),
+                    "fold" => self.consume_fold(),
+                     _ => Err(fmt!("unrecognised op %s", s))
+                };
+                let r = match r {
+                    Ok(r) => r,
+                    Err(e) => return Err(e),
                 };
-                self.skip_str(")");
+                match self.skip_str(")") {
+                    Ok(()) => {}
+                    Err(e) => return Err(e),
+                }
                 r
             }
             _ => {
-                ~Ident(self.consume_id())
+                match self.consume_id() {
+                    Ok(id) => ~Ident(id),
+                    Err(e) => return Err(e),
+                }
             }
         };
         self.skip_ws();
-        ret
+        Ok(ret)
+    }
+
+    pub fn consume_op1(&mut self, op: UnaOp) -> Result<~Expr, ~str> {
+        match self.consume_expr() {
+            Ok(e) => Ok(~Op1(op, e)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn consume_op2(&mut self, op: BinOp) -> Result<~Expr, ~str> {
+        let e1 = match self.consume_expr() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        match self.consume_expr() {
+            Ok(e2) => Ok(~Op2(op, e1, e2)),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn consume_op1(&mut self, op: UnaOp) -> ~Expr {
-        ~Op1(op, self.consume_expr())
+    fn consume_if0(&mut self) -> Result<~Expr, ~str> {
+        let cond = match self.consume_expr() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let then = match self.consume_expr() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        match self.consume_expr() {
+            Ok(els) => Ok(~If0(cond, then, els)),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn consume_op2(&mut self, op: BinOp) -> ~Expr {
-        let e1 = self.consume_expr();
-        let e2 = self.consume_expr();
-        ~Op2(op, e1, e2)
+    fn consume_fold(&mut self) -> Result<~Expr, ~str> {
+        let foldee = match self.consume_expr() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        let init = match self.consume_expr() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        match self.skip_lambda() {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        let next = match self.consume_id() {
+            Ok(id) => id,
+            Err(e) => return Err(e),
+        };
+        let accum = match self.consume_id() {
+            Ok(id) => id,
+            Err(e) => return Err(e),
+        };
+        match self.skip_str(")") {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+
+        let body = match self.consume_expr() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        // the lambda's closing paren; the fold's is left to consume_expr
+        match self.skip_str(")") {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(~Fold {
+            foldee: foldee,
+            init: init,
+            next_id: next,
+            accum_id: accum,
+            body: body
+        })
     }
 
-    pub fn consume_id(&mut self) -> Id {
-        let s = self.consume_ident_str();
+    pub fn consume_id(&mut self) -> Result<Id, ~str> {
+        let s = match self.consume_ident_str() {
+            Ok(s) => s,
+            Err(e) => return Err(e),
+        };
         let id = do self.interned.find_or_insert_with(s) |_| {
             let num = self.next_id;
             self.next_id += 1;
             num
         };
-        *id
+        Ok(*id)
     }
 
-    pub fn consume_ident_str(&mut self) -> ~str {
+    pub fn consume_ident_str(&mut self) -> Result<~str, ~str> {
         let mut offset = 0;
         for (new_offset, c) in self.src.char_offset_iter() {
             if c.is_alphanumeric() || c == '_' {
@@ -158,12 +277,12 @@ impl<'self> Parser<'self> {
             }
         }
         if offset == 0 {
-            fail!("expected ident, found %s", self.src);
+            return Err(fmt!("expected ident, found %s", self.src));
         }
         let ret = self.src.slice_to(offset).to_owned();
         self.src = self.src.slice_from(offset);
         self.skip_ws();
-        ret
+        Ok(ret)
     }
 }
 
@@ -189,33 +308,54 @@ mod tests {
     #[test]
     fn test_skip_str() {
         let mut p = Parser::new("hurro");
-        p.skip_str("hurr");
+        assert_eq!(p.skip_str("hurr"), Ok(()));
         assert_eq!(p.src, "o");
+        assert!(p.skip_str("x").is_err());
     }
 
     #[test]
     fn test_parse() {
        let mut p = Parser::new("(lambda (x) (or x (shl1 (if0 x 0 1))))");
-       assert_eq!(p.parse(), Program::new(0, ~Op2(Or, ~Ident(0),
-                                                  ~Op1(Shl1,
-                                                       ~If0(~Ident(0),~Zero,~One)))));
+       assert_eq!(p.parse(), Ok(Program::new(0, ~Op2(Or, ~Ident(0),
+                                                     ~Op1(Shl1,
+                                                          ~If0(~Ident(0),~Zero,~One))))));
     }
 
     #[test]
     fn test_parse_fold() {
        let mut p = Parser::new("(lambda (x) (fold x 0 (lambda (a b) (plus a b))))");
-       assert_eq!(p.parse(), Program::new(0, ~Fold {
+       assert_eq!(p.parse(), Ok(Program::new(0, ~Fold {
                         foldee: ~Ident(0),
                         init: ~Zero,
                         next_id: 1,
                         accum_id: 2,
                         body: ~Op2(Plus, ~Ident(1), ~Ident(2))
-                    }));
+                    })));
+
+       // a fold that isn't the last thing in the program
+       let mut p = Parser::new("(lambda (x) (plus (fold x 0 (lambda (a b) a)) 1))");
+       assert_eq!(p.parse(), Ok(Program::new(0, ~Op2(Plus, ~Fold {
+                        foldee: ~Ident(0),
+                        init: ~Zero,
+                        next_id: 1,
+                        accum_id: 2,
+                        body: ~Ident(1)
+                    }, ~One))));
+    }
+
+    #[test]
+    fn test_parse_program() {
+        assert_eq!(parse_program("(lambda (x) (not x))").unwrap().unwrap(),
+                   Program::new(0, ~Op1(Not, ~Ident(0))));
+        assert!(parse_program("(lambda (x) (not y))").is_err());
+        assert!(parse_program("(lambda (x) (frob x))").is_err());
+        assert!(parse_program("(lambda (x) (not x)").is_err());
+        assert!(parse_program("(lambda (x) (not").is_err());
     }
 
     #[test]
     fn test_parse_weird_id() {
         let mut p = Parser::new("(lambda (x_29045) x_29045)");
-        assert_eq!(p.parse(), Program::new(0, ~Ident(0)));
+        assert_eq!(p.parse(), Ok(Program::new(0, ~Ident(0))));
     }
 }
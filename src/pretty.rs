@@ -0,0 +1,262 @@
+use program::*;
+
+use std::hashmap::{HashMap, HashSet};
+
+pub enum Layout {
+    // everything on one line
+    Compact,
+    // break anything that doesn't fit in this many columns, one argument
+    // per line
+    Indented(uint),
+}
+
+// The printed form, before it's laid out.
+enum Sexp {
+    Atom(~str),
+    List(~[Sexp]),
+}
+
+impl Sexp {
+    fn flat(&self, out: &mut ~str) {
+        match *self {
+            Atom(ref s) => out.push_str(*s),
+            List(ref items) => {
+                out.push_char('(');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_char(' ');
+                    }
+                    item.flat(out);
+                }
+                out.push_char(')');
+            }
+        }
+    }
+
+    fn flat_str(&self) -> ~str {
+        let mut s = ~"";
+        self.flat(&mut s);
+        s
+    }
+
+    // Lay this out starting at column `col`, with at most `width` columns.
+    fn indented(&self, col: uint, width: uint, out: &mut ~str) {
+        let flat = self.flat_str();
+        let items = match *self {
+            List(ref items) if col + flat.len() > width => items,
+            _ => {
+                out.push_str(flat);
+                return;
+            }
+        };
+
+        // the operator (and a lambda's parameters) stay on the first line
+        let head = match items[0] {
+            Atom(ref s) if s.as_slice() == "lambda" => 2,
+            _ => 1,
+        };
+        out.push_char('(');
+        for i in range(0, head) {
+            if i > 0 {
+                out.push_char(' ');
+            }
+            items[i].flat(out);
+        }
+        for item in items.slice_from(head).iter() {
+            out.push_char('\n');
+            for _ in range(0, col + 2) {
+                out.push_char(' ');
+            }
+            item.indented(col + 2, width, out);
+        }
+        out.push_char(')');
+    }
+}
+
+// Prints programs as s-expressions the parser reads back as the same
+// program, though numbered its own way (which Eq ignores). Identifiers
+// keep the names they were given, where known (see Parser::names); the
+// rest are named with id_to_str, made unique, so distinct ids never share
+// a name and a fold only shadows what it shadowed before.
+pub struct Printer {
+    names: HashMap<Id, ~str>,
+    taken: HashSet<~str>,
+    layout: Layout,
+}
+
+impl Printer {
+    pub fn new(layout: Layout) -> Printer {
+        Printer::with_names(HashMap::new(), layout)
+    }
+
+    pub fn with_names(names: HashMap<Id, ~str>, layout: Layout) -> Printer {
+        let mut taken = HashSet::new();
+        for (_, name) in names.iter() {
+            taken.insert(name.clone());
+        }
+        Printer {
+            names: names,
+            taken: taken,
+            layout: layout,
+        }
+    }
+
+    // The name `id` gets printed as, picking one if it hasn't got one yet.
+    pub fn name(&mut self, id: Id) -> ~str {
+        match self.names.find(&id) {
+            Some(name) => return name.clone(),
+            None => {}
+        }
+
+        let mut name = id_to_str(id);
+        if self.taken.contains(&name) {
+            name = fmt!("%s_%u", name, id);
+            while self.taken.contains(&name) {
+                name.push_char('_');
+            }
+        }
+        self.taken.insert(name.clone());
+        self.names.insert(id, name.clone());
+        name
+    }
+
+    pub fn print(&mut self, p: &Program) -> ~str {
+        let arg = self.name(p.id);
+        let sexp = List(~[Atom(~"lambda"), List(~[Atom(arg)]), self.sexp(p.expr)]);
+
+        let mut out = ~"";
+        match self.layout {
+            Compact => sexp.flat(&mut out),
+            Indented(width) => sexp.indented(0, width, &mut out),
+        }
+        out
+    }
+
+    fn sexp(&mut self, e: &Expr) -> Sexp {
+        match *e {
+            Zero => Atom(~"0"),
+            One => Atom(~"1"),
+            Ident(id) => Atom(self.name(id)),
+            If0(~ref test, ~ref then, ~ref other) => {
+                List(~[Atom(~"if0"), self.sexp(test), self.sexp(then), self.sexp(other)])
+            }
+            Op1(op, ~ref e) => List(~[Atom(op.to_str()), self.sexp(e)]),
+            Op2(op, ~ref left, ~ref right) => {
+                List(~[Atom(op.to_str()), self.sexp(left), self.sexp(right)])
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id, accum_id,
+                body: ~ref body
+            } => {
+                let foldee = self.sexp(foldee);
+                let init = self.sexp(init);
+                let params = List(~[Atom(self.name(next_id)), Atom(self.name(accum_id))]);
+                let lambda = List(~[Atom(~"lambda"), params, self.sexp(body)]);
+                List(~[Atom(~"fold"), foldee, init, lambda])
+            }
+        }
+    }
+}
+
+pub fn compact(p: &Program) -> ~str {
+    Printer::new(Compact).print(p)
+}
+
+pub fn indented(p: &Program, width: uint) -> ~str {
+    Printer::new(Indented(width)).print(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use parse::{Parse, Parser};
+    use gen::{RandomGen, Deadline, Found, TimedOut};
+    use webapi::Problem;
+    use std::hashmap::HashMap;
+
+    #[test]
+    fn test_keeps_names() {
+        let src = "(lambda (x_1) (fold x_1 0 (lambda (y z) (plus y (shr4 z)))))";
+        let mut parser = Parser::new(src);
+        let p = parser.parse().unwrap();
+
+        let mut printer = Printer::with_names(parser.names(), Compact);
+        assert_eq!(printer.print(&p), src.to_owned());
+    }
+
+    #[test]
+    fn test_indented() {
+        let src = "(lambda (x) (fold x 0 (lambda (y z) (plus y (shr4 z)))))";
+        let p = src.parse().unwrap();
+        assert_eq!(indented(&p, 80), src.to_owned());
+        assert_eq!(indented(&p, 30),
+                   ~"(lambda (x)\n  (fold\n    x\n    0\n    (lambda (y z)\n      (plus y (shr4 z)))))");
+    }
+
+    #[test]
+    fn test_unique_names() {
+        // 1 would be "b" too
+        let mut names = HashMap::new();
+        names.insert(0u, ~"b");
+        let p = Program::new(0, ~Op2(And, ~Ident(0), ~Ident(1)));
+        let mut printer = Printer::with_names(names, Compact);
+        assert_eq!(printer.print(&p), ~"(lambda (b) (and b b_1))");
+    }
+
+    #[test]
+    fn test_round_trip_numbering() {
+        // numbered as gen_tfold does, not as the parser would
+        let tfold = Program::new(2, ~Fold {
+            foldee: ~Ident(2),
+            init: ~Zero,
+            next_id: 0,
+            accum_id: 1,
+            body: ~Op2(Plus, ~Ident(0), ~Ident(1))
+        });
+        let printed = compact(&tfold);
+        assert_eq!(printed, ~"(lambda (c) (fold c 0 (lambda (a b) (plus a b))))");
+        assert_eq!(printed.as_slice().parse(), Ok(tfold));
+
+        // the fold's parameter shadows the argument
+        let shadowed = Program::new(0, ~Op2(Or, ~Ident(0), ~Fold {
+            foldee: ~Ident(0),
+            init: ~One,
+            next_id: 0,
+            accum_id: 1,
+            body: ~Op2(Xor, ~Ident(0), ~Ident(1))
+        }));
+        assert_eq!(compact(&shadowed).as_slice().parse(), Ok(shadowed));
+    }
+
+    #[test]
+    fn test_round_trip_generated() {
+        let op_sets = ~[
+            ~[~"not", ~"shl1", ~"and", ~"xor", ~"if0"],
+            ~[~"shr4", ~"shr16", ~"or", ~"plus", ~"fold"],
+            ~[~"shr1", ~"and", ~"plus", ~"if0", ~"fold"],
+            ~[~"not", ~"shr4", ~"xor", ~"plus", ~"tfold"],
+        ];
+
+        for ops in op_sets.iter() {
+            let mut operators = OperatorSet::new();
+            operators.add(ops.clone());
+            let problem = Problem {
+                id: ~"test_round_trip_generated",
+                size: 16,
+                operators: operators,
+            };
+            let mut gen = RandomGen::new(problem, ~[]);
+
+            for _ in range(0, 20) {
+                let p = match gen.next(Deadline::in_secs(60f)) {
+                    Found(~p) => p,
+                    TimedOut => fail!("no program generated"),
+                };
+                assert_eq!(compact(&p).as_slice().parse(), Ok(p.clone()));
+                assert_eq!(indented(&p, 20).as_slice().parse(), Ok(p.clone()));
+            }
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use std::str;
+use std::to_bytes::{IterBytes, Cb};
 pub type Id = uint;
 
-fn id_to_str(mut num: Id) -> ~str {
+pub fn id_to_str(mut num: Id) -> ~str {
     let mut s = str::with_capacity(5);
     loop {
         let (div, rem) = num.div_rem(&26);
@@ -14,67 +15,189 @@ fn id_to_str(mut num: Id) -> ~str {
     s
 }
 
+// Everything a problem's operator list can mention, in the order
+// OperatorSet prints them.
+#[deriving(Eq, Clone, IterBytes)]
+pub enum Operator {
+    OpBonus = 0,
+    OpTfold = 1,
+    OpFold = 2,
+    OpIf0 = 3,
+    OpNot = 4,
+    OpShl1 = 5,
+    OpShr1 = 6,
+    OpShr4 = 7,
+    OpShr16 = 8,
+    OpAnd = 9,
+    OpOr = 10,
+    OpXor = 11,
+    OpPlus = 12,
+}
+
+pub static ALL_OPERATORS: [Operator, ..13] = [
+    OpBonus, OpTfold, OpFold, OpIf0,
+    OpNot, OpShl1, OpShr1, OpShr4, OpShr16,
+    OpAnd, OpOr, OpXor, OpPlus,
+];
+
+impl Operator {
+    pub fn from_op1(op: UnaOp) -> Operator {
+        match op {
+            Not => OpNot,
+            Shl1 => OpShl1,
+            Shr1 => OpShr1,
+            Shr4 => OpShr4,
+            Shr16 => OpShr16,
+        }
+    }
+
+    pub fn from_op2(op: BinOp) -> Operator {
+        match op {
+            And => OpAnd,
+            Or => OpOr,
+            Xor => OpXor,
+            Plus => OpPlus,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OpBonus => "bonus",
+            OpTfold => "tfold",
+            OpFold => "fold",
+            OpIf0 => "if0",
+            OpNot => "not",
+            OpShl1 => "shl1",
+            OpShr1 => "shr1",
+            OpShr4 => "shr4",
+            OpShr16 => "shr16",
+            OpAnd => "and",
+            OpOr => "or",
+            OpXor => "xor",
+            OpPlus => "plus",
+        }
+    }
+}
+
+impl ToStr for Operator {
+    pub fn to_str(&self) -> ~str {
+        self.name().to_owned()
+    }
+}
+
+impl FromStr for Operator {
+    pub fn from_str(s: &str) -> Option<Operator> {
+        for &op in ALL_OPERATORS.iter() {
+            if op.name() == s {
+                return Some(op);
+            }
+        }
+        None
+    }
+}
+
+// A set of operators, one bit per Operator.
+#[deriving(Eq, Clone, IterBytes)]
 pub struct OperatorSet {
-    op1: [bool, ..5],
-    op2: [bool, ..4],
-    if0: bool,
-    fold: bool,
-    tfold: bool,
-    bonus: bool,
+    bits: u16,
 }
 
 impl OperatorSet {
     pub fn new() -> OperatorSet {
-        OperatorSet {
-            op1: [false, false, false, false, false],
-            op2: [false, false, false, false],
-            if0: false,
-            fold: false,
-            tfold: false,
-            bonus: false,
+        OperatorSet { bits: 0 }
+    }
+
+    pub fn from_ops(ops: &[Operator]) -> OperatorSet {
+        let mut set = OperatorSet::new();
+        for &op in ops.iter() {
+            set.insert(op);
         }
+        set
+    }
+
+    pub fn has(&self, op: Operator) -> bool {
+        self.bits & (1 << op as uint) != 0
+    }
+
+    pub fn has_op1(&self, op: UnaOp) -> bool {
+        self.has(Operator::from_op1(op))
+    }
+
+    pub fn has_op2(&self, op: BinOp) -> bool {
+        self.has(Operator::from_op2(op))
+    }
+
+    pub fn insert(&mut self, op: Operator) {
+        self.bits |= 1 << op as uint;
+    }
+
+    pub fn remove(&mut self, op: Operator) {
+        self.bits &= !(1 << op as uint);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    pub fn len(&self) -> uint {
+        let mut n = 0;
+        for _ in self.iter() {
+            n += 1;
+        }
+        n
+    }
+
+    pub fn union(&self, other: &OperatorSet) -> OperatorSet {
+        OperatorSet { bits: self.bits | other.bits }
     }
 
-    pub fn add(&mut self, ops: ~[~str]) {
+    pub fn intersection(&self, other: &OperatorSet) -> OperatorSet {
+        OperatorSet { bits: self.bits & other.bits }
+    }
+
+    pub fn difference(&self, other: &OperatorSet) -> OperatorSet {
+        OperatorSet { bits: self.bits & !other.bits }
+    }
+
+    pub fn is_subset(&self, other: &OperatorSet) -> bool {
+        self.bits & !other.bits == 0
+    }
+
+    // The members, in to_str order.
+    pub fn iter(&self) -> OperatorIterator {
+        OperatorIterator { bits: self.bits, next: 0 }
+    }
+
+    // Add operators by name, as the server lists them.
+    pub fn add(&mut self, ops: ~[~str]) -> Result<(), ~str> {
         for op in ops.iter() {
-            match *op {
-                ~"not" => self.op1[Not as uint] = true,
-                ~"shl1" => self.op1[Shl1 as uint] = true,
-                ~"shr1" => self.op1[Shr1 as uint] = true,
-                ~"shr4" => self.op1[Shr4 as uint] = true,
-                ~"shr16" => self.op1[Shr16 as uint] = true,
-                ~"and" => self.op2[And as uint] = true,
-                ~"or" => self.op2[Or as uint] = true,
-                ~"xor" => self.op2[Xor as uint] = true,
-                ~"plus" => self.op2[Plus as uint] = true,
-                ~"if0" => self.if0 = true,
-                ~"fold" => self.fold = true,
-                ~"tfold" => self.tfold = true,
-                ~"bonus" => self.bonus = true,
-                _ => fail!(fmt!("bad operation %s", *op)),
+            match FromStr::from_str(op.as_slice()) {
+                Some(op) => self.insert(op),
+                None => return Err(fmt!("bad operation %s", *op)),
             }
         }
+        Ok(())
     }
 
     fn add_from_expr(&mut self, e: &Expr) {
         match *e {
             Op1(op, ~ref sub_e) => {
-                self.op1[op as uint] = true;
+                self.insert(Operator::from_op1(op));
                 self.add_from_expr(sub_e)
             }
             Op2(op, ~ref lhs, ~ref rhs) => {
-                self.op2[op as uint] = true;
+                self.insert(Operator::from_op2(op));
                 self.add_from_expr(lhs);
                 self.add_from_expr(rhs);
             }
             If0(~ref c, ~ref t, ~ref e) => {
-                self.if0 = true;
+                self.insert(OpIf0);
                 self.add_from_expr(c);
                 self.add_from_expr(t);
                 self.add_from_expr(e);
             }
             Fold {foldee: ~ref foldee, init: ~ref init, body: ~ref body, _ } => {
-                self.fold = true;
+                self.insert(OpFold);
                 self.add_from_expr(foldee);
                 self.add_from_expr(init);
                 self.add_from_expr(body);
@@ -84,79 +207,91 @@ impl OperatorSet {
     }
 
     pub fn add_from_program(&mut self, p: &Program) {
-        match *p.expr {
-            // need to handle tfold specially; note that this isn't
-            // quite right (since it doesn't check for shadowing)
-            Fold {foldee: ~ref foldee, init: ~ref init, body: ~ref body, _ } => {
-                match (foldee, init) {
-                    (&Ident(_), &Zero) => {
-                        self.tfold = true;
-                        self.add_from_expr(body);
-                        return;
-                    }
-                    _ => {}
+        // a tfold counts as that rather than as a fold
+        if p.is_tfold() {
+            match *p.expr {
+                Fold { body: ~ref body, _ } => {
+                    self.insert(OpTfold);
+                    self.add_from_expr(body);
+                    return;
                 }
+                _ => {}
             }
-            _ => {}
         }
         self.add_from_expr(p.expr)
     }
-}
 
-impl ToStr for OperatorSet {
-    pub fn to_str(&self) -> ~str {
-        let mut ops = ~[];
-        if self.bonus { ops.push(~"bonus") }
-        if self.tfold { ops.push(~"tfold"); }
-        if self.fold { ops.push(~"fold"); }
-        if self.if0 { ops.push(~"if0"); }
-        if self.op1[0] { ops.push(~"not"); }
-        if self.op1[1] { ops.push(~"shl1"); }
-        if self.op1[2] { ops.push(~"shr1"); }
-        if self.op1[3] { ops.push(~"shr4"); }
-        if self.op1[4] { ops.push(~"shr16"); }
-        if self.op2[0] { ops.push(~"and"); }
-        if self.op2[1] { ops.push(~"or"); }
-        if self.op2[2] { ops.push(~"xor"); }
-        if self.op2[3] { ops.push(~"plus"); }
-        ops.connect(",")
+    // Does `used` contain every operator required by this set? Bonus
+    // isn't an operator, and a top-level fold may be detected as a
+    // tfold, so either satisfies a fold requirement.
+    pub fn covered_by(&self, used: &OperatorSet) -> bool {
+        let mut required = self.clone();
+        required.remove(OpBonus);
+        let mut used = used.clone();
+        if used.has(OpTfold) {
+            used.insert(OpFold);
+        }
+        required.is_subset(&used)
+    }
+
+    // May a program using `used` be guessed for a problem with this set?
+    // A top-level fold counts as a fold, but a tfold problem allows no
+    // other fold.
+    pub fn allows(&self, used: &OperatorSet) -> bool {
+        let mut allowed = self.clone();
+        if allowed.has(OpFold) {
+            allowed.insert(OpTfold);
+        }
+        used.is_subset(&allowed)
     }
 }
 
-impl Clone for OperatorSet {
-    pub fn clone(&self) -> OperatorSet {
-        let mut ops = OperatorSet::new();
-        for i in range(0, 5) {
-            ops.op1[i] = self.op1[i];
-            if i != 4 {
-                ops.op2[i] = self.op2[i];
+pub struct OperatorIterator {
+    bits: u16,
+    next: uint,
+}
+
+impl Iterator<Operator> for OperatorIterator {
+    fn next(&mut self) -> Option<Operator> {
+        while self.next < ALL_OPERATORS.len() {
+            let op = ALL_OPERATORS[self.next];
+            self.next += 1;
+            if self.bits & (1 << op as uint) != 0 {
+                return Some(op);
             }
         }
-        ops.if0 = self.if0;
-        ops.fold = self.fold;
-        ops.tfold = self.tfold;
-        ops.bonus = self.bonus;
-        ops
+        None
     }
 }
 
-impl Eq for OperatorSet {
-    pub fn eq(&self, other: &OperatorSet) -> bool {
-        for i in range(0, 5) {
-            if other.op1[i] != self.op1[i] { return false; }
-            if i != 4 {
-                if other.op2[i] != self.op2[i] { return false; }
+impl ToStr for OperatorSet {
+    pub fn to_str(&self) -> ~str {
+        let names: ~[&'static str] = self.iter().transform(|op| op.name()).collect();
+        names.connect(",")
+    }
+}
+
+// The comma-separated form to_str produces.
+impl FromStr for OperatorSet {
+    pub fn from_str(s: &str) -> Option<OperatorSet> {
+        let mut set = OperatorSet::new();
+        for name in s.split_iter(',') {
+            let name = name.trim();
+            if name.is_empty() {
+                loop;
+            }
+            match FromStr::from_str(name) {
+                Some(op) => set.insert(op),
+                None => return None,
             }
         }
-        if other.if0 != self.if0 { return false; }
-        if other.fold != self.fold { return false; }
-        if other.tfold != self.tfold { return false; }
-        if other.bonus != self.bonus { return false; }
-        true
+        Some(set)
     }
 }
 
-#[deriving(Eq, Clone)]
+// Programs are equal if they're alpha-equivalent: the same but for the
+// numbering of their identifiers (see alpha_normalize).
+#[deriving(Clone)]
 pub struct Program {
     id: Id,
     expr: ~Expr,
@@ -164,7 +299,7 @@ pub struct Program {
 
 pub static OP1_CHOICE: [UnaOp, ..5] = [Not, Shl1, Shr1, Shr4, Shr16];
 
-#[deriving(Rand,Eq, Clone)]
+#[deriving(Rand,Eq, Clone, IterBytes)]
 pub enum UnaOp {
     Not = 0,
     Shl1 = 1,
@@ -175,13 +310,13 @@ pub enum UnaOp {
 
 impl UnaOp {
     fn in_ops(&self, ops: &OperatorSet) -> bool {
-        ops.op1[*self as uint]
+        ops.has_op1(*self)
     }
 }
 
 pub static OP2_CHOICE: [BinOp, ..4] = [And, Or, Xor, Plus];
 
-#[deriving(Rand,Eq, Clone)]
+#[deriving(Rand,Eq, Clone, IterBytes)]
 pub enum BinOp {
     And = 0,
     Or = 1,
@@ -191,11 +326,11 @@ pub enum BinOp {
 
 impl BinOp {
     fn in_ops(&self, ops: &OperatorSet) -> bool {
-        ops.op2[*self as uint]
+        ops.has_op2(*self)
     }
 }
 
-#[deriving(Eq, Clone)]
+#[deriving(Eq, Clone, IterBytes)]
 pub enum Expr {
     Zero,
     One,
@@ -229,9 +364,232 @@ impl Program {
         operators.add_from_program(self);
         operators
     }
+
+    // Is this a tfold: a fold of the argument, starting from 0, at the
+    // top level, whose body can't see the argument (it either doesn't
+    // mention it or rebinds its name)?
+    pub fn is_tfold(&self) -> bool {
+        match *self.expr {
+            Fold { foldee: ~Ident(id), init: ~Zero, next_id, accum_id, body: ~ref body } => {
+                id == self.id &&
+                    (next_id == id || accum_id == id || !body.references_free(id))
+            }
+            _ => false,
+        }
+    }
+
+    // A copy with the identifiers renumbered canonically: the argument is
+    // 0 and each fold's parameters get the next two numbers as they're
+    // reached, which is how the parser numbers distinct names. Programs
+    // that only differ in naming normalize to the same thing, which is
+    // what Eq and the hash go by.
+    pub fn alpha_normalize(&self) -> Program {
+        let mut renamer = Renamer::new(self.id);
+        let expr = renamer.rename(self.expr);
+        Program::new(0, ~expr)
+    }
+}
+
+impl Eq for Program {
+    pub fn eq(&self, other: &Program) -> bool {
+        let mut left = Renamer::new(self.id);
+        let mut right = Renamer::new(other.id);
+        alpha_eq(self.expr, &mut left, other.expr, &mut right)
+    }
+}
+
+// Hashes what the normalized form would, so that it agrees with Eq.
+impl IterBytes for Program {
+    fn iter_bytes(&self, lsb0: bool, f: Cb) -> bool {
+        Renamer::new(self.id).iter_bytes(self.expr, lsb0, f)
+    }
+}
+
+// Do `a` and `b` normalize to the same thing? Each renamer numbers its
+// side's identifiers as alpha_normalize would.
+fn alpha_eq(a: &Expr, ra: &mut Renamer, b: &Expr, rb: &mut Renamer) -> bool {
+    match (a, b) {
+        (&Zero, &Zero) | (&One, &One) => true,
+        (&Ident(x), &Ident(y)) => ra.lookup(x) == rb.lookup(y),
+        (&If0(~ref c1, ~ref t1, ~ref e1), &If0(~ref c2, ~ref t2, ~ref e2)) => {
+            alpha_eq(c1, ra, c2, rb) && alpha_eq(t1, ra, t2, rb) && alpha_eq(e1, ra, e2, rb)
+        }
+        (&Op1(op1, ~ref e1), &Op1(op2, ~ref e2)) => op1 == op2 && alpha_eq(e1, ra, e2, rb),
+        (&Op2(op1, ~ref l1, ~ref r1), &Op2(op2, ~ref l2, ~ref r2)) => {
+            op1 == op2 && alpha_eq(l1, ra, l2, rb) && alpha_eq(r1, ra, r2, rb)
+        }
+        (&Fold { foldee: ~ref foldee1, init: ~ref init1,
+                 next_id: next1, accum_id: accum1, body: ~ref body1 },
+         &Fold { foldee: ~ref foldee2, init: ~ref init2,
+                 next_id: next2, accum_id: accum2, body: ~ref body2 }) => {
+            if !alpha_eq(foldee1, ra, foldee2, rb) || !alpha_eq(init1, ra, init2, rb) {
+                return false;
+            }
+            ra.bind(next1, accum1);
+            rb.bind(next2, accum2);
+            let same = alpha_eq(body1, ra, body2, rb);
+            ra.unbind();
+            rb.unbind();
+            same
+        }
+        _ => false,
+    }
+}
+
+struct Renamer {
+    // innermost binding last
+    bound: ~[(Id, Id)],
+    // anything unbound is numbered consistently too, though valid
+    // programs don't have any
+    free: ~[(Id, Id)],
+    next: Id,
+}
+
+impl Renamer {
+    fn new(arg: Id) -> Renamer {
+        Renamer { bound: ~[(arg, 0)], free: ~[], next: 1 }
+    }
+
+    fn fresh(&mut self) -> Id {
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+
+    fn lookup(&mut self, id: Id) -> Id {
+        for &(old, new) in self.bound.rev_iter() {
+            if old == id {
+                return new;
+            }
+        }
+        for &(old, new) in self.free.iter() {
+            if old == id {
+                return new;
+            }
+        }
+        let new = self.fresh();
+        self.free.push((id, new));
+        new
+    }
+
+    // Enter a fold, returning the numbers its parameters get.
+    fn bind(&mut self, next_id: Id, accum_id: Id) -> (Id, Id) {
+        let next = self.fresh();
+        let accum = self.fresh();
+        self.bound.push((next_id, next));
+        self.bound.push((accum_id, accum));
+        (next, accum)
+    }
+
+    fn unbind(&mut self) {
+        self.bound.pop();
+        self.bound.pop();
+    }
+
+    // Feed `e` to `f` as its renamed form, without building it.
+    fn iter_bytes(&mut self, e: &Expr, lsb0: bool, f: Cb) -> bool {
+        match *e {
+            Zero => 0u8.iter_bytes(lsb0, |b| f(b)),
+            One => 1u8.iter_bytes(lsb0, |b| f(b)),
+            Ident(id) => {
+                3u8.iter_bytes(lsb0, |b| f(b)) && self.lookup(id).iter_bytes(lsb0, |b| f(b))
+            }
+            If0(~ref c, ~ref t, ~ref e) => {
+                4u8.iter_bytes(lsb0, |b| f(b)) &&
+                    self.iter_bytes(c, lsb0, |b| f(b)) &&
+                    self.iter_bytes(t, lsb0, |b| f(b)) &&
+                    self.iter_bytes(e, lsb0, |b| f(b))
+            }
+            Op1(op, ~ref e) => {
+                5u8.iter_bytes(lsb0, |b| f(b)) && op.iter_bytes(lsb0, |b| f(b)) &&
+                    self.iter_bytes(e, lsb0, |b| f(b))
+            }
+            Op2(op, ~ref lhs, ~ref rhs) => {
+                6u8.iter_bytes(lsb0, |b| f(b)) && op.iter_bytes(lsb0, |b| f(b)) &&
+                    self.iter_bytes(lhs, lsb0, |b| f(b)) &&
+                    self.iter_bytes(rhs, lsb0, |b| f(b))
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id, accum_id,
+                body: ~ref body
+            } => {
+                if !(7u8.iter_bytes(lsb0, |b| f(b)) &&
+                     self.iter_bytes(foldee, lsb0, |b| f(b)) &&
+                     self.iter_bytes(init, lsb0, |b| f(b))) {
+                    return false;
+                }
+                self.bind(next_id, accum_id);
+                let more = self.iter_bytes(body, lsb0, |b| f(b));
+                self.unbind();
+                more
+            }
+        }
+    }
+
+    fn rename(&mut self, e: &Expr) -> Expr {
+        match *e {
+            Zero => Zero,
+            One => One,
+            Ident(id) => Ident(self.lookup(id)),
+            If0(~ref c, ~ref t, ~ref e) => {
+                let c = self.rename(c);
+                let t = self.rename(t);
+                let e = self.rename(e);
+                If0(~c, ~t, ~e)
+            }
+            Op1(op, ~ref e) => Op1(op, ~self.rename(e)),
+            Op2(op, ~ref lhs, ~ref rhs) => {
+                let lhs = self.rename(lhs);
+                let rhs = self.rename(rhs);
+                Op2(op, ~lhs, ~rhs)
+            }
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id, accum_id,
+                body: ~ref body
+            } => {
+                let foldee = self.rename(foldee);
+                let init = self.rename(init);
+
+                let (next, accum) = self.bind(next_id, accum_id);
+                let body = self.rename(body);
+                self.unbind();
+
+                Fold {
+                    foldee: ~foldee,
+                    init: ~init,
+                    next_id: next,
+                    accum_id: accum,
+                    body: ~body
+                }
+            }
+        }
+    }
 }
 
 impl Expr {
+    // Does this refer to `id` somewhere it isn't rebound by a fold?
+    pub fn references_free(&self, id: Id) -> bool {
+        match *self {
+            Zero | One => false,
+            Ident(i) => i == id,
+            If0(~ref c, ~ref t, ~ref e) => {
+                c.references_free(id) || t.references_free(id) || e.references_free(id)
+            }
+            Op1(_, ~ref e) => e.references_free(id),
+            Op2(_, ~ref lhs, ~ref rhs) => lhs.references_free(id) || rhs.references_free(id),
+            Fold {
+                foldee: ~ref foldee, init: ~ref init,
+                next_id, accum_id,
+                body: ~ref body
+            } => {
+                foldee.references_free(id) || init.references_free(id) ||
+                    (next_id != id && accum_id != id && body.references_free(id))
+            }
+        }
+    }
+
     pub fn len(&self) -> u8 {
         match *self {
             Zero => 1,
@@ -310,3 +668,146 @@ impl ToStr for Expr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hashmap::HashMap;
+
+    // as gen_tfold makes them
+    fn tfold_prog() -> Program {
+        Program::new(2, ~Fold {
+            foldee: ~Ident(2),
+            init: ~Zero,
+            next_id: 0,
+            accum_id: 1,
+            body: ~Op2(Plus, ~Ident(0), ~Ident(1))
+        })
+    }
+
+    fn fold_of(foldee: Expr, init: Expr, next_id: Id, accum_id: Id, body: Expr) -> Program {
+        Program::new(0, ~Fold {
+            foldee: ~foldee,
+            init: ~init,
+            next_id: next_id,
+            accum_id: accum_id,
+            body: ~body
+        })
+    }
+
+    #[test]
+    fn test_is_tfold() {
+        assert!(tfold_prog().is_tfold());
+        assert!(fold_of(Ident(0), Zero, 1, 2, Op2(Or, ~Ident(1), ~Ident(2))).is_tfold());
+
+        // the argument is shadowed, so the body doesn't see it
+        assert!(fold_of(Ident(0), Zero, 0, 1, Op2(Or, ~Ident(0), ~Ident(1))).is_tfold());
+
+        // the body uses the argument
+        assert!(!fold_of(Ident(0), Zero, 1, 2, Op2(Or, ~Ident(0), ~Ident(2))).is_tfold());
+        // not folding the argument, or not from 0
+        assert!(!fold_of(Ident(1), Zero, 1, 2, Ident(2)).is_tfold());
+        assert!(!fold_of(Ident(0), One, 1, 2, Ident(2)).is_tfold());
+        assert!(!fold_of(Op1(Not, ~Ident(0)), Zero, 1, 2, Ident(2)).is_tfold());
+        // not at the top
+        assert!(!Program::new(0, ~Op1(Not, tfold_prog().expr)).is_tfold());
+    }
+
+    #[test]
+    fn test_tfold_operators() {
+        let ops = tfold_prog().operators();
+        assert!(ops.has(OpTfold) && !ops.has(OpFold) && ops.has(OpPlus));
+
+        let ops = fold_of(Ident(0), Zero, 1, 2, Op2(Or, ~Ident(0), ~Ident(2))).operators();
+        assert!(ops.has(OpFold) && !ops.has(OpTfold) && ops.has(OpOr));
+
+        let folds = OperatorSet::from_ops([OpFold, OpPlus]);
+        assert!(folds.allows(&tfold_prog().operators()));
+        assert!(!OperatorSet::from_ops([OpTfold]).allows(&tfold_prog().operators()));
+        assert!(OperatorSet::from_ops([OpTfold, OpPlus]).allows(&tfold_prog().operators()));
+        assert!(!OperatorSet::from_ops([OpTfold, OpOr]).allows(&ops));
+    }
+
+    #[test]
+    fn test_operator_set() {
+        let a = OperatorSet::from_ops([OpNot, OpPlus, OpFold]);
+        let b = OperatorSet::from_ops([OpPlus, OpIf0]);
+
+        assert_eq!(a.union(&b), OperatorSet::from_ops([OpNot, OpPlus, OpFold, OpIf0]));
+        assert_eq!(a.intersection(&b), OperatorSet::from_ops([OpPlus]));
+        assert_eq!(a.difference(&b), OperatorSet::from_ops([OpNot, OpFold]));
+        assert!(a.intersection(&b).is_subset(&a));
+        assert!(!a.is_subset(&b));
+        assert_eq!(a.len(), 3);
+
+        let members: ~[Operator] = a.iter().collect();
+        assert_eq!(members, ~[OpFold, OpNot, OpPlus]);
+    }
+
+    #[test]
+    fn test_operator_set_strings() {
+        let mut ops = OperatorSet::new();
+        assert!(ops.add(~[~"shr4", ~"tfold", ~"xor"]).is_ok());
+        assert_eq!(ops.to_str(), ~"tfold,shr4,xor");
+        assert_eq!(FromStr::from_str(ops.to_str()), Some(ops));
+        let empty: Option<OperatorSet> = FromStr::from_str("");
+        assert_eq!(empty, Some(OperatorSet::new()));
+
+        assert!(ops.add(~[~"frob"]).is_err());
+        let bad: Option<OperatorSet> = FromStr::from_str("not,frob");
+        assert_eq!(bad, None);
+    }
+
+    #[test]
+    fn test_alpha_normalize() {
+        let expected = Program::new(0, ~Fold {
+            foldee: ~Ident(0),
+            init: ~Zero,
+            next_id: 1,
+            accum_id: 2,
+            body: ~Op2(Plus, ~Ident(1), ~Ident(2))
+        });
+        // Eq doesn't care about numbering, so compare the printed forms
+        assert_eq!(tfold_prog().alpha_normalize().to_str(), expected.to_str());
+        assert_eq!(expected.alpha_normalize().to_str(), expected.to_str());
+
+        // the fold's parameter shadows the argument
+        let shadowed = Program::new(0, ~Fold {
+            foldee: ~Ident(0),
+            init: ~Zero,
+            next_id: 0,
+            accum_id: 1,
+            body: ~Op2(Plus, ~Ident(0), ~Ident(1))
+        });
+        assert_eq!(shadowed.alpha_normalize().to_str(), expected.to_str());
+        assert_eq!(shadowed, expected);
+    }
+
+    #[test]
+    fn test_alpha_keys() {
+        let mut seen = HashMap::new();
+        seen.insert(tfold_prog(), 1);
+
+        let renamed = Program::new(7, ~Fold {
+            foldee: ~Ident(7),
+            init: ~Zero,
+            next_id: 3,
+            accum_id: 9,
+            body: ~Op2(Plus, ~Ident(3), ~Ident(9))
+        });
+        assert_eq!(renamed, tfold_prog());
+        assert!(seen.contains_key(&renamed));
+        seen.insert(renamed, 2);
+        assert_eq!(seen.len(), 1);
+
+        let different = Program::new(2, ~Fold {
+            foldee: ~Ident(2),
+            init: ~Zero,
+            next_id: 0,
+            accum_id: 1,
+            body: ~Op2(Plus, ~Ident(1), ~Ident(1))
+        });
+        assert!(different != tfold_prog());
+        assert!(!seen.contains_key(&different));
+    }
+}
@@ -0,0 +1,484 @@
+use program::*;
+use webapi::RealProblem;
+
+use extra::sort;
+use std::char;
+use std::from_str::FromStr;
+
+// Problems that haven't been asked about yet have the full time.
+static FULL_TIME_LEFT: float = 300f;
+
+#[deriving(Eq, Clone)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    EqTo,
+    Ne,
+}
+
+impl Cmp {
+    fn test<T: Ord + Eq>(&self, a: T, b: T) -> bool {
+        match *self {
+            Lt => a < b,
+            Le => a <= b,
+            Gt => a > b,
+            Ge => a >= b,
+            EqTo => a == b,
+            Ne => a != b,
+        }
+    }
+}
+
+// A filter over problems, e.g.
+//
+//     size>=12 && has(fold) && !has(if0) && !solved
+//
+// Operators can be tested with has(op) or just op; `ops` compares the
+// whole set, with <= and >= as subset and superset (`ops <= not,plus`).
+// The statuses are solved, unsolved (not started), inprogress and
+// failed. The old problems filters (all, unfold, noif0, ...) still work.
+#[deriving(Eq, Clone)]
+pub enum Query {
+    QTrue,
+    QNot(~Query),
+    QAnd(~Query, ~Query),
+    QOr(~Query, ~Query),
+    QHas(Operator),
+    QOps(Cmp, OperatorSet),
+    QSize(Cmp, float),
+    QTimeLeft(Cmp, float),
+    QSolved,
+    QUnsolved,
+    QInProgress,
+    QFailed,
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Query, ~str> {
+        let tokens = match tokenize(s) {
+            Ok(tokens) => tokens,
+            Err(e) => return Err(e),
+        };
+        let mut parser = QueryParser { tokens: tokens, pos: 0 };
+        let q = match parser.or() {
+            Ok(q) => q,
+            Err(e) => return Err(e),
+        };
+        if parser.pos < parser.tokens.len() {
+            return Err(fmt!("unexpected %s", parser.tokens[parser.pos].to_str()));
+        }
+        Ok(q)
+    }
+
+    pub fn matches(&self, p: &RealProblem) -> bool {
+        let ops = &p.problem.operators;
+        match *self {
+            QTrue => true,
+            QNot(ref q) => !q.matches(p),
+            QAnd(ref a, ref b) => a.matches(p) && b.matches(p),
+            QOr(ref a, ref b) => a.matches(p) || b.matches(p),
+            QHas(op) => ops.has(op),
+            QOps(Le, ref set) => ops.is_subset(set),
+            QOps(Ge, ref set) => set.is_subset(ops),
+            QOps(Lt, ref set) => ops.is_subset(set) && *ops != *set,
+            QOps(Gt, ref set) => set.is_subset(ops) && *ops != *set,
+            QOps(EqTo, ref set) => *ops == *set,
+            QOps(Ne, ref set) => *ops != *set,
+            QSize(cmp, n) => cmp.test(p.problem.size as float, n),
+            QTimeLeft(cmp, n) => cmp.test(p.time_left.unwrap_or_default(FULL_TIME_LEFT), n),
+            QSolved => p.solved,
+            QUnsolved => !p.solved && p.time_left.is_none(),
+            QInProgress => !p.solved && p.time_left.map_default(false, |&t| t > 0f),
+            QFailed => !p.solved && p.time_left.map_default(false, |&t| t <= 0f),
+        }
+    }
+}
+
+#[deriving(Eq, Clone)]
+enum Token {
+    TWord(~str),
+    TNum(float),
+    TSym(~str),
+}
+
+impl ToStr for Token {
+    fn to_str(&self) -> ~str {
+        match *self {
+            TWord(ref s) => fmt!("'%s'", *s),
+            TNum(n) => fmt!("'%f'", n),
+            TSym(ref s) => fmt!("'%s'", *s),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<~[Token], ~str> {
+    let chars: ~[char] = s.iter().collect();
+    let mut tokens = ~[];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(TWord(str_of(chars.slice(start, i))));
+        } else if char::is_digit(c) {
+            let start = i;
+            while i < chars.len() && (char::is_digit(chars[i]) || chars[i] == '.') {
+                i += 1;
+            }
+            let s = str_of(chars.slice(start, i));
+            match FromStr::from_str(s.as_slice()) {
+                Some(n) => tokens.push(TNum(n)),
+                None => return Err(fmt!("bad number %s", s)),
+            }
+        } else {
+            let two = if i + 1 < chars.len() { str_of(chars.slice(i, i + 2)) } else { ~"" };
+            match two.as_slice() {
+                "&&" | "||" | "<=" | ">=" | "==" | "!=" => {
+                    tokens.push(TSym(two.clone()));
+                    i += 2;
+                }
+                _ => match c {
+                    '!' | '<' | '>' | '(' | ')' | ',' => {
+                        tokens.push(TSym(str_of([c])));
+                        i += 1;
+                    }
+                    '=' => {
+                        // = means == here
+                        tokens.push(TSym(~"=="));
+                        i += 1;
+                    }
+                    _ => return Err(fmt!("unexpected '%c'", c)),
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn str_of(chars: &[char]) -> ~str {
+    let mut s = ~"";
+    for &c in chars.iter() {
+        s.push_char(c);
+    }
+    s
+}
+
+struct QueryParser {
+    tokens: ~[Token],
+    pos: uint,
+}
+
+impl QueryParser {
+    fn peek_sym(&self, sym: &str) -> bool {
+        if self.pos >= self.tokens.len() {
+            return false;
+        }
+        match self.tokens[self.pos] {
+            TSym(ref s) => s.as_slice() == sym,
+            _ => false,
+        }
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<(), ~str> {
+        if self.peek_sym(sym) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(fmt!("expected '%s'", sym))
+        }
+    }
+
+    fn word(&mut self) -> Result<~str, ~str> {
+        if self.pos < self.tokens.len() {
+            match self.tokens[self.pos] {
+                TWord(ref s) => {
+                    let s = s.clone();
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                _ => {}
+            }
+        }
+        Err(~"expected a name")
+    }
+
+    fn or(&mut self) -> Result<Query, ~str> {
+        let mut q = match self.and() { Ok(q) => q, Err(e) => return Err(e) };
+        while self.peek_sym("||") {
+            self.pos += 1;
+            let rhs = match self.and() { Ok(q) => q, Err(e) => return Err(e) };
+            q = QOr(~q, ~rhs);
+        }
+        Ok(q)
+    }
+
+    fn and(&mut self) -> Result<Query, ~str> {
+        let mut q = match self.unary() { Ok(q) => q, Err(e) => return Err(e) };
+        while self.peek_sym("&&") {
+            self.pos += 1;
+            let rhs = match self.unary() { Ok(q) => q, Err(e) => return Err(e) };
+            q = QAnd(~q, ~rhs);
+        }
+        Ok(q)
+    }
+
+    fn unary(&mut self) -> Result<Query, ~str> {
+        if self.peek_sym("!") {
+            self.pos += 1;
+            return match self.unary() {
+                Ok(q) => Ok(QNot(~q)),
+                Err(e) => Err(e),
+            };
+        }
+        if self.peek_sym("(") {
+            self.pos += 1;
+            let q = match self.or() { Ok(q) => q, Err(e) => return Err(e) };
+            return match self.expect_sym(")") {
+                Ok(()) => Ok(q),
+                Err(e) => Err(e),
+            };
+        }
+
+        let word = match self.word() { Ok(w) => w, Err(e) => return Err(e) };
+        match word.as_slice() {
+            "true" | "all" => Ok(QTrue),
+            "solved" => Ok(QSolved),
+            "unsolved" => Ok(QUnsolved),
+            "inprogress" => Ok(QInProgress),
+            "failed" => Ok(QFailed),
+            "has" => {
+                match self.expect_sym("(") { Ok(()) => {}, Err(e) => return Err(e) }
+                let op = match self.operator() { Ok(op) => op, Err(e) => return Err(e) };
+                match self.expect_sym(")") { Ok(()) => {}, Err(e) => return Err(e) }
+                Ok(QHas(op))
+            }
+            "size" | "time_left" => {
+                let cmp = match self.cmp() { Ok(c) => c, Err(e) => return Err(e) };
+                let n = match self.number() { Ok(n) => n, Err(e) => return Err(e) };
+                if word.as_slice() == "size" {
+                    Ok(QSize(cmp, n))
+                } else {
+                    Ok(QTimeLeft(cmp, n))
+                }
+            }
+            "ops" => {
+                let cmp = match self.cmp() { Ok(c) => c, Err(e) => return Err(e) };
+                let mut set = OperatorSet::new();
+                loop {
+                    match self.operator() {
+                        Ok(op) => set.insert(op),
+                        Err(e) => return Err(e),
+                    }
+                    if !self.peek_sym(",") {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                Ok(QOps(cmp, set))
+            }
+            // the filters problems used to take
+            "unfold" => Ok(QNot(~QOr(~QHas(OpFold), ~QOr(~QHas(OpTfold), ~QHas(OpBonus))))),
+            "nobonus" => Ok(QNot(~QHas(OpBonus))),
+            "noif0" => Ok(QNot(~QHas(OpIf0))),
+            "foldnoif0" => Ok(QAnd(~QHas(OpFold), ~QNot(~QHas(OpIf0)))),
+            "tfoldnoif0" => Ok(QAnd(~QHas(OpTfold), ~QNot(~QHas(OpIf0)))),
+            name => match FromStr::from_str(name) {
+                Some(op) => Ok(QHas(op)),
+                None => Err(fmt!("unknown name '%s'", name)),
+            }
+        }
+    }
+
+    fn operator(&mut self) -> Result<Operator, ~str> {
+        let word = match self.word() { Ok(w) => w, Err(e) => return Err(e) };
+        match FromStr::from_str(word.as_slice()) {
+            Some(op) => Ok(op),
+            None => Err(fmt!("unknown operator '%s'", word)),
+        }
+    }
+
+    fn cmp(&mut self) -> Result<Cmp, ~str> {
+        let cmps = [("<=", Le), (">=", Ge), ("==", EqTo), ("!=", Ne), ("<", Lt), (">", Gt)];
+        for &(sym, cmp) in cmps.iter() {
+            if self.peek_sym(sym) {
+                self.pos += 1;
+                return Ok(cmp);
+            }
+        }
+        Err(~"expected a comparison")
+    }
+
+    fn number(&mut self) -> Result<float, ~str> {
+        if self.pos < self.tokens.len() {
+            match self.tokens[self.pos] {
+                TNum(n) => {
+                    self.pos += 1;
+                    return Ok(n);
+                }
+                _ => {}
+            }
+        }
+        Err(~"expected a number")
+    }
+}
+
+#[deriving(Eq, Clone)]
+pub enum SortKey {
+    BySize,
+    ByTimeLeft,
+    // how many operators are listed
+    ByOps,
+    ById,
+}
+
+// Sort keys, comma-separated, most significant first; a leading '-'
+// sorts that key in descending order, e.g. "-time_left,size".
+pub fn parse_sort(s: &str) -> Result<~[(SortKey, bool)], ~str> {
+    let mut keys = ~[];
+    for part in s.split_iter(',') {
+        let part = part.trim();
+        let (name, descending) = if part.starts_with("-") {
+            (part.slice_from(1), true)
+        } else {
+            (part, false)
+        };
+        let key = match name {
+            "size" => BySize,
+            "time_left" => ByTimeLeft,
+            "ops" => ByOps,
+            "id" => ById,
+            _ => return Err(fmt!("unknown sort key '%s'", name)),
+        };
+        keys.push((key, descending));
+    }
+    Ok(keys)
+}
+
+// -1, 0 or 1 as a is less than, equal to or greater than b on `key`.
+fn compare(key: SortKey, a: &RealProblem, b: &RealProblem) -> int {
+    fn sign<T: Ord>(a: T, b: T) -> int {
+        if a < b { -1 } else if b < a { 1 } else { 0 }
+    }
+    match key {
+        BySize => sign(a.problem.size, b.problem.size),
+        ByTimeLeft => sign(a.time_left.unwrap_or_default(FULL_TIME_LEFT),
+                           b.time_left.unwrap_or_default(FULL_TIME_LEFT)),
+        ByOps => sign(a.problem.operators.len(), b.problem.operators.len()),
+        ById => sign(a.problem.id.as_slice(), b.problem.id.as_slice()),
+    }
+}
+
+// Sort by `keys`, breaking any remaining ties by id so the order is
+// always the same.
+pub fn sort_problems(probs: &mut [RealProblem], keys: &[(SortKey, bool)]) {
+    do sort::quick_sort(probs) |a, b| {
+        let mut order = 0;
+        for &(key, descending) in keys.iter() {
+            order = compare(key, a, b);
+            if descending {
+                order = -order;
+            }
+            if order != 0 {
+                break;
+            }
+        }
+        if order == 0 {
+            order = compare(ById, a, b);
+        }
+        order <= 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use webapi::{Problem, RealProblem};
+    use std::from_str::FromStr;
+
+    fn problem(id: &str, size: u8, ops: &str, time_left: Option<float>,
+               solved: bool) -> RealProblem {
+        RealProblem {
+            problem: Problem {
+                id: id.to_owned(),
+                size: size,
+                operators: FromStr::from_str(ops).unwrap(),
+            },
+            time_left: time_left,
+            solved: solved,
+        }
+    }
+
+    fn matches(q: &str, p: &RealProblem) -> bool {
+        match Query::parse(q) {
+            Ok(q) => q.matches(p),
+            Err(e) => fail!("couldn't parse %s: %s", q, e),
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let p = problem("a", 14, "fold,not,plus", None, false);
+        assert!(matches("size>=12 && has(fold) && !has(if0) && !solved", &p));
+        assert!(!matches("size < 12 || if0", &p));
+        assert!(matches("!(size < 12 || if0)", &p));
+        assert!(matches("fold && (tfold || plus)", &p));
+        assert!(matches("ops >= fold,plus && ops <= fold,not,plus,xor", &p));
+        assert!(!matches("ops == fold,plus", &p));
+        assert!(matches("unsolved && time_left == 300", &p));
+    }
+
+    #[test]
+    fn test_statuses() {
+        let solved = problem("a", 8, "not", Some(100f), true);
+        let started = problem("b", 8, "not", Some(100f), false);
+        let failed = problem("c", 8, "not", Some(0f), false);
+
+        assert!(matches("solved", &solved) && !matches("inprogress", &solved));
+        assert!(matches("inprogress && time_left < 200", &started));
+        assert!(matches("failed", &failed) && !matches("unsolved", &failed));
+    }
+
+    #[test]
+    fn test_legacy_filters() {
+        let p = problem("a", 14, "tfold,xor", None, false);
+        assert!(matches("all", &p));
+        assert!(matches("tfoldnoif0", &p));
+        assert!(!matches("unfold", &p));
+        assert!(!matches("foldnoif0", &p));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Query::parse("size >=").is_err());
+        assert!(Query::parse("has(frob)").is_err());
+        assert!(Query::parse("(fold").is_err());
+        assert!(Query::parse("fold fold").is_err());
+        assert!(Query::parse("size # 3").is_err());
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut probs = ~[
+            problem("a", 10, "not", Some(50f), false),
+            problem("b", 8, "not,plus", None, false),
+            problem("c", 10, "not,plus,xor", Some(200f), false),
+        ];
+        sort_problems(probs, parse_sort("-size,time_left").unwrap());
+        let ids: ~[~str] = probs.iter().transform(|p| p.problem.id.clone()).collect();
+        assert_eq!(ids, ~[~"a", ~"c", ~"b"]);
+
+        sort_problems(probs, parse_sort("-ops").unwrap());
+        let ids: ~[~str] = probs.iter().transform(|p| p.problem.id.clone()).collect();
+        assert_eq!(ids, ~[~"c", ~"b", ~"a"]);
+
+        assert!(parse_sort("size,frob").is_err());
+    }
+}
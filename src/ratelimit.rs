@@ -0,0 +1,345 @@
+use webapi::{StatusResponse, Window};
+
+use std::rt::io::timer::Timer;
+use std::rt::rtio::RtioTimer;
+use extra::time;
+
+// the windows' lengths as of the contest, used until the server says
+// otherwise
+static REQUEST_PERIOD_NS: u64 = 20_000_000_000;
+static CPU_PERIOD_NS: u64 = 60_000_000_000;
+// resync from /status at least this often
+static RESYNC_NS: u64 = 60_000_000_000;
+
+pub trait Clock {
+    fn now_ns(&self) -> u64;
+    fn sleep_ns(&mut self, ns: u64);
+}
+
+pub struct SystemClock {
+    timer: Timer,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock { timer: Timer::new().unwrap() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ns(&self) -> u64 {
+        time::precise_time_ns()
+    }
+
+    fn sleep_ns(&mut self, ns: u64) {
+        // round up, so that waking means the time has passed
+        self.timer.sleep((ns + 999_999) / 1_000_000);
+    }
+}
+
+// A clock that only moves when it's told to, or slept on.
+pub struct FakeClock {
+    now: u64,
+}
+
+impl FakeClock {
+    pub fn new() -> FakeClock {
+        FakeClock { now: 0 }
+    }
+
+    pub fn advance_ns(&mut self, ns: u64) {
+        self.now += ns;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_ns(&self) -> u64 {
+        self.now
+    }
+
+    fn sleep_ns(&mut self, ns: u64) {
+        self.now += ns;
+    }
+}
+
+// Real time, except that sleeping skips ahead instead of waiting, so a
+// simulation can keep to the server's windows without taking as long.
+pub struct VirtualClock {
+    skipped_ns: u64,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock { skipped_ns: 0 }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now_ns(&self) -> u64 {
+        time::precise_time_ns() + self.skipped_ns
+    }
+
+    fn sleep_ns(&mut self, ns: u64) {
+        self.skipped_ns += ns;
+    }
+}
+
+// One of the server's windows: `limit` units per period, all given back
+// at once when it resets.
+pub struct Bucket {
+    limit: float,
+    used: float,
+    resets_at_ns: u64,
+    period_ns: u64,
+}
+
+impl Bucket {
+    pub fn new(limit: float, period_ns: u64, now: u64) -> Bucket {
+        Bucket {
+            limit: limit,
+            used: 0f,
+            resets_at_ns: now + period_ns,
+            period_ns: period_ns,
+        }
+    }
+
+    // Take the server's word for where the window is.
+    pub fn sync(&mut self, window: &Window, now: u64) {
+        let resets_in_ns = (window.resets_in * 1_000_000_000f) as u64;
+        self.limit = window.limit;
+        self.used = window.amount;
+        self.resets_at_ns = now + resets_in_ns;
+        if resets_in_ns > self.period_ns {
+            self.period_ns = resets_in_ns;
+        }
+    }
+
+    // Start any windows that have begun since.
+    fn roll(&mut self, now: u64) {
+        if now >= self.resets_at_ns {
+            let behind = now - self.resets_at_ns;
+            self.used = 0f;
+            self.resets_at_ns = now + self.period_ns - behind % self.period_ns;
+        }
+    }
+
+    pub fn available(&mut self, now: u64) -> float {
+        self.roll(now);
+        if self.used < self.limit { self.limit - self.used } else { 0f }
+    }
+
+    // How long until `cost` more fits in the window. Anything fits in an
+    // empty window, so nothing waits forever.
+    pub fn wait_ns(&mut self, now: u64, cost: float) -> u64 {
+        self.roll(now);
+        if self.used > 0f && (self.used >= self.limit || self.used + cost > self.limit) {
+            self.resets_at_ns - now
+        } else {
+            0
+        }
+    }
+
+    pub fn take(&mut self, now: u64, cost: float) {
+        self.roll(now);
+        self.used += cost;
+    }
+
+    // Mark the window full, whatever we thought was in it.
+    pub fn exhaust(&mut self, now: u64) {
+        self.roll(now);
+        if self.used < self.limit {
+            self.used = self.limit;
+        }
+    }
+
+    pub fn resets_in(&mut self, now: u64) -> float {
+        self.roll(now);
+        (self.resets_at_ns - now) as float / 1_000_000_000f
+    }
+}
+
+// What's left in the current windows.
+#[deriving(Clone)]
+pub struct Budget {
+    requests: float,
+    requests_reset_in: float,
+    cpu: float,
+    cpu_reset_in: float,
+}
+
+// Keeps to the server's request and CPU windows. Requests are counted
+// here as they're made; CPU time is only known from /status, so it's
+// only waited on once a sync says it has run out.
+pub struct RateLimiter<C> {
+    clock: C,
+    requests: Bucket,
+    cpu: Bucket,
+    synced_ns: u64,
+    // the server has told us we were wrong about the windows
+    stale: bool,
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn new(clock: C, status: &StatusResponse) -> RateLimiter<C> {
+        let now = clock.now_ns();
+        let mut limiter = RateLimiter {
+            clock: clock,
+            requests: Bucket::new(status.request_window.limit, REQUEST_PERIOD_NS, now),
+            cpu: Bucket::new(status.cpu_window.limit, CPU_PERIOD_NS, now),
+            synced_ns: now,
+            stale: false,
+        };
+        limiter.sync(status);
+        limiter
+    }
+
+    // Until the first sync, only one request per window, as the limits
+    // aren't known.
+    pub fn unsynced(clock: C) -> RateLimiter<C> {
+        let now = clock.now_ns();
+        RateLimiter {
+            clock: clock,
+            requests: Bucket::new(1f, REQUEST_PERIOD_NS, now),
+            cpu: Bucket::new(1f, CPU_PERIOD_NS, now),
+            synced_ns: now,
+            stale: true,
+        }
+    }
+
+    pub fn sync(&mut self, status: &StatusResponse) {
+        let now = self.clock.now_ns();
+        self.requests.sync(&status.request_window, now);
+        self.cpu.sync(&status.cpu_window, now);
+        self.synced_ns = now;
+        self.stale = false;
+    }
+
+    pub fn needs_sync(&self) -> bool {
+        self.stale || self.clock.now_ns() - self.synced_ns >= RESYNC_NS
+    }
+
+    // The server turned a request down for being over the limit.
+    pub fn throttled(&mut self) {
+        let now = self.clock.now_ns();
+        self.requests.exhaust(now);
+        self.stale = true;
+    }
+
+    // Is there room for a request, with `reserve` more left over?
+    pub fn has_room(&mut self, reserve: float) -> bool {
+        let now = self.clock.now_ns();
+        self.requests.available(now) >= 1f + reserve && self.cpu.available(now) > 0f
+    }
+
+    // Wait until there's room for a request, and count it.
+    pub fn wait(&mut self) {
+        loop {
+            let now = self.clock.now_ns();
+            let request_ns = self.requests.wait_ns(now, 1f);
+            let cpu_ns = self.cpu.wait_ns(now, 0f);
+            let ns = if request_ns > cpu_ns { request_ns } else { cpu_ns };
+            if ns == 0 {
+                break;
+            }
+            self.clock.sleep_ns(ns);
+        }
+        let now = self.clock.now_ns();
+        self.requests.take(now, 1f);
+    }
+
+    pub fn budget(&mut self) -> Budget {
+        let now = self.clock.now_ns();
+        Budget {
+            requests: self.requests.available(now),
+            requests_reset_in: self.requests.resets_in(now),
+            cpu: self.cpu.available(now),
+            cpu_reset_in: self.cpu.resets_in(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webapi::{StatusResponse, Window};
+
+    static SEC: u64 = 1_000_000_000;
+
+    fn window((amount, limit, resets_in): (float, float, float)) -> Window {
+        Window { amount: amount, limit: limit, resets_in: resets_in }
+    }
+
+    fn status(requests: (float, float, float), cpu: (float, float, float)) -> StatusResponse {
+        StatusResponse {
+            easy_chair_id: ~"0",
+            contest_score: 0f,
+            lightning_score: 0f,
+            training_score: 0f,
+            mismatches: 0f,
+            num_requests: 0f,
+            cpu_total_time: 0f,
+            request_window: window(requests),
+            cpu_window: window(cpu),
+        }
+    }
+
+    #[test]
+    fn test_waits_for_reset() {
+        // 2 of 5 used, resetting in 10s
+        let mut limiter = RateLimiter::new(FakeClock::new(),
+                                           &status((2f, 5f, 10f), (0f, 100f, 60f)));
+        for _ in range(0, 3) {
+            limiter.wait();
+        }
+        assert_eq!(limiter.clock.now_ns(), 0);
+        assert!(!limiter.has_room(0f));
+
+        limiter.wait();
+        assert_eq!(limiter.clock.now_ns(), 10 * SEC);
+
+        // the next window is the usual 20s
+        for _ in range(0, 4) {
+            limiter.wait();
+        }
+        assert_eq!(limiter.clock.now_ns(), 10 * SEC);
+        limiter.wait();
+        assert_eq!(limiter.clock.now_ns(), 30 * SEC);
+    }
+
+    #[test]
+    fn test_cpu_window() {
+        // plenty of requests, but no CPU time until it resets in 30s
+        let mut limiter = RateLimiter::new(FakeClock::new(),
+                                           &status((0f, 5f, 20f), (100f, 100f, 30f)));
+        assert!(!limiter.has_room(0f));
+        limiter.wait();
+        assert_eq!(limiter.clock.now_ns(), 30 * SEC);
+    }
+
+    #[test]
+    fn test_throttled() {
+        let mut limiter = RateLimiter::new(FakeClock::new(),
+                                           &status((0f, 5f, 20f), (0f, 100f, 60f)));
+        assert!(!limiter.needs_sync());
+        limiter.throttled();
+        assert!(limiter.needs_sync());
+
+        limiter.sync(&status((1f, 5f, 4f), (0f, 100f, 60f)));
+        assert!(!limiter.needs_sync());
+        let budget = limiter.budget();
+        assert_eq!(budget.requests, 4f);
+        assert_eq!(budget.requests_reset_in, 4f);
+
+        limiter.clock.advance_ns(61 * SEC);
+        assert!(limiter.needs_sync());
+    }
+
+    #[test]
+    fn test_unsynced() {
+        let mut limiter = RateLimiter::unsynced(FakeClock::new());
+        assert!(limiter.needs_sync());
+        limiter.wait();
+        limiter.wait();
+        assert_eq!(limiter.clock.now_ns(), 20 * SEC);
+    }
+}
@@ -0,0 +1,301 @@
+use std::io;
+use std::io::{ReaderUtil, WriterUtil};
+use std::num::FromStrRadix;
+use std::os;
+use std::path::Path;
+use extra::json;
+use extra::json::{Json, ToJson, Object, Number, String, List};
+use extra::treemap::TreeMap;
+
+// Endpoints whose response doesn't depend on what was sent, so a replay
+// can answer with any recording of them.
+static BODY_FREE_PATHS: [&'static str, ..2] = ["status", "myproblems"];
+
+// One HTTP request to the server and its response, without the auth key.
+#[deriving(Clone, Eq)]
+pub struct Exchange {
+    method: ~str,
+    // e.g. "eval", as in make_url
+    path: ~str,
+    body: ~str,
+    status: uint,
+    response: ~str,
+}
+
+impl Exchange {
+    pub fn to_json(&self) -> Json {
+        let mut obj = TreeMap::new();
+        obj.insert(~"method", self.method.to_json());
+        obj.insert(~"path", self.path.to_json());
+        obj.insert(~"body", self.body.to_json());
+        obj.insert(~"status", (self.status as float).to_json());
+        obj.insert(~"response", self.response.to_json());
+        obj.to_json()
+    }
+
+    pub fn from_json(j: &Json) -> Option<Exchange> {
+        let obj = match *j {
+            Object(ref obj) => obj,
+            _ => return None,
+        };
+        match (str_field(*obj, "method"), str_field(*obj, "path"), str_field(*obj, "body"),
+               obj.find(&~"status"), str_field(*obj, "response")) {
+            (Some(method), Some(path), Some(body), Some(&Number(status)), Some(response)) => {
+                Some(Exchange {
+                    method: method,
+                    path: path,
+                    body: body,
+                    status: status as uint,
+                    response: response,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn str_field(obj: &Object, key: &str) -> Option<~str> {
+    match obj.find(&key.to_owned()) {
+        Some(&String(ref s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+// Where the request task's HTTP goes: to the server, to the server with
+// every exchange appended to a session file (one JSON object per line),
+// or nowhere, answered from a session file.
+pub enum Session {
+    Live,
+    Recording(Path),
+    Replaying(Replay),
+}
+
+impl Session {
+    // SQUIGGLE_REPLAY=FILE answers from FILE; otherwise
+    // SQUIGGLE_RECORD=FILE records to it.
+    pub fn from_env() -> Result<Session, ~str> {
+        match os::getenv("SQUIGGLE_REPLAY") {
+            Some(file) => return match Replay::load(&Path(file)) {
+                Ok(replay) => Ok(Replaying(replay)),
+                Err(e) => Err(fmt!("can't replay %s: %s", file, e)),
+            },
+            None => {}
+        }
+        match os::getenv("SQUIGGLE_RECORD") {
+            Some(file) => Ok(Recording(Path(file))),
+            None => Ok(Live),
+        }
+    }
+
+    pub fn is_replay(&self) -> bool {
+        match *self {
+            Replaying(_) => true,
+            _ => false,
+        }
+    }
+
+    // The inputs to evaluate problem `id` on next: when replaying, those
+    // that were recorded (see Replay::eval_inputs), since nothing else
+    // has an answer; otherwise None, and any will do.
+    pub fn eval_inputs(&self, id: &str, n: uint) -> Option<~[u64]> {
+        match *self {
+            Replaying(ref replay) => Some(replay.eval_inputs(id, n)),
+            _ => None,
+        }
+    }
+
+    // The status and body of the response to a request, made with `live`
+    // unless it's a replay.
+    pub fn send(&mut self, method: &str, path: &str, body: &str,
+                live: &fn() -> (uint, ~str)) -> (uint, ~str) {
+        match *self {
+            Live => live(),
+            Recording(ref file) => {
+                let (status, response) = live();
+                let exchange = Exchange {
+                    method: method.to_owned(),
+                    path: path.to_owned(),
+                    body: body.to_owned(),
+                    status: status,
+                    response: response.clone(),
+                };
+                match io::file_writer(file, [io::Create, io::Append]) {
+                    Ok(w) => w.write_line(exchange.to_json().to_str()),
+                    Err(e) => printfln!("warning: couldn't record to %s: %s", file.to_str(), e),
+                }
+                (status, response)
+            }
+            Replaying(ref mut replay) => {
+                match replay.respond(method, path, body) {
+                    Some(r) => r,
+                    None => (404, fmt!("no recorded response for %s /%s", method, path)),
+                }
+            }
+        }
+    }
+}
+
+// A recorded session, each exchange answered once.
+pub struct Replay {
+    exchanges: ~[Exchange],
+    used: ~[bool],
+}
+
+impl Replay {
+    pub fn new(exchanges: ~[Exchange]) -> Replay {
+        let used = exchanges.map(|_| false);
+        Replay {
+            exchanges: exchanges,
+            used: used,
+        }
+    }
+
+    pub fn load(file: &Path) -> Result<Replay, ~str> {
+        let reader = match io::file_reader(file) {
+            Ok(r) => r,
+            Err(e) => return Err(e),
+        };
+        let mut exchanges = ~[];
+        let mut line_no = 0u;
+        for line in reader.read_lines().iter() {
+            line_no += 1;
+            if line.trim().is_empty() {
+                loop;
+            }
+            match json::from_str(*line) {
+                Ok(j) => match Exchange::from_json(&j) {
+                    Some(exchange) => exchanges.push(exchange),
+                    None => return Err(fmt!("line %u: not an exchange", line_no)),
+                },
+                Err(e) => return Err(fmt!("line %u: %s", line_no, e.to_str())),
+            }
+        }
+        Ok(Replay::new(exchanges))
+    }
+
+    // The arguments of the unused recorded evals of problem `id`, in the
+    // order they were made, a whole request at a time until there are at
+    // least `n` (or there are no more). Sending them in the same chunks
+    // gets the recorded answers back, which random inputs never would.
+    pub fn eval_inputs(&self, id: &str, n: uint) -> ~[u64] {
+        let mut inputs = ~[];
+        for (i, e) in self.exchanges.iter().enumerate() {
+            if inputs.len() >= n {
+                break;
+            }
+            if self.used[i] || e.path.as_slice() != "eval" {
+                loop;
+            }
+            match eval_arguments(e.body, id) {
+                Some(args) => inputs.push_all_move(args),
+                None => {}
+            }
+        }
+        inputs
+    }
+
+    // The first unused exchange that's the same request exactly. For
+    // endpoints that don't take a body (see BODY_FREE_PATHS) any unused
+    // one will do; anything else, like an eval of different inputs or a
+    // guess of a different program, has no answer.
+    pub fn respond(&mut self, method: &str, path: &str, body: &str) -> Option<(uint, ~str)> {
+        let any_body = BODY_FREE_PATHS.iter().any(|&p| p == path);
+        let mut found = None;
+        for (i, e) in self.exchanges.iter().enumerate() {
+            if self.used[i] || e.method.as_slice() != method || e.path.as_slice() != path {
+                loop;
+            }
+            if any_body || e.body.as_slice() == body {
+                found = Some(i);
+                break;
+            }
+        }
+
+        do found.map |&i| {
+            self.used[i] = true;
+            (self.exchanges[i].status, self.exchanges[i].response.clone())
+        }
+    }
+}
+
+// The arguments of an eval request body, if it's about problem `id`.
+fn eval_arguments(body: &str, id: &str) -> Option<~[u64]> {
+    let j = match json::from_str(body) {
+        Ok(j) => j,
+        Err(_) => return None,
+    };
+    let obj = match j {
+        Object(obj) => obj,
+        _ => return None,
+    };
+    match str_field(obj, "id") {
+        Some(ref i) if i.as_slice() == id => {}
+        _ => return None,
+    }
+    let args = match obj.find(&~"arguments") {
+        Some(&List(ref args)) => args,
+        _ => return None,
+    };
+    let mut inputs = ~[];
+    for arg in args.iter() {
+        match *arg {
+            String(ref s) => {
+                let digits = if s.starts_with("0x") { s.slice_from(2) } else { s.as_slice() };
+                match FromStrRadix::from_str_radix(digits, 16) {
+                    Some(x) => inputs.push(x),
+                    None => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(method: &str, path: &str, body: &str, response: &str) -> Exchange {
+        Exchange {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body: body.to_owned(),
+            status: 200,
+            response: response.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let e = exchange("POST", "eval", "{\"id\": \"x\"}", "{\"status\": \"ok\"}");
+        assert_eq!(Exchange::from_json(&e.to_json()), Some(e.clone()));
+    }
+
+    #[test]
+    fn test_respond() {
+        let mut replay = Replay::new(~[
+            exchange("POST", "eval", "a", "1"),
+            exchange("POST", "eval", "b", "2"),
+            exchange("POST", "guess", "a", "3"),
+            exchange("POST", "eval", "a", "4"),
+            exchange("GET", "myproblems", "", "5"),
+            exchange("GET", "myproblems", "", "6"),
+        ]);
+
+        // only the same request gets an answer, each recording once
+        assert_eq!(replay.respond("POST", "eval", "b"), Some((200, ~"2")));
+        assert_eq!(replay.respond("POST", "eval", "a"), Some((200, ~"1")));
+        assert_eq!(replay.respond("POST", "eval", "a"), Some((200, ~"4")));
+        assert_eq!(replay.respond("POST", "eval", "a"), None);
+        assert_eq!(replay.respond("POST", "eval", "z"), None);
+        assert_eq!(replay.respond("POST", "guess", "z"), None);
+        assert_eq!(replay.respond("GET", "guess", "a"), None);
+        assert_eq!(replay.respond("POST", "guess", "a"), Some((200, ~"3")));
+
+        // but the problem list is the problem list, in order
+        assert_eq!(replay.respond("GET", "myproblems", "x"), Some((200, ~"5")));
+        assert_eq!(replay.respond("GET", "myproblems", ""), Some((200, ~"6")));
+        assert_eq!(replay.respond("GET", "myproblems", ""), None);
+    }
+}
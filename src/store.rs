@@ -0,0 +1,435 @@
+use program::*;
+use webapi::*;
+
+use std::io;
+use std::io::WriterUtil;
+use std::num::{FromStrRadix, ToStrRadix};
+use std::os;
+use std::path::Path;
+use extra::json;
+use extra::json::{Json, ToJson, Object, Number, String, List, Boolean};
+use extra::time;
+use extra::treemap::TreeMap;
+
+static DEFAULT_DIR: &'static str = "store";
+
+// Everything we know about one problem.
+#[deriving(Clone)]
+pub struct ProblemRecord {
+    problem: Problem,
+    // every eval result we've been given, oldest first
+    pairs: ~[(u64, u64)],
+    guesses: ~[GuessRecord],
+    // the program that won
+    solution: Option<~str>,
+}
+
+#[deriving(Clone)]
+pub struct GuessRecord {
+    program: ~str,
+    result: GuessResult,
+}
+
+impl ProblemRecord {
+    pub fn new(problem: Problem) -> ProblemRecord {
+        ProblemRecord {
+            problem: problem,
+            pairs: ~[],
+            guesses: ~[],
+            solution: None,
+        }
+    }
+}
+
+// An on-disk database of problems, one JSON file per problem id in a
+// directory (SQUIGGLE_STORE, or ./store), plus the last problem list we
+// fetched. Files are replaced whole, so a run killed halfway through
+// leaves the previous version behind rather than half a file.
+#[deriving(Clone)]
+pub struct Store {
+    dir: Path,
+}
+
+impl Store {
+    pub fn open() -> Store {
+        let dir = os::getenv("SQUIGGLE_STORE").unwrap_or_default(DEFAULT_DIR.to_owned());
+        Store::at(Path(dir))
+    }
+
+    // The directory is made when something is first saved in it.
+    pub fn at(dir: Path) -> Store {
+        Store { dir: dir }
+    }
+
+    // Training and local problems are named by their program, which
+    // doesn't make a good file name; only real ids get stored. So the
+    // fake problems of faketrain, corpustrain and bench are never
+    // remembered, and never create the directory.
+    fn path(&self, id: &str) -> Option<Path> {
+        let ok = !id.is_empty() && id.iter().all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+        if ok { Some(self.dir.push(fmt!("%s.json", id))) } else { None }
+    }
+
+    pub fn load(&self, id: &str) -> Option<ProblemRecord> {
+        do self.path(id).chain |path| {
+            read_json(&path).chain(|j| record_from_json(&j))
+        }
+    }
+
+    pub fn save(&self, record: &ProblemRecord) {
+        match self.path(record.problem.id) {
+            Some(path) => write_json(&path, record_to_json(record)),
+            None => {}
+        }
+    }
+
+    // The record for `problem`, or a fresh one.
+    pub fn load_or_new(&self, problem: &Problem) -> ProblemRecord {
+        self.load(problem.id).unwrap_or_default(ProblemRecord::new(problem.clone()))
+    }
+
+    pub fn add_pairs(&self, problem: &Problem, pairs: &[(u64, u64)]) {
+        let mut record = self.load_or_new(problem);
+        record.pairs.push_all(pairs);
+        self.save(&record);
+    }
+
+    pub fn add_guess(&self, problem: &Problem, program: &str, result: &GuessResult) {
+        let mut record = self.load_or_new(problem);
+        match *result {
+            Win => record.solution = Some(program.to_owned()),
+            _ => {}
+        }
+        record.guesses.push(GuessRecord { program: program.to_owned(), result: result.clone() });
+        self.save(&record);
+    }
+
+    // The last problem list saved and how many seconds ago that was.
+    pub fn load_problem_list(&self) -> Option<(float, ~[RealProblem])> {
+        let path = self.dir.push("problems.json");
+        do read_json(&path).chain |j| {
+            match j {
+                Object(ref obj) => {
+                    let fetched = num_field(*obj, "fetched");
+                    let probs = list_field(*obj, "problems").chain(|l| {
+                        collect_all(l.iter().transform(real_problem_from_json))
+                    });
+                    match (fetched, probs) {
+                        (Some(fetched), Some(probs)) => Some((now_secs() - fetched, probs)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        }
+    }
+
+    pub fn save_problem_list(&self, probs: &[RealProblem]) {
+        let mut obj = ~TreeMap::new();
+        obj.insert(~"fetched", Number(now_secs()));
+        obj.insert(~"problems", List(probs.iter().transform(real_problem_to_json).collect()));
+        write_json(&self.dir.push("problems.json"), Object(obj));
+    }
+}
+
+fn now_secs() -> float {
+    let t = time::get_time();
+    (t.sec as float) + (t.nsec as float) / 1_000_000_000f
+}
+
+fn read_json(path: &Path) -> Option<Json> {
+    if !os::path_exists(path) {
+        return None;
+    }
+    match io::read_whole_file_str(path) {
+        Ok(s) => match json::from_str(s) {
+            Ok(j) => Some(j),
+            Err(e) => {
+                printfln!("warning: ignoring %s: %s", path.to_str(), e.to_str());
+                None
+            }
+        },
+        Err(e) => {
+            printfln!("warning: couldn't read %s: %s", path.to_str(), e);
+            None
+        }
+    }
+}
+
+fn write_json(path: &Path, j: Json) {
+    let dir = path.dir_path();
+    if !os::path_exists(&dir) && !os::mkdir_recursive(&dir, 0x1ed) {
+        printfln!("warning: couldn't create %s", dir.to_str());
+        return;
+    }
+    let tmp = path.with_filetype("tmp");
+    match io::file_writer(&tmp, [io::Create, io::Truncate]) {
+        Ok(w) => w.write_str(j.to_str()),
+        Err(e) => {
+            printfln!("warning: couldn't write %s: %s", tmp.to_str(), e);
+            return;
+        }
+    }
+    if !os::rename_file(&tmp, path) {
+        printfln!("warning: couldn't replace %s", path.to_str());
+    }
+}
+
+fn hex(x: u64) -> Json {
+    String(fmt!("0x%s", x.to_str_radix(16)))
+}
+
+fn from_hex(j: &Json) -> Option<u64> {
+    match *j {
+        String(ref s) if s.starts_with("0x") => FromStrRadix::from_str_radix(s.slice_from(2), 16),
+        _ => None,
+    }
+}
+
+fn field<'a>(obj: &'a Object, key: &str) -> Option<&'a Json> {
+    obj.find(&key.to_owned())
+}
+
+fn str_field(obj: &Object, key: &str) -> Option<~str> {
+    match field(obj, key) {
+        Some(&String(ref s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+fn num_field(obj: &Object, key: &str) -> Option<float> {
+    match field(obj, key) {
+        Some(&Number(n)) => Some(n),
+        _ => None,
+    }
+}
+
+fn list_field<'a>(obj: &'a Object, key: &str) -> Option<&'a ~[Json]> {
+    match field(obj, key) {
+        Some(&List(ref l)) => Some(l),
+        _ => None,
+    }
+}
+
+// Some of everything, if there's nothing missing.
+fn collect_all<T, I: Iterator<Option<T>>>(mut it: I) -> Option<~[T]> {
+    let mut out = ~[];
+    for x in it {
+        match x {
+            Some(x) => out.push(x),
+            None => return None,
+        }
+    }
+    Some(out)
+}
+
+fn problem_to_json(p: &Problem) -> Json {
+    let mut obj = ~TreeMap::new();
+    obj.insert(~"id", p.id.to_json());
+    obj.insert(~"size", Number(p.size as float));
+    let ops: ~[~str] = p.operators.iter().transform(|op| op.to_str()).collect();
+    obj.insert(~"operators", ops.to_json());
+    Object(obj)
+}
+
+fn problem_from_json(j: &Json) -> Option<Problem> {
+    let obj = match *j {
+        Object(ref obj) => obj,
+        _ => return None,
+    };
+    let ops = list_field(*obj, "operators").chain(|l| {
+        collect_all(l.iter().transform(|op| match *op {
+            String(ref s) => FromStr::from_str(s.as_slice()),
+            _ => None,
+        }))
+    });
+    match (str_field(*obj, "id"), num_field(*obj, "size"), ops) {
+        (Some(id), Some(size), Some(ops)) => Some(Problem {
+            id: id,
+            size: size as u8,
+            operators: OperatorSet::from_ops(ops),
+        }),
+        _ => None,
+    }
+}
+
+fn real_problem_to_json(p: &RealProblem) -> Json {
+    let mut obj = ~TreeMap::new();
+    obj.insert(~"problem", problem_to_json(&p.problem));
+    match p.time_left {
+        Some(t) => { obj.insert(~"timeLeft", Number(t)); }
+        None => {}
+    }
+    obj.insert(~"solved", Boolean(p.solved));
+    Object(obj)
+}
+
+fn real_problem_from_json(j: &Json) -> Option<RealProblem> {
+    let obj = match *j {
+        Object(ref obj) => obj,
+        _ => return None,
+    };
+    let solved = match field(*obj, "solved") {
+        Some(&Boolean(b)) => Some(b),
+        _ => None,
+    };
+    match (field(*obj, "problem").chain(problem_from_json), solved) {
+        (Some(problem), Some(solved)) => Some(RealProblem {
+            problem: problem,
+            time_left: num_field(*obj, "timeLeft"),
+            solved: solved,
+        }),
+        _ => None,
+    }
+}
+
+fn guess_to_json(g: &GuessRecord) -> Json {
+    let mut obj = ~TreeMap::new();
+    obj.insert(~"program", g.program.to_json());
+    match g.result {
+        Win => {
+            obj.insert(~"status", (~"win").to_json());
+        }
+        Mismatch(input, real, ours) => {
+            obj.insert(~"status", (~"mismatch").to_json());
+            obj.insert(~"values", List(~[hex(input), hex(real), hex(ours)]));
+        }
+        Error(ref message) => {
+            obj.insert(~"status", (~"error").to_json());
+            obj.insert(~"message", message.to_json());
+        }
+    }
+    Object(obj)
+}
+
+fn guess_from_json(j: &Json) -> Option<GuessRecord> {
+    let obj = match *j {
+        Object(ref obj) => obj,
+        _ => return None,
+    };
+    let result = match str_field(*obj, "status") {
+        Some(~"win") => Some(Win),
+        Some(~"mismatch") => {
+            match list_field(*obj, "values").chain(|l| collect_all(l.iter().transform(from_hex))) {
+                Some(vals) => {
+                    if vals.len() == 3 { Some(Mismatch(vals[0], vals[1], vals[2])) } else { None }
+                }
+                None => None,
+            }
+        }
+        Some(~"error") => str_field(*obj, "message").map(|m| Error(m.clone())),
+        _ => None,
+    };
+    match (str_field(*obj, "program"), result) {
+        (Some(program), Some(result)) => Some(GuessRecord { program: program, result: result }),
+        _ => None,
+    }
+}
+
+fn record_to_json(r: &ProblemRecord) -> Json {
+    let mut obj = ~TreeMap::new();
+    obj.insert(~"problem", problem_to_json(&r.problem));
+    let pairs = r.pairs.iter().transform(|&(x, y)| List(~[hex(x), hex(y)])).collect();
+    obj.insert(~"pairs", List(pairs));
+    obj.insert(~"guesses", List(r.guesses.iter().transform(guess_to_json).collect()));
+    match r.solution {
+        Some(ref s) => { obj.insert(~"solution", s.to_json()); }
+        None => {}
+    }
+    Object(obj)
+}
+
+fn record_from_json(j: &Json) -> Option<ProblemRecord> {
+    let obj = match *j {
+        Object(ref obj) => obj,
+        _ => return None,
+    };
+    let pairs = list_field(*obj, "pairs").chain(|l| {
+        collect_all(l.iter().transform(|pair| match *pair {
+            List(ref xy) if xy.len() == 2 => match (from_hex(&xy[0]), from_hex(&xy[1])) {
+                (Some(x), Some(y)) => Some((x, y)),
+                _ => None,
+            },
+            _ => None,
+        }))
+    });
+    let guesses = list_field(*obj, "guesses").chain(|l| {
+        collect_all(l.iter().transform(guess_from_json))
+    });
+    match (field(*obj, "problem").chain(problem_from_json), pairs, guesses) {
+        (Some(problem), Some(pairs), Some(guesses)) => Some(ProblemRecord {
+            problem: problem,
+            pairs: pairs,
+            guesses: guesses,
+            solution: str_field(*obj, "solution"),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use program::*;
+    use webapi::*;
+    use std::os;
+    use std::rand;
+
+    fn temp_store() -> Store {
+        let name = fmt!("squiggle-store-%u", rand::random::<uint>());
+        Store::at(os::tmpdir().push(name))
+    }
+
+    fn problem() -> Problem {
+        Problem {
+            id: ~"abc123",
+            size: 12,
+            operators: FromStr::from_str("fold,not,plus").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let store = temp_store();
+        assert!(store.load("abc123").is_none());
+
+        store.add_pairs(&problem(), [(0, 1), (-1, 0x8000_0000_0000_0000)]);
+        store.add_guess(&problem(), "(lambda (x) x)", &Mismatch(3, 4, 3));
+        store.add_pairs(&problem(), [(3, 4)]);
+        store.add_guess(&problem(), "(lambda (x) (plus x 1))", &Win);
+
+        let record = store.load("abc123").expect("no record");
+        assert_eq!(record.problem, problem());
+        assert_eq!(record.pairs, ~[(0, 1), (-1, 0x8000_0000_0000_0000), (3, 4)]);
+        assert_eq!(record.guesses.len(), 2);
+        match record.guesses[0].result {
+            Mismatch(3, 4, 3) => {}
+            _ => fail!("wrong guess result"),
+        }
+        assert_eq!(record.solution, Some(~"(lambda (x) (plus x 1))"));
+    }
+
+    #[test]
+    fn test_unsafe_ids_not_stored() {
+        let store = temp_store();
+        let mut p = problem();
+        p.id = ~"(lambda (x) x)";
+        store.add_pairs(&p, [(0, 0)]);
+        assert!(store.load(p.id).is_none());
+        assert!(!os::path_exists(&store.dir));
+    }
+
+    #[test]
+    fn test_problem_list() {
+        let store = temp_store();
+        let probs = ~[
+            RealProblem { problem: problem(), time_left: Some(12.5), solved: false },
+            RealProblem { problem: problem(), time_left: None, solved: true },
+        ];
+        store.save_problem_list(probs);
+
+        let (age, loaded) = store.load_problem_list().expect("no problem list");
+        assert!(age >= 0f && age < 60f);
+        assert_eq!(loaded, probs);
+    }
+}
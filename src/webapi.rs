@@ -1,7 +1,12 @@
 use program::*;
+use eval::ValidProgram;
+use ratelimit::{Budget, Bucket, Clock, RateLimiter, SystemClock, VirtualClock};
+use session::Session;
 
 use std::cell::Cell;
+use std::cmp;
 use std::comm;
+use std::comm::SharedChan;
 use std::hashmap::HashMap;
 use std::io::ReaderUtil;
 use std::rand;
@@ -13,6 +18,7 @@ use std::task;
 use std::to_str::ToStr;
 use std::num::{FromStrRadix,ToStrRadix};
 use std::util;
+use std::vec;
 use extra::json;
 use extra::json::{Json, ToJson, Object, Number, String, List, Boolean};
 use extra::time;
@@ -22,39 +28,130 @@ static SERVER: &'static str = "http://icfpc2013.cloudapp.net/";
 
 static PRIVATE_KEY: &'static str = include_str!("private.key");
 
-pub struct WebApi(Chan<Request>);
+// the most arguments /eval takes in one request
+static MAX_EVAL_ARGS: uint = 256;
+// a problem's spare pairs are topped up when there are fewer than this...
+static SPARE_LOW: uint = 50;
+// ...while the request window has room for this many other requests
+static PREFETCH_RESERVE: float = 2f;
+// a problem's clock runs out this long after the first request about it,
+// so its spare pairs are no use after that
+static SPARE_LIFETIME_NS: u64 = 300_000_000_000;
+
+type Limiter = RateLimiter<SystemClock>;
+
+// Where in a reply decoding went wrong, as a path like
+// `myproblems[3].operators[1]`, and what was wrong there.
+#[deriving(Clone, Eq)]
+pub struct DecodeError {
+    path: ~str,
+    message: ~str,
+}
+
+impl ToStr for DecodeError {
+    pub fn to_str(&self) -> ~str {
+        fmt!("%s: %s", self.path, self.message)
+    }
+}
+
+type Decoded<T> = Result<T, DecodeError>;
+
+#[deriving(Clone, Eq)]
+pub enum ApiError {
+    // the reply wasn't what we expected
+    BadReply(DecodeError),
+    // no usable reply at all
+    RequestFailed(~str),
+    // the server said no, e.g. to an eval on a problem that's run out of
+    // time
+    Refused(~str),
+}
+
+impl ApiError {
+    // Might asking again work?
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            BadReply(_) | RequestFailed(_) => true,
+            Refused(_) => false,
+        }
+    }
+
+    // Has the problem run out of time? The server says 410 Gone.
+    pub fn is_expired(&self) -> bool {
+        match *self {
+            Refused(ref s) => s.starts_with("HTTP 410"),
+            _ => false,
+        }
+    }
+}
+
+impl ToStr for ApiError {
+    pub fn to_str(&self) -> ~str {
+        match *self {
+            BadReply(ref e) => fmt!("bad reply at %s", e.to_str()),
+            RequestFailed(ref s) => fmt!("request failed: %s", *s),
+            Refused(ref s) => fmt!("refused: %s", *s),
+        }
+    }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+// A handle on the request task. Clones share it, and so share its
+// request window.
+pub struct WebApi(SharedChan<Request>);
+
+impl Clone for WebApi {
+    fn clone(&self) -> WebApi {
+        WebApi((**self).clone())
+    }
+}
 
 pub trait Api {
-    fn get_training(&mut self, size: u8, operator: TrainOperator) -> Port<TrainProblem>;
-    fn get_training_blocking(&mut self, size: u8, operator: TrainOperator) -> TrainProblem {
+    // The time by the clock the problems' deadlines go by.
+    fn now_ns(&self) -> u64 {
+        time::precise_time_ns()
+    }
+
+    fn get_training(&mut self, size: u8, operator: TrainOperator) -> Port<ApiResult<TrainProblem>>;
+    fn get_training_blocking(&mut self, size: u8, operator: TrainOperator) -> ApiResult<TrainProblem> {
         self.get_training(size, operator).recv()
     }
 
-    fn get_problems(&mut self) -> Port<~[RealProblem]>;
-    fn get_problems_blocking(&mut self) -> ~[RealProblem] {
+    fn get_problems(&mut self) -> Port<ApiResult<~[RealProblem]>>;
+    fn get_problems_blocking(&mut self) -> ApiResult<~[RealProblem]> {
         self.get_problems().recv()
     }
 
-    fn eval(&mut self, problem: Problem, inputs: ~[u64]) -> Port<Option<~[u64]>>;
-    fn eval_blocking(&mut self, problem: Problem, inputs: ~[u64]) -> Option<~[u64]> {
+    fn eval(&mut self, problem: Problem, inputs: ~[u64]) -> Port<ApiResult<~[u64]>>;
+    fn eval_blocking(&mut self, problem: Problem, inputs: ~[u64]) -> ApiResult<~[u64]> {
         print("evaluating inputs...");
         let res = self.eval(problem, inputs).recv();
         println("done.");
         res
     }
 
-    fn eval_program(&mut self, program: Program, inputs: ~[u64]) -> Port<Option<~[u64]>>;
-    fn eval_program_blocking(&mut self, program: Program, inputs: ~[u64]) -> Option<~[u64]> {
+    fn eval_program(&mut self, program: Program, inputs: ~[u64]) -> Port<ApiResult<~[u64]>>;
+    fn eval_program_blocking(&mut self, program: Program, inputs: ~[u64]) -> ApiResult<~[u64]> {
         print("evaluating inputs...");
         let res = self.eval_program(program, inputs).recv();
         println("done.");
         res
     }
 
-    fn guess(&mut self, problem: Problem, program: ~str) -> Port<GuessResult>;
-    fn guess_blocking(&mut self, problem: Problem, program: ~str) -> GuessResult {
+    fn guess(&mut self, problem: Problem, program: ~str) -> Port<ApiResult<GuessResult>>;
+    fn guess_blocking(&mut self, problem: Problem, program: ~str) -> ApiResult<GuessResult> {
         self.guess(problem, program).recv()
     }
+
+    // `n` random inputs and the problem's outputs on them.
+    fn random_testcases(&mut self, problem: Problem, n: uint) -> Port<ApiResult<~[(u64, u64)]>>;
+    fn random_testcases_blocking(&mut self, problem: Problem, n: uint) -> ApiResult<~[(u64, u64)]> {
+        print("evaluating inputs...");
+        let res = self.random_testcases(problem, n).recv();
+        println("done.");
+        res
+    }
 }
 
 impl WebApi {
@@ -66,297 +163,659 @@ impl WebApi {
             WebApi::run(port.take());
         }
 
-        WebApi(chan)
+        WebApi(SharedChan::new(chan))
     }
 
     fn run(port: Port<Request>) {
-        let mut running = true;
-        let timer = Timer::new().unwrap();
+        let session = match Session::from_env() {
+            Ok(session) => session,
+            Err(e) => {
+                printfln!("error: %s", e);
+                WebApi::refuse_all(port, e);
+                return;
+            }
+        };
+        let mut conn = Conn::new(session);
+        // requests taken off the port but not made yet
+        let mut queue: ~[Request] = ~[];
+        let mut spares: ~[Spare] = ~[];
+
+        loop {
+            // take everything that's waiting, so evals can be batched
+            while port.peek() {
+                match port.try_recv() {
+                    Some(req) => queue.push(req),
+                    None => break,
+                }
+            }
 
-        let status = StatusResponse::from_json(get_request(make_url("status")));
-        let mut req_win = status.request_window;
-        let mut last_reset = time::precise_time_ns();
+            if queue.is_empty() {
+                // nothing to do, so put any room left in the window to use
+                if conn.has_room(PREFETCH_RESERVE) && prefetch(&mut spares, &mut conn) {
+                    loop;
+                }
+                match port.try_recv() {
+                    Some(req) => queue.push(req),
+                    None => break,
+                }
+            }
 
-        while running {
-            match port.try_recv() {
-                None => running = false,
-                Some(req) => {
-                    if req_win.amount >= req_win.limit {
-                        // over request limit, must wait until reset
-                        let elapsed_ms = (time::precise_time_ns() - last_reset) / 1000000;
-                        let ms_to_sleep = (1000f * req_win.resets_in) - (elapsed_ms as float);
-                        if ms_to_sleep > 0f {
-                            timer.sleep(ms_to_sleep as u64);
-                            req_win.resets_in = 20f; // may change, but hardcoded for now
-                            last_reset = time::precise_time_ns();
-                        } else {
-                            // we are past the window, ms_to_sleep is negative
-                            req_win.resets_in = 20f + ms_to_sleep; // may change, but hardcoded for now
-                            last_reset = time::precise_time_ns() + (ms_to_sleep as u64) * 1000000;
-                        }
-                        req_win.amount = 0f;
+            match queue.shift() {
+                Eval(problem, inputs, chan) => {
+                    let batch = take_evals(problem.id, (inputs, chan), &mut queue);
+                    eval_batch(&problem, batch, &mut conn);
+                }
+                RandomTestcases(problem, n, chan) => {
+                    chan.send(random_testcases(problem, n, &mut spares, &mut conn));
+                }
+                Guess(problem, prog, chan) => {
+                    let result = problem.guess(prog, &mut conn);
+                    match result {
+                        Ok(Win) => spares.retain(|s| s.problem.id != problem.id),
+                        _ => {}
                     }
-
-                    dispatch(req);
-
-                    req_win.amount += 1f;
+                    chan.send(result);
                 }
+                Status(chan) => {
+                    let status = get_status(&mut conn.session);
+                    match status {
+                        Ok(ref s) => conn.sync(s),
+                        Err(_) => {}
+                    }
+                    chan.send(status);
+                }
+                GetBudget(chan) => chan.send(conn.budget()),
+                req => dispatch(req, &mut conn),
             }
+        }
+    }
 
-
+    // Answer every request with `message`, for when there's nothing to
+    // send them to.
+    fn refuse_all(port: Port<Request>, message: ~str) {
+        loop {
+            let err = RequestFailed(message.clone());
+            match port.try_recv() {
+                None => break,
+                Some(Status(chan)) => chan.send(Err(err)),
+                Some(Train(_, _, chan)) => chan.send(Err(err)),
+                Some(Problems(chan)) => chan.send(Err(err)),
+                Some(Eval(_, _, chan)) => chan.send(Err(err)),
+                Some(EvalProgram(_, _, chan)) => chan.send(Err(err)),
+                Some(Guess(_, _, chan)) => chan.send(Err(err)),
+                Some(RandomTestcases(_, _, chan)) => chan.send(Err(err)),
+                Some(GetBudget(chan)) => chan.send(None),
+            }
         }
     }
 
-    pub fn get_status(&mut self) -> Port<StatusResponse> {
+    pub fn get_status(&mut self) -> Port<ApiResult<StatusResponse>> {
         let (port, chan) = comm::stream();
         (**self).send(Status(chan));
         port
     }
 
-    pub fn get_status_blocking(&mut self) -> StatusResponse {
+    pub fn get_status_blocking(&mut self) -> ApiResult<StatusResponse> {
         let port = self.get_status();
         port.recv()
     }
+
+    // What's left of the rate limits, as the request task sees them. None
+    // when replaying a session, which has no limits.
+    pub fn get_budget(&mut self) -> Port<Option<Budget>> {
+        let (port, chan) = comm::stream();
+        (**self).send(GetBudget(chan));
+        port
+    }
+
+    pub fn get_budget_blocking(&mut self) -> Option<Budget> {
+        self.get_budget().recv()
+    }
 }
 impl Api for WebApi {
-    pub fn get_training(&mut self, size: u8, operator: TrainOperator) -> Port<TrainProblem> {
+    pub fn get_training(&mut self, size: u8, operator: TrainOperator) -> Port<ApiResult<TrainProblem>> {
         let (port, chan) = comm::stream();
         (**self).send(Train(size, operator, chan));
         port
     }
 
-    pub fn get_problems(&mut self) -> Port<~[RealProblem]> {
+    pub fn get_problems(&mut self) -> Port<ApiResult<~[RealProblem]>> {
         let (port, chan) = comm::stream();
         (**self).send(Problems(chan));
         port
     }
 
-    pub fn eval(&mut self, problem: Problem, inputs: ~[u64]) -> Port<Option<~[u64]>> {
+    pub fn eval(&mut self, problem: Problem, inputs: ~[u64]) -> Port<ApiResult<~[u64]>> {
         let (port, chan) = comm::stream();
         (**self).send(Eval(problem, inputs, chan));
         port
     }
 
-    pub fn eval_program(&mut self, program: Program, inputs: ~[u64]) -> Port<Option<~[u64]>> {
+    pub fn eval_program(&mut self, program: Program, inputs: ~[u64]) -> Port<ApiResult<~[u64]>> {
         let (port, chan) = comm::stream();
         (**self).send(EvalProgram(program, inputs, chan));
         port
     }
 
-    pub fn guess(&mut self, problem: Problem, program: ~str) -> Port<GuessResult> {
+    pub fn guess(&mut self, problem: Problem, program: ~str) -> Port<ApiResult<GuessResult>> {
         let (port, chan) = comm::stream();
         (**self).send(Guess(problem, program, chan));
         port
     }
+
+    pub fn random_testcases(&mut self, problem: Problem, n: uint) -> Port<ApiResult<~[(u64, u64)]>> {
+        let (port, chan) = comm::stream();
+        (**self).send(RandomTestcases(problem, n, chan));
+        port
+    }
+}
+
+// Evaluated random inputs kept back for a problem, for the next time it
+// wants some.
+struct Spare {
+    problem: Problem,
+    pairs: ~[(u64, u64)],
+    created_ns: u64,
+}
+
+// The evals for `id` queued behind `first`, with `first`, as many as fit in
+// one request. The rest stay queued, in order.
+fn take_evals(id: &str, first: (~[u64], Chan<ApiResult<~[u64]>>),
+              queue: &mut ~[Request]) -> ~[(~[u64], Chan<ApiResult<~[u64]>>)] {
+    let mut total = first.first_ref().len();
+    let mut batch = ~[first];
+
+    let queued = util::replace(queue, ~[]);
+    for req in queued.consume_iter() {
+        match req {
+            Eval(problem, inputs, chan) => {
+                if problem.id.as_slice() == id && total + inputs.len() <= MAX_EVAL_ARGS {
+                    total += inputs.len();
+                    batch.push((inputs, chan));
+                } else {
+                    queue.push(Eval(problem, inputs, chan));
+                }
+            }
+            req => queue.push(req),
+        }
+    }
+    batch
+}
+
+// Make a batch of evals on `problem` in as few requests as the argument
+// limit allows, and hand each its share of the outputs.
+fn eval_batch(problem: &Problem, batch: ~[(~[u64], Chan<ApiResult<~[u64]>>)],
+              conn: &mut Conn) {
+    let mut inputs = ~[];
+    for &(ref xs, _) in batch.iter() {
+        inputs.push_all(*xs);
+    }
+    let outputs = eval_chunked(problem, inputs, conn);
+
+    let mut start = 0;
+    for (xs, chan) in batch.consume_iter() {
+        let end = start + xs.len();
+        chan.send(match outputs {
+            Ok(ref outs) => Ok(outs.slice(start, end).to_owned()),
+            Err(ref e) => Err(e.clone()),
+        });
+        start = end;
+    }
+}
+
+fn eval_chunked(problem: &Problem, inputs: &[u64], conn: &mut Conn) -> ApiResult<~[u64]> {
+    let mut outputs = ~[];
+    let mut start = 0;
+    while start < inputs.len() {
+        let end = cmp::min(start + MAX_EVAL_ARGS, inputs.len());
+        match problem.eval(inputs.slice(start, end), conn) {
+            Ok(outs) => outputs.push_all_move(outs),
+            Err(e) => return Err(e),
+        }
+        start = end;
+    }
+    Ok(outputs)
+}
+
+fn fetch_pairs(problem: &Problem, n: uint, conn: &mut Conn) -> ApiResult<~[(u64, u64)]> {
+    let recorded = conn.session.eval_inputs(problem.id, n).unwrap_or_default(~[]);
+    // a replay that's run out of recorded evals says so with a 404
+    let inputs = if !recorded.is_empty() {
+        recorded
+    } else {
+        let mut rng = rand::task_rng();
+        vec::from_fn(n, |_| rng.gen())
+    };
+    match eval_chunked(problem, inputs, conn) {
+        Ok(outs) => Ok(inputs.iter().zip(outs.iter()).transform(|(&x, &y)| (x, y)).collect()),
+        Err(e) => Err(e),
+    }
 }
 
+// `n` evaluated random inputs for `problem`, from its spare pairs if there
+// are enough. Otherwise whole requests' worth are fetched (they cost the
+// same) and what isn't needed now is kept.
+fn random_testcases(problem: Problem, n: uint, spares: &mut ~[Spare],
+                    conn: &mut Conn) -> ApiResult<~[(u64, u64)]> {
+    let mut found = None;
+    for (i, spare) in spares.iter().enumerate() {
+        if spare.problem.id == problem.id {
+            found = Some(i);
+            break;
+        }
+    }
+    let i = match found {
+        Some(i) => i,
+        None => {
+            spares.push(Spare {
+                problem: problem.clone(),
+                pairs: ~[],
+                created_ns: time::precise_time_ns(),
+            });
+            spares.len() - 1
+        }
+    };
+
+    let spare = &mut spares[i];
+    if spare.pairs.len() < n {
+        let missing = n - spare.pairs.len();
+        let requests = (missing + MAX_EVAL_ARGS - 1) / MAX_EVAL_ARGS;
+        match fetch_pairs(&problem, requests * MAX_EVAL_ARGS, conn) {
+            Ok(pairs) => spare.pairs.push_all_move(pairs),
+            Err(e) => return Err(e),
+        }
+    }
+
+    // a replay may have recorded fewer
+    let n = cmp::min(n, spare.pairs.len());
+    let taken = spare.pairs.slice_to(n).to_owned();
+    spare.pairs = spare.pairs.slice_from(n).to_owned();
+    Ok(taken)
+}
+
+// Top up the spare pairs of a problem that's running low. Returns whether
+// that took a request.
+fn prefetch(spares: &mut ~[Spare], conn: &mut Conn) -> bool {
+    let now = time::precise_time_ns();
+    spares.retain(|s| now - s.created_ns < SPARE_LIFETIME_NS);
+
+    for spare in spares.mut_iter() {
+        if spare.pairs.len() < SPARE_LOW {
+            match fetch_pairs(&spare.problem, MAX_EVAL_ARGS, conn) {
+                Ok(pairs) => spare.pairs.push_all_move(pairs),
+                // the server won't have it any more; don't keep asking
+                Err(_) => spare.created_ns = 0,
+            }
+            return true;
+        }
+    }
+    false
+}
+
+// The server's rules, as FakeApi keeps them: each problem may be asked
+// about for 300s from the first eval or guess, and 5 requests per 20s.
+static FAKE_TIME_LIMIT_NS: u64 = 300_000_000_000;
+static FAKE_REQUESTS: float = 5f;
+static FAKE_WINDOW_NS: u64 = 20_000_000_000;
+// random inputs tried on a guess the BDDs can't decide
+static FAKE_GUESS_SAMPLES: uint = 100000;
+
+// A stand-in for the server, answering from programs we already know.
+// Time passes on its own clock, which skips ahead instead of sleeping
+// when the request window is full, so problems run out of time as they
+// would for real.
 pub struct FakeApi {
-    programs: ~[Program],
-    // for checking answers
-    by_str: HashMap<~str, Program>
+    // ids still to be handed out for training
+    training: ~[~str],
+    problems: HashMap<~str, FakeProblem>,
+    // ids in the order they were added
+    order: ~[~str],
+    clock: ~Clock,
+    window: Bucket,
+    stats: FakeStats,
 }
 
-impl FakeApi {
-    pub fn new(progs: ~[Program]) -> FakeApi {
-        // this copies every program, but it shouldn't matter much at
-        // all: only run once.
-        let by_str = progs.iter().transform(|p| (p.to_str(), p.clone())).collect();
-        FakeApi {
-            programs: progs,
-            by_str: by_str
+struct FakeProblem {
+    program: ValidProgram,
+    operators: OperatorSet,
+    started_ns: Option<u64>,
+    solved: bool,
+}
+
+#[deriving(Clone)]
+pub struct FakeStats {
+    requests: uint,
+    evals: uint,
+    guesses: uint,
+    wins: uint,
+    mismatches: uint,
+    refusals: uint,
+    throttled_ns: u64,
+}
+
+impl FakeStats {
+    pub fn new() -> FakeStats {
+        FakeStats {
+            requests: 0,
+            evals: 0,
+            guesses: 0,
+            wins: 0,
+            mismatches: 0,
+            refusals: 0,
+            throttled_ns: 0,
         }
     }
+}
+
+impl ToStr for FakeStats {
+    fn to_str(&self) -> ~str {
+        fmt!("%u requests (%u evals, %u guesses), %u wins, %u mismatches, %u refused, %.1fs throttled",
+             self.requests, self.evals, self.guesses, self.wins, self.mismatches, self.refusals,
+             self.throttled_ns as float / 1_000_000_000f)
+    }
+}
 
-    fn get_prog<'a>(&'a self, id: &str) -> &'a Program {
-        self.by_str.find_equiv(&id).expect(fmt!("unknown problem id %s", id))
+impl FakeApi {
+    pub fn new(progs: ~[ValidProgram]) -> FakeApi {
+        FakeApi::with_clock(progs, ~VirtualClock::new() as ~Clock)
+    }
+
+    pub fn with_clock(progs: ~[ValidProgram], clock: ~Clock) -> FakeApi {
+        let now = clock.now_ns();
+        let mut api = FakeApi {
+            training: ~[],
+            problems: HashMap::new(),
+            order: ~[],
+            clock: clock,
+            window: Bucket::new(FAKE_REQUESTS, FAKE_WINDOW_NS, now),
+            stats: FakeStats::new(),
+        };
+        for p in progs.consume_iter() {
+            let id = p.to_str();
+            api.add_prog(id, p);
+        }
+        api
     }
 
     pub fn has_programs(&self) -> bool {
-        !self.programs.is_empty()
+        !self.training.is_empty()
+    }
+
+    pub fn add_prog(&mut self, id: &str, program: ValidProgram) {
+        let operators = program.get_ref().operators();
+        self.add_problem(id, program, operators);
+    }
+
+    // A problem listing `operators`, which may be more than the program
+    // uses (e.g. bonus).
+    pub fn add_problem(&mut self, id: &str, program: ValidProgram, operators: OperatorSet) {
+        self.training.push(id.to_owned());
+        if !self.problems.contains_key_equiv(&id) {
+            self.order.push(id.to_owned());
+        }
+        self.problems.insert(id.to_owned(), FakeProblem {
+            operators: operators,
+            program: program,
+            started_ns: None,
+            solved: false,
+        });
+    }
+
+    pub fn stats(&self) -> FakeStats {
+        self.stats.clone()
     }
 
-    pub fn add_prog(&mut self, id: &str, program: Program) {
-        self.programs.push(program.clone());
-        //self.by_str = self.programs.iter().transform(|p| (p.to_str(), p.clone())).collect();
-        self.by_str.insert(id.to_owned(), program);
+    // Start a new request window now, so that what earlier problems
+    // asked doesn't hold up the next one.
+    pub fn fresh_window(&mut self) {
+        self.window = Bucket::new(FAKE_REQUESTS, FAKE_WINDOW_NS, self.clock.now_ns());
+    }
+
+    // Let time pass, as if we'd been thinking.
+    pub fn advance_ns(&mut self, ns: u64) {
+        self.clock.sleep_ns(ns);
+    }
+
+    // Count a request, waiting for the window if it's full.
+    fn request(&mut self) {
+        let wait = self.window.wait_ns(self.clock.now_ns(), 1f);
+        if wait > 0 {
+            self.clock.sleep_ns(wait);
+            self.stats.throttled_ns += wait;
+        }
+        self.window.take(self.clock.now_ns(), 1f);
+        self.stats.requests += 1;
+    }
+
+    // The problem's program, if it can still be asked about. The first
+    // time starts its clock.
+    fn open_problem(&mut self, id: &str) -> ApiResult<ValidProgram> {
+        let now = self.clock.now_ns();
+        let result = match self.problems.find_mut(&id.to_owned()) {
+            None => Err(refused(404, "problem not found")),
+            Some(p) => {
+                let started = p.started_ns.unwrap_or_default(now);
+                p.started_ns = Some(started);
+                if p.solved {
+                    Err(refused(412, "problem was already solved"))
+                } else if now - started > FAKE_TIME_LIMIT_NS {
+                    Err(refused(410, "problem requested more than 5 minutes ago"))
+                } else {
+                    Ok(p.program.clone())
+                }
+            }
+        };
+        if result.is_err() {
+            self.stats.refusals += 1;
+        }
+        result
+    }
+
+    fn run_eval(&mut self, id: &str, inputs: &[u64]) -> ApiResult<~[u64]> {
+        self.request();
+        if inputs.len() > MAX_EVAL_ARGS {
+            self.stats.refusals += 1;
+            return Err(refused(400, fmt!("more than %u arguments", MAX_EVAL_ARGS)));
+        }
+        let prog = match self.open_problem(id) {
+            Ok(prog) => prog,
+            Err(e) => return Err(e),
+        };
+        self.stats.evals += 1;
+        Ok(inputs.iter().transform(|&x| prog.eval(x)).collect())
+    }
+
+    fn run_guess(&mut self, id: &str, program: &str) -> ApiResult<GuessResult> {
+        use parse::parse_program;
+
+        self.request();
+        let real = match self.open_problem(id) {
+            Ok(prog) => prog,
+            Err(e) => return Err(e),
+        };
+        self.stats.guesses += 1;
+        let ours = match parse_program(program) {
+            Ok(p) => p,
+            Err(e) => return Ok(Error(fmt!("bad program: %s", e))),
+        };
+        let allowed = self.problems.get(&id.to_owned()).operators.clone();
+        let used = ours.get_ref().operators();
+        if !allowed.allows(&used) {
+            return Ok(Error(fmt!("operators not allowed: %s",
+                                 used.difference(&allowed).to_str())));
+        }
+
+        let result = fake_verdict(&real, &ours);
+        match result {
+            Win => {
+                self.stats.wins += 1;
+                self.problems.get_mut(&id.to_owned()).solved = true;
+            }
+            Mismatch(*) => self.stats.mismatches += 1,
+            Error(_) => {}
+        }
+        Ok(result)
+    }
+}
+
+fn refused(code: uint, message: &str) -> ApiError {
+    Refused(fmt!("HTTP %u: %s", code, message))
+}
+
+// What the server would say to guessing `ours` for `real`: decided
+// exactly where the BDDs manage it, and by random inputs where not.
+fn fake_verdict(real: &ValidProgram, ours: &ValidProgram) -> GuessResult {
+    use bdd::{compare, Equivalent, Differ, Unknown};
+    use compile::compile_program;
+
+    match compare(real.get_ref(), ours.get_ref()) {
+        Equivalent => Win,
+        Differ(x) => Mismatch(x, real.eval(x), ours.eval(x)),
+        Unknown => {
+            let real = compile_program(real);
+            let ours = compile_program(ours);
+            let mut rng = rand::task_rng();
+            for _ in range(0, FAKE_GUESS_SAMPLES) {
+                let x = rng.gen();
+                let expected = real.eval(x);
+                let test = ours.eval(x);
+                if test != expected {
+                    return Mismatch(x, expected, test);
+                }
+            }
+            Win
+        }
     }
 }
 
 impl Api for FakeApi {
-    pub fn get_training(&mut self, _size: u8, _operator: TrainOperator) -> Port<TrainProblem> {
-        let prog = self.programs.shift();
-        let prog_str = prog.to_str();
+    // Its own clock, which skips ahead while throttled.
+    pub fn now_ns(&self) -> u64 {
+        self.clock.now_ns()
+    }
+
+    pub fn get_training(&mut self, _size: u8, _operator: TrainOperator) -> Port<ApiResult<TrainProblem>> {
+        self.request();
+        let (port, chan) = comm::stream();
+        if self.training.is_empty() {
+            self.stats.refusals += 1;
+            chan.send(Err(refused(404, "no training problems left")));
+            return port;
+        }
+        let id = self.training.shift();
+        let p = self.problems.get(&id);
 
         let tp = TrainProblem {
-            challenge: prog_str.clone(),
+            challenge: p.program.to_str(),
             problem: Problem {
-                id: prog_str,
-                size: prog.len(),
-                operators: prog.operators()
+                id: id.clone(),
+                size: p.program.get_ref().len(),
+                operators: p.operators.clone()
             }
         };
-
-        let (port, chan) = comm::stream();
-        chan.send(tp);
+        chan.send(Ok(tp));
         port
     }
 
-    pub fn get_problems(&mut self) -> Port<~[RealProblem]> {
-        let mut progs = ~[];
-        util::swap(&mut progs, &mut self.programs);
-
-        let real_probs = do progs.consume_iter().transform |p| {
+    pub fn get_problems(&mut self) -> Port<ApiResult<~[RealProblem]>> {
+        self.request();
+        let now = self.clock.now_ns();
+        let real_probs = do self.order.iter().transform |id| {
+            let p = self.problems.get(id);
+            let time_left = do p.started_ns.map |&started| {
+                let elapsed = now - started;
+                if elapsed >= FAKE_TIME_LIMIT_NS {
+                    0f
+                } else {
+                    (FAKE_TIME_LIMIT_NS - elapsed) as float / 1_000_000_000f
+                }
+            };
             RealProblem {
                 problem: Problem {
-                    id: p.to_str(),
-                    size: p.len(),
-                    operators: p.operators()
+                    id: id.clone(),
+                    size: p.program.get_ref().len(),
+                    operators: p.operators.clone()
                 },
-                time_left: None,
-                solved: false
+                time_left: time_left,
+                solved: p.solved
             }
         }.collect();
 
         let (port, chan) = comm::stream();
-        chan.send(real_probs);
+        chan.send(Ok(real_probs));
         port
     }
 
-    pub fn eval(&mut self, problem: Problem, inputs: ~[u64]) -> Port<Option<~[u64]>> {
-        let prog = self.get_prog(problem.id);
-
-        let outs = do inputs.consume_iter().transform |x| {
-            prog.eval(x)
-        }.collect();
-
+    pub fn eval(&mut self, problem: Problem, inputs: ~[u64]) -> Port<ApiResult<~[u64]>> {
         let (port, chan) = comm::stream();
-        chan.send(Some(outs));
+        chan.send(self.run_eval(problem.id, inputs));
         port
     }
 
-    pub fn eval_program(&mut self, program: Program, inputs: ~[u64]) -> Port<Option<~[u64]>> {
-        let outs = do inputs.consume_iter().transform |x| {
-            program.eval(x)
-        }.collect();
-
+    pub fn eval_program(&mut self, program: Program, inputs: ~[u64]) -> Port<ApiResult<~[u64]>> {
+        self.request();
         let (port, chan) = comm::stream();
-        chan.send(Some(outs));
+        if inputs.len() > MAX_EVAL_ARGS {
+            self.stats.refusals += 1;
+            chan.send(Err(refused(400, fmt!("more than %u arguments", MAX_EVAL_ARGS))));
+            return port;
+        }
+        match program.validated() {
+            Ok(program) => {
+                let outs = do inputs.consume_iter().transform |x| {
+                    program.eval(x)
+                }.collect();
+                chan.send(Ok(outs));
+            }
+            Err(e) => {
+                self.stats.refusals += 1;
+                chan.send(Err(refused(400, e.to_str())));
+            }
+        }
         port
     }
 
-    pub fn guess(&mut self, problem: Problem, program: ~str) -> Port<GuessResult> {
-        use compile::compile_program;
-        use parse::Parse;
-
-        let parsed = compile_program(&program.parse());
-        let real = compile_program(self.get_prog(problem.id));
+    pub fn random_testcases(&mut self, problem: Problem, n: uint) -> Port<ApiResult<~[(u64, u64)]>> {
         let mut rng = rand::task_rng();
+        let inputs: ~[u64] = vec::from_fn(n, |_| rng.gen());
 
-        let mut result = Win;
-        for _ in range(0, 100000) {
-            let x = rng.gen();
-            let test = parsed.eval(x);
-            let expected = real.eval(x);
-            if test != expected {
-                result = Mismatch(x, expected, test);
-                break
+        // in as many requests as the real thing takes
+        let (port, chan) = comm::stream();
+        let mut outputs = ~[];
+        let mut start = 0;
+        while start < inputs.len() {
+            let end = cmp::min(start + MAX_EVAL_ARGS, inputs.len());
+            match self.run_eval(problem.id, inputs.slice(start, end)) {
+                Ok(outs) => outputs.push_all_move(outs),
+                Err(e) => {
+                    chan.send(Err(e));
+                    return port;
+                }
             }
+            start = end;
         }
+        chan.send(Ok(inputs.iter().zip(outputs.iter()).transform(|(&x, &y)| (x, y)).collect()));
+        port
+    }
 
+    pub fn guess(&mut self, problem: Problem, program: ~str) -> Port<ApiResult<GuessResult>> {
         let (port, chan) = comm::stream();
-        chan.send(result);
+        chan.send(self.run_guess(problem.id, program));
         port
     }
 }
 
-fn dispatch(req: Request) {
+fn dispatch(req: Request, conn: &mut Conn) {
     match req {
-        Status(ref resp_chan)  => {
-            let response = get_request(make_url("status"));
-            resp_chan.send(StatusResponse::from_json(response));
-        }
         Train(_, _, ref resp_chan) => {
-            let response = post_request(make_url("train"), req.to_json_str());
-
-            match response {
-                Object(obj) => {
-                    let challenge = get_json_str(obj, ~"challenge");
-                    let id = get_json_str(obj, ~"id");
-                    let size = get_json_num(obj, ~"size");
-
-                    let array = get_json_array(obj, ~"operators");
-                    let mut ops = OperatorSet::new();
-                    let str_ops: ~[~str] = do array.iter().transform |op| {
-                        match *op {
-                            String(ref s) => s.clone(),
-                            _ => fail!("bad value in 'operators'"),
-                        }
-                    }.collect();
-                    ops.add(str_ops);
-
-                    resp_chan.send(TrainProblem {
-                        challenge: challenge,
-                        problem: Problem {
-                            id: id,
-                            size: size as u8,
-                            operators: ops,
-                        },
-                    });
-                }
-                _ => fail!("bad response"),
-            }
+            let response = post_request("train", req.to_json_str(), conn);
+            resp_chan.send(decode_reply(response, decode_train));
         }
         Problems(resp_chan) => {
-            let response = match get_request(make_url("myproblems")) {
-                List(a) => a,
-                _ => fail!("bad myproblems response")
-            };
-
-            let probs = do response.consume_iter().transform |x| {
-                match x {
-                    Object(resp) => {
-                        let id = get_json_str(resp, ~"id");
-                        let size = get_json_num(resp, ~"size");
-
-                        let solved = match resp.find(&~"solved")  {
-                            Some(&Boolean(x)) => x,
-                            None => false,
-                            _ => fail!("invalid solved boolean"),
-                        };
-
-                        let time_left = do resp.find(&~"timeLeft").map |tl| {
-                            match **tl {
-                                Number(x) => x,
-                                _ => fail!("invalid timeLeft number")
-                            }
-                        };
-                        let array = get_json_array(resp, ~"operators");
-                        let mut ops = OperatorSet::new();
-                        let str_ops = do array.iter().transform |op| {
-                            match *op {
-                                String(ref s) => s.clone(),
-                                _ => fail!("bad value in 'operators'"),
-                            }
-                        }.collect();
-                        ops.add(str_ops);
-
-                        RealProblem {
-                            problem: Problem {
-                                id: id,
-                                size: size as u8,
-                                operators: ops,
-                            },
-                            time_left: time_left,
-                            solved: solved,
-                        }
-                    }
-                    _ => fail!("invalid response")
-                }
-            }.collect();
-
-            resp_chan.send(probs);
+            let response = get_request("myproblems", conn);
+            resp_chan.send(decode_reply(response, decode_problems));
         }
-        Eval(prob, inputs, resp_chan) => resp_chan.send(prob.eval(inputs)),
+        Eval(prob, inputs, resp_chan) => resp_chan.send(prob.eval(inputs, conn)),
         EvalProgram(prog, inputs, resp_chan) => {
             let mut obj: TreeMap<~str, Json> = TreeMap::new();
             obj.insert(~"program", prog.to_str().to_json());
@@ -365,33 +824,24 @@ fn dispatch(req: Request) {
             obj.insert(~"arguments", args.to_json());
             let eval_json = obj.to_json().to_str();
 
-            let response = match post_request(make_url("eval"), eval_json) {
-                Object(o) => o,
-                _ => fail!("bad eval response")
-            };
-
-            let outs = if "ok" == get_json_str(response, ~"status") {
-                let outs = get_json_array(response, ~"outputs");
-                Some(do outs.iter().transform |j| {
-                    read_0x_hex(unwrap_json_str(j))
-                }.to_owned_vec())
-            } else {
-                println(get_json_str(response, ~"message"));
-                None
-            };
-            resp_chan.send(outs);
+            let response = post_request("eval", eval_json, conn);
+            resp_chan.send(decode_eval_reply(response));
         }
-        Guess(prob, prog, resp_chan) => resp_chan.send(prob.guess(prog)),
+        Guess(prob, prog, resp_chan) => resp_chan.send(prob.guess(prog, conn)),
+        // WebApi::run answers these itself
+        Status(*) | GetBudget(*) | RandomTestcases(*) => fail!("request not dispatched"),
     }
 }
 
 enum Request {
-    Status(Chan<StatusResponse>),
-    Train(u8, TrainOperator, Chan<TrainProblem>),
-    Problems(Chan<~[RealProblem]>),
-    Eval(Problem, ~[u64], Chan<Option<~[u64]>>),
-    EvalProgram(Program, ~[u64], Chan<Option<~[u64]>>),
-    Guess(Problem, ~str, Chan<GuessResult>),
+    Status(Chan<ApiResult<StatusResponse>>),
+    Train(u8, TrainOperator, Chan<ApiResult<TrainProblem>>),
+    Problems(Chan<ApiResult<~[RealProblem]>>),
+    Eval(Problem, ~[u64], Chan<ApiResult<~[u64]>>),
+    EvalProgram(Program, ~[u64], Chan<ApiResult<~[u64]>>),
+    Guess(Problem, ~str, Chan<ApiResult<GuessResult>>),
+    RandomTestcases(Problem, uint, Chan<ApiResult<~[(u64, u64)]>>),
+    GetBudget(Chan<Option<Budget>>),
 }
 
 pub enum TrainOperator {
@@ -418,7 +868,7 @@ impl Request {
     }
 }
 
-struct StatusResponse {
+pub struct StatusResponse {
     easy_chair_id: ~str,
     contest_score: float,
     lightning_score: float,
@@ -430,66 +880,66 @@ struct StatusResponse {
     cpu_window: Window,
 }
 
-struct Window {
+pub struct Window {
     resets_in: float,
     amount: float,
     limit: float,
 }
 
 impl Window {
-    pub fn from_json(data: Json) -> Window {
-        match data {
-            Object(ref obj) => {
-                let resets_in = get_json_num(*obj, ~"resetsIn");
-                let amount = get_json_num(*obj, ~"amount");
-                let limit = get_json_num(*obj, ~"limit");
-
-                Window {
-                    resets_in: resets_in,
-                    amount: amount,
-                    limit: limit,
-                }
-            }
-            _ => fail!("unexpected data"),
-        }
+    pub fn from_json(data: &Json, path: &str) -> Result<Window, DecodeError> {
+        let obj = match get_json_object(data, path) { Ok(o) => o, Err(e) => return Err(e) };
+        let resets_in = match get_json_num(obj, path, "resetsIn") { Ok(n) => n, Err(e) => return Err(e) };
+        let amount = match get_json_num(obj, path, "amount") { Ok(n) => n, Err(e) => return Err(e) };
+        let limit = match get_json_num(obj, path, "limit") { Ok(n) => n, Err(e) => return Err(e) };
+
+        Ok(Window {
+            resets_in: resets_in,
+            amount: amount,
+            limit: limit,
+        })
     }
 }
 
 impl StatusResponse {
-    pub fn from_json(data: Json) -> StatusResponse {
-        match data {
-            Object(ref obj) => {
-                let easy_chair_id = get_json_str(*obj, ~"easyChairId");
-                let contest_score = get_json_num(*obj, ~"contestScore");
-                let lightning_score = get_json_num(*obj, ~"lightningScore");
-                let training_score = get_json_num(*obj, ~"trainingScore");
-                let mismatches = get_json_num(*obj, ~"mismatches");
-                let num_requests = get_json_num(*obj, ~"numRequests");
-                let cpu_total_time = get_json_num(*obj, ~"cpuTotalTime");
-
-                let request_window = match obj.find(&~"requestWindow") {
-                    Some(win_data) => Window::from_json(win_data.clone()),
-                    _ => fail!("bad requestWindow"),
-                };
-                let cpu_window = match obj.find(&~"cpuWindow") {
-                    Some(win_data) => Window::from_json(win_data.clone()),
-                    _ => fail!("bad cpuWindow"),
-                };
-
-                StatusResponse {
-                    easy_chair_id: easy_chair_id,
-                    contest_score: contest_score,
-                    lightning_score: lightning_score,
-                    training_score: training_score,
-                    mismatches: mismatches,
-                    num_requests: num_requests,
-                    cpu_total_time: cpu_total_time,
-                    request_window: request_window,
-                    cpu_window: cpu_window,
-                }
+    pub fn from_json(data: &Json) -> Result<StatusResponse, DecodeError> {
+        let path = "status";
+        let obj = match get_json_object(data, path) { Ok(o) => o, Err(e) => return Err(e) };
+        let easy_chair_id = match get_json_str(obj, path, "easyChairId") { Ok(s) => s, Err(e) => return Err(e) };
+
+        let mut nums = ~[];
+        for key in ["contestScore", "lightningScore", "trainingScore", "mismatches",
+                    "numRequests", "cpuTotalTime"].iter() {
+            match get_json_num(obj, path, *key) {
+                Ok(n) => nums.push(n),
+                Err(e) => return Err(e),
             }
-            _ => fail!("unexpected data"),
         }
+
+        let request_window = match get_json_field(obj, path, "requestWindow").chain(|w| {
+            Window::from_json(w, join_path(path, "requestWindow"))
+        }) {
+            Ok(w) => w,
+            Err(e) => return Err(e),
+        };
+        let cpu_window = match get_json_field(obj, path, "cpuWindow").chain(|w| {
+            Window::from_json(w, join_path(path, "cpuWindow"))
+        }) {
+            Ok(w) => w,
+            Err(e) => return Err(e),
+        };
+
+        Ok(StatusResponse {
+            easy_chair_id: easy_chair_id,
+            contest_score: nums[0],
+            lightning_score: nums[1],
+            training_score: nums[2],
+            mismatches: nums[3],
+            num_requests: nums[4],
+            cpu_total_time: nums[5],
+            request_window: request_window,
+            cpu_window: cpu_window,
+        })
     }
 
     pub fn score_report(&self) {
@@ -532,6 +982,7 @@ impl WebEval for Problem {
     }
 }
 
+#[deriving(Clone)]
 pub enum GuessResult {
     Win,
     Mismatch(u64, u64, u64),
@@ -541,90 +992,244 @@ pub enum GuessResult {
 trait WebEval {
     fn get_id(&self) -> ~str;
 
-    fn guess(&self, prog: ~str) -> GuessResult {
+    fn guess(&self, prog: ~str, conn: &mut Conn) -> ApiResult<GuessResult> {
         let mut obj: TreeMap<~str, Json> = TreeMap::new();
         obj.insert(~"id", self.get_id().to_json());
         obj.insert(~"program", prog.to_json());
         let guess_json = obj.to_json().to_str();
 
-        let response = match post_request(make_url("guess"), guess_json) {
-            Object(o) => o,
-            _ => fail!("bad guess response")
-        };
+        let response = post_request("guess", guess_json, conn);
+        decode_reply(response, decode_guess)
+    }
 
-        match get_json_str(response, ~"status") {
-            ~"win" => Win,
-            ~"mismatch" => {
-                let vals = get_json_array(response, ~"values");
-                assert!(vals.len() == 3, "not enough values in guess.mismatch");
-                let mut parsed = do vals.iter().transform |s| {
-                    read_0x_hex(unwrap_json_str(s))
-                };
-                Mismatch(parsed.next().unwrap(),
-                         parsed.next().unwrap(),
-                         parsed.next().unwrap())
-            }
-            _ => {
-                Error(get_json_str(response, ~"message"))
-            }
+    fn eval(&self, nums: &[u64], conn: &mut Conn) -> ApiResult<~[u64]> {
+        let response = post_request("eval", eval_body(self.get_id(), nums), conn);
+        decode_eval_reply(response)
+    }
+}
+
+// What's sent to evaluate problem `id` on `nums`.
+fn eval_body(id: &str, nums: &[u64]) -> ~str {
+    let mut obj: TreeMap<~str, Json> = TreeMap::new();
+    obj.insert(~"id", id.to_json());
+
+    let args = nums.iter().transform(|i| i.to_str_radix(16)).to_owned_vec();
+    obj.insert(~"arguments", args.to_json());
+    obj.to_json().to_str()
+}
+
+fn decode_reply<T>(response: ApiResult<Json>, decode: &fn(&Json) -> Decoded<T>) -> ApiResult<T> {
+    match response {
+        Ok(j) => match decode(&j) {
+            Ok(x) => Ok(x),
+            Err(e) => Err(BadReply(e)),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// An eval's outputs, or why the server wouldn't give them.
+fn decode_eval_reply(response: ApiResult<Json>) -> ApiResult<~[u64]> {
+    match decode_reply(response, decode_eval) {
+        Ok(Ok(outs)) => Ok(outs),
+        Ok(Err(message)) => {
+            println(message);
+            Err(Refused(message))
         }
+        Err(e) => Err(e),
     }
+}
 
-    fn eval(&self, nums: &[u64]) -> Option<~[u64]> {
-        let mut obj: TreeMap<~str, Json> = TreeMap::new();
-        obj.insert(~"id", self.get_id().to_json());
+fn decode_train(j: &Json) -> Decoded<TrainProblem> {
+    let path = "train";
+    let obj = match get_json_object(j, path) { Ok(o) => o, Err(e) => return Err(e) };
+    let challenge = match get_json_str(obj, path, "challenge") { Ok(s) => s, Err(e) => return Err(e) };
+    match decode_problem(obj, path) {
+        Ok(problem) => Ok(TrainProblem { challenge: challenge, problem: problem }),
+        Err(e) => Err(e),
+    }
+}
+
+fn decode_problems(j: &Json) -> Decoded<~[RealProblem]> {
+    let path = "myproblems";
+    let list = match *j {
+        List(ref l) => l,
+        _ => return Err(DecodeError { path: path.to_owned(), message: ~"expected a list" }),
+    };
+
+    let mut probs = ~[];
+    for (i, x) in list.iter().enumerate() {
+        let path = fmt!("%s[%u]", path, i);
+        let obj = match get_json_object(x, path) { Ok(o) => o, Err(e) => return Err(e) };
+        let problem = match decode_problem(obj, path) { Ok(p) => p, Err(e) => return Err(e) };
+
+        let solved = match obj.find(&~"solved") {
+            Some(&Boolean(x)) => x,
+            None => false,
+            Some(_) => return Err(DecodeError {
+                path: join_path(path, "solved"),
+                message: ~"expected a boolean",
+            }),
+        };
+        let time_left = match obj.find(&~"timeLeft") {
+            Some(&Number(x)) => Some(x),
+            None => None,
+            Some(_) => return Err(DecodeError {
+                path: join_path(path, "timeLeft"),
+                message: ~"expected a number",
+            }),
+        };
+
+        probs.push(RealProblem {
+            problem: problem,
+            time_left: time_left,
+            solved: solved,
+        });
+    }
+    Ok(probs)
+}
+
+// The id, size and operators both /train and /myproblems give.
+fn decode_problem(obj: &Object, path: &str) -> Decoded<Problem> {
+    let id = match get_json_str(obj, path, "id") { Ok(s) => s, Err(e) => return Err(e) };
+    let size = match get_json_num(obj, path, "size") { Ok(n) => n, Err(e) => return Err(e) };
+    let array = match get_json_array(obj, path, "operators") { Ok(a) => a, Err(e) => return Err(e) };
+
+    let ops_path = join_path(path, "operators");
+    let mut str_ops = ~[];
+    for (i, op) in array.iter().enumerate() {
+        match get_str(op, fmt!("%s[%u]", ops_path, i)) {
+            Ok(s) => str_ops.push(s),
+            Err(e) => return Err(e),
+        }
+    }
+    let mut ops = OperatorSet::new();
+    match ops.add(str_ops) {
+        Ok(()) => {}
+        Err(e) => return Err(DecodeError { path: ops_path, message: e }),
+    }
 
-        let args = nums.iter().transform(|i| i.to_str_radix(16)).to_owned_vec();
-        obj.insert(~"arguments", args.to_json());
-        let eval_json = obj.to_json().to_str();
+    Ok(Problem {
+        id: id,
+        size: size as u8,
+        operators: ops,
+    })
+}
 
-        let response = match post_request(make_url("eval"), eval_json) {
-            Object(o) => o,
-            _ => fail!("bad eval response")
+// The outputs, or the server's message if it didn't evaluate them.
+fn decode_eval(j: &Json) -> Decoded<Result<~[u64], ~str>> {
+    let path = "eval";
+    let obj = match get_json_object(j, path) { Ok(o) => o, Err(e) => return Err(e) };
+    let status = match get_json_str(obj, path, "status") { Ok(s) => s, Err(e) => return Err(e) };
+    if "ok" != status {
+        return match get_json_str(obj, path, "message") {
+            Ok(message) => Ok(Err(message)),
+            Err(e) => Err(e),
         };
+    }
 
-        if "ok" == get_json_str(response, ~"status") {
-            let outs = get_json_array(response, ~"outputs");
-            Some(do outs.iter().transform |j| {
-                    read_0x_hex(unwrap_json_str(j))
-                }.to_owned_vec())
-        } else {
-            println(get_json_str(response, ~"message"));
-            None
+    let outs = match get_json_array(obj, path, "outputs") { Ok(a) => a, Err(e) => return Err(e) };
+    let outs_path = join_path(path, "outputs");
+    let mut outputs = ~[];
+    for (i, j) in outs.iter().enumerate() {
+        match get_hex(j, fmt!("%s[%u]", outs_path, i)) {
+            Ok(x) => outputs.push(x),
+            Err(e) => return Err(e),
         }
     }
+    Ok(Ok(outputs))
 }
 
-fn read_0x_hex(s: &str) -> u64 {
-    let no_0x = s.slice_from(2);
-    FromStrRadix::from_str_radix::<u64>(no_0x, 16).expect("not a hex number")
+fn decode_guess(j: &Json) -> Decoded<GuessResult> {
+    let path = "guess";
+    let obj = match get_json_object(j, path) { Ok(o) => o, Err(e) => return Err(e) };
+    let status = match get_json_str(obj, path, "status") { Ok(s) => s, Err(e) => return Err(e) };
+
+    match status {
+        ~"win" => Ok(Win),
+        ~"mismatch" => {
+            let vals = match get_json_array(obj, path, "values") { Ok(a) => a, Err(e) => return Err(e) };
+            let vals_path = join_path(path, "values");
+            if vals.len() != 3 {
+                return Err(DecodeError { path: vals_path, message: ~"expected 3 values" });
+            }
+            let mut parsed = ~[];
+            for (i, j) in vals.iter().enumerate() {
+                match get_hex(j, fmt!("%s[%u]", vals_path, i)) {
+                    Ok(x) => parsed.push(x),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Mismatch(parsed[0], parsed[1], parsed[2]))
+        }
+        _ => {
+            match get_json_str(obj, path, "message") {
+                Ok(message) => Ok(Error(message)),
+                Err(e) => Err(e),
+            }
+        }
+    }
 }
 
-fn unwrap_json_str<'a>(json: &'a Json) -> &'a str {
-    match *json {
-        String(ref s) => s.as_slice(),
-        _ => fail!("non string")
+fn join_path(path: &str, key: &str) -> ~str {
+    fmt!("%s.%s", path, key)
+}
+
+fn get_json_object<'a>(j: &'a Json, path: &str) -> Decoded<&'a Object> {
+    match *j {
+        Object(ref obj) => Ok(&**obj),
+        _ => Err(DecodeError { path: path.to_owned(), message: ~"expected an object" }),
+    }
+}
+
+fn get_json_field<'a>(obj: &'a Object, path: &str, key: &str) -> Decoded<&'a Json> {
+    match obj.find(&key.to_owned()) {
+        Some(j) => Ok(j),
+        None => Err(DecodeError { path: join_path(path, key), message: ~"missing" }),
     }
 }
 
-fn get_json_array<'a>(obj: &'a Object, key: ~str) -> ~[Json] {
-    match obj.find(&key) {
-        Some(&List(ref l)) => l.clone(),
-        _ => fail!(fmt!("unexpected type for '%s'", key)),
+fn get_json_array<'a>(obj: &'a Object, path: &str, key: &str) -> Decoded<&'a ~[Json]> {
+    match get_json_field(obj, path, key) {
+        Ok(&List(ref l)) => Ok(l),
+        Ok(_) => Err(DecodeError { path: join_path(path, key), message: ~"expected a list" }),
+        Err(e) => Err(e),
     }
 }
 
-fn get_json_num(obj: &Object, key: ~str) -> float {
-    match obj.find(&key) {
-        Some(&Number(n)) => n,
-        _ => fail!(fmt!("unexpected type for '%s'", key)),
+fn get_json_num(obj: &Object, path: &str, key: &str) -> Decoded<float> {
+    match get_json_field(obj, path, key) {
+        Ok(&Number(n)) => Ok(n),
+        Ok(_) => Err(DecodeError { path: join_path(path, key), message: ~"expected a number" }),
+        Err(e) => Err(e),
     }
 }
 
-fn get_json_str(obj: &Object, key: ~str) -> ~str {
-    match obj.find(&key) {
-        Some(&String(ref s)) => s.clone(),
-        _ => fail!(fmt!("unexpected type for '%s'", key)),
+fn get_json_str(obj: &Object, path: &str, key: &str) -> Decoded<~str> {
+    match get_json_field(obj, path, key) {
+        Ok(j) => get_str(j, join_path(path, key)),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_str(j: &Json, path: &str) -> Decoded<~str> {
+    match *j {
+        String(ref s) => Ok(s.clone()),
+        _ => Err(DecodeError { path: path.to_owned(), message: ~"expected a string" }),
+    }
+}
+
+// A "0x..." string.
+fn get_hex(j: &Json, path: &str) -> Decoded<u64> {
+    let bad = || DecodeError { path: path.to_owned(), message: ~"expected a 0x hex number" };
+    match *j {
+        String(ref s) if s.starts_with("0x") => {
+            match FromStrRadix::from_str_radix(s.slice_from(2), 16) {
+                Some(x) => Ok(x),
+                None => Err(bad()),
+            }
+        }
+        _ => Err(bad()),
     }
 }
 
@@ -637,58 +1242,408 @@ fn make_url(path: &str) -> ~str {
     s
 }
 
-fn get_request(url: ~str) -> Json {
-    let mut tries = 15;
-    while tries > 0 {
-        info!("GET /%s", extract_path(url));
-        let mut p = Process::new("curl", [~"-f", url.clone()], ProcessOptions::new());
-        let output = str::from_bytes(p.output().read_whole_stream());
-        let retval = p.finish();
-        info!("HTTP %i: %s", retval, output);
-        if retval == 0 {
-            match json::from_str(output) {
-                Ok(res) => return res,
-                Err(e) => fail!(fmt!("error: %s\n%s", e.to_str(), output)),
-            }
+// Not counted against the request window, nor retried: it's only ever
+// advice for the rate limiter.
+fn get_status(session: &mut Session) -> ApiResult<StatusResponse> {
+    info!("GET /status");
+    let (code, output) = session.send("GET", "status", "", || curl("GET", "status", ""));
+    info!("HTTP %u: %s", code, output);
+    if code != 200 {
+        return Err(RequestFailed(fmt!("HTTP %u", code)));
+    }
+    match json::from_str(output) {
+        Ok(j) => decode_reply(Ok(j), StatusResponse::from_json),
+        Err(e) => Err(RequestFailed(fmt!("bad JSON: %s", e.to_str()))),
+    }
+}
+
+fn get_request(path: &str, conn: &mut Conn) -> ApiResult<Json> {
+    info!("GET /%s", path);
+    http_request("GET", path, "", conn)
+}
+
+fn post_request(path: &str, data: ~str, conn: &mut Conn) -> ApiResult<Json> {
+    info!("POST /%s", path);
+    info!("DATA: %s", data);
+    http_request("POST", path, data, conn)
+}
+
+// Where the request task's requests go, and the limits they're kept to.
+struct Conn {
+    session: Session,
+    // a replay has no limits to keep to
+    limiter: Option<Limiter>,
+}
+
+impl Conn {
+    fn new(session: Session) -> Conn {
+        let limiter = if session.is_replay() {
+            None
         } else {
-            println("WARN: http throttled. retrying");
-            tries -= 1;
-            let timer = Timer::new().unwrap();
-            timer.sleep(4000);
+            // synced from /status before the first request
+            Some(RateLimiter::unsynced(SystemClock::new()))
+        };
+        Conn {
+            session: session,
+            limiter: limiter,
+        }
+    }
+
+    // Is there room to make a request nobody is waiting on?
+    fn has_room(&mut self, reserve: float) -> bool {
+        match self.limiter {
+            Some(ref mut limiter) => limiter.has_room(reserve),
+            None => false,
+        }
+    }
+
+    fn sync(&mut self, status: &StatusResponse) {
+        match self.limiter {
+            Some(ref mut limiter) => limiter.sync(status),
+            None => {}
+        }
+    }
+
+    fn budget(&mut self) -> Option<Budget> {
+        match self.limiter {
+            Some(ref mut limiter) => Some(limiter.budget()),
+            None => None,
+        }
+    }
+
+    // Wait until the limits allow a request, and count it.
+    fn wait(&mut self) {
+        match self.limiter {
+            Some(ref mut limiter) => {
+                if limiter.needs_sync() {
+                    match get_status(&mut self.session) {
+                        Ok(status) => limiter.sync(&status),
+                        Err(e) => printfln!("WARN: couldn't sync the rate limits: %s", e.to_str()),
+                    }
+                }
+                limiter.wait();
+            }
+            None => {}
+        }
+    }
+
+    fn throttled(&mut self) {
+        match self.limiter {
+            Some(ref mut limiter) => limiter.throttled(),
+            None => {}
         }
     }
-    fail!("ran out of retries");
 }
 
-fn post_request(url: ~str, data: ~str) -> Json {
+// Make a request when the limits allow, retrying while the server is
+// throttling us or not answering.
+fn http_request(method: &str, path: &str, body: &str, conn: &mut Conn) -> ApiResult<Json> {
     let mut tries = 15;
     while tries > 0 {
-        info!("POST /%s", extract_path(url));
-        info!("DATA: %s", data);
-        let mut p = Process::new("curl", [~"-X", ~"POST", ~"-f", url.clone(), ~"-d", data.clone()], ProcessOptions::new());
-        let output = str::from_bytes(p.output().read_whole_stream());
-        let retval = p.finish();
-        info!("HTTP %i: %s", retval, output);
-        if retval == 0 {
-            match json::from_str(output) {
-                Ok(res) => return res,
-                Err(e) => fail!(fmt!("error: %s\n%s", e.to_str(), output)),
+        conn.wait();
+
+        let (code, output) = conn.session.send(method, path, body, || curl(method, path, body));
+        info!("HTTP %u: %s", code, output);
+        match code {
+            200 => {
+                return match json::from_str(output) {
+                    Ok(res) => Ok(res),
+                    Err(e) => Err(RequestFailed(fmt!("bad JSON: %s", e.to_str()))),
+                };
             }
-        } else {
-            println("WARN: http throttled. retrying");
-            tries -= 1;
-            let timer = Timer::new().unwrap();
-            timer.sleep(4000);
+            429 => {
+                // the limiter will wait for the window to reset
+                println("WARN: http throttled. retrying");
+                conn.throttled();
+            }
+            0 | 500..599 => {
+                printfln!("WARN: http %u. retrying", code);
+                let timer = Timer::new().unwrap();
+                timer.sleep(4000);
+            }
+            _ => return Err(Refused(fmt!("HTTP %u: %s", code, output))),
         }
+        tries -= 1;
     }
-    fail!("ran out of retries");
+    Err(RequestFailed(~"ran out of retries"))
 }
 
-fn extract_path(url: &str) -> ~str {
-    let mut pieces = url.split_iter('/');
-    let path_opt = pieces.nth(3);
-    let path = path_opt.get_ref().to_owned();
-    let mut pieces = path.split_iter('?');
-    let path = pieces.nth(0);
-    path.get_ref().to_owned()
+// Run curl, for the HTTP status (0 if there was no response) and the body.
+fn curl(method: &str, path: &str, body: &str) -> (uint, ~str) {
+    let mut args = ~[~"-s", ~"-w", ~"\n%{http_code}"];
+    if method == "POST" {
+        args.push_all([~"-X", ~"POST", ~"-d", body.to_owned()]);
+    }
+    args.push(make_url(path));
+    let mut p = Process::new("curl", args, ProcessOptions::new());
+    let output = str::from_bytes(p.output().read_whole_stream());
+    p.finish();
+
+    // -w puts the status on a line of its own after the body
+    match output.rfind('\n') {
+        Some(i) => {
+            let code: Option<uint> = FromStr::from_str(output.slice_from(i + 1).trim());
+            (code.unwrap_or_default(0), output.slice_to(i).to_owned())
+        }
+        None => (0, output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{take_evals, Eval, Guess, Problem, MAX_EVAL_ARGS};
+    use super::{decode_problems, decode_guess, decode_eval, DecodeError, Mismatch};
+    use super::{dispatch, Conn, Problems, Train, Empty, Refused};
+    use super::{eval_body, random_testcases};
+    use super::{Api, ApiResult, FakeApi, Win, Error};
+    use session::{Replaying, Replay, Exchange};
+    use ratelimit::{Clock, FakeClock};
+    use parse::parse_program;
+    use program::OperatorSet;
+    use std::comm;
+    use std::vec;
+    use extra::json;
+
+    fn problem(id: &str) -> Problem {
+        Problem { id: id.to_owned(), size: 3, operators: OperatorSet::new() }
+    }
+
+    #[test]
+    fn test_take_evals() {
+        let (_p1, c1) = comm::stream();
+        let (_p2, c2) = comm::stream();
+        let (_p3, c3) = comm::stream();
+        let (_p4, c4) = comm::stream();
+        let (_p5, c5) = comm::stream();
+        let mut queue = ~[
+            Eval(problem("a"), ~[2, 3], c2),
+            Eval(problem("b"), ~[4], c3),
+            Guess(problem("a"), ~"(lambda (x) x)", c4),
+            // too many to fit
+            Eval(problem("a"), vec::from_elem(MAX_EVAL_ARGS, 0u64), c5),
+        ];
+
+        let batch = take_evals("a", (~[1], c1), &mut queue);
+        let inputs: ~[~[u64]] = batch.consume_iter().transform(|(xs, _)| xs).collect();
+        assert_eq!(inputs, ~[~[1], ~[2, 3]]);
+
+        assert_eq!(queue.len(), 3);
+        match queue[0] {
+            Eval(ref p, _, _) => assert_eq!(p.id, ~"b"),
+            _ => fail!("queue out of order"),
+        }
+        match queue[2] {
+            Eval(_, ref xs, _) => assert_eq!(xs.len(), MAX_EVAL_ARGS),
+            _ => fail!("queue out of order"),
+        }
+    }
+
+    #[test]
+    fn test_decode_problems() {
+        let j = json::from_str(
+            "[{\"id\": \"a\", \"size\": 5, \"operators\": [\"not\", \"fold\"]},
+              {\"id\": \"b\", \"size\": 8, \"operators\": [\"if0\"],
+               \"solved\": false, \"timeLeft\": 12.5}]").unwrap();
+        let probs = decode_problems(&j).unwrap();
+        assert_eq!(probs.len(), 2);
+        assert_eq!(probs[0].problem.size, 5);
+        assert_eq!(probs[0].time_left, None);
+        assert_eq!(probs[1].time_left, Some(12.5));
+
+        let j = json::from_str(
+            "[{\"id\": \"a\", \"size\": 5, \"operators\": [\"not\"]},
+              {\"id\": \"b\", \"size\": 8, \"operators\": [\"not\", 3]}]").unwrap();
+        assert_eq!(decode_problems(&j),
+                   Err(DecodeError { path: ~"myproblems[1].operators[1]",
+                                     message: ~"expected a string" }));
+
+        let j = json::from_str("[{\"id\": \"a\", \"operators\": []}]").unwrap();
+        assert_eq!(decode_problems(&j).unwrap_err().path, ~"myproblems[0].size");
+    }
+
+    #[test]
+    fn test_decode_replies() {
+        let j = json::from_str(
+            "{\"status\": \"mismatch\", \"values\": [\"0x1\", \"0x2\", \"0xFF\"]}").unwrap();
+        match decode_guess(&j) {
+            Ok(Mismatch(1, 2, 255)) => {}
+            _ => fail!("bad mismatch"),
+        }
+
+        let j = json::from_str("{\"status\": \"mismatch\", \"values\": [\"0x1\"]}").unwrap();
+        assert_eq!(decode_guess(&j).unwrap_err().path, ~"guess.values");
+
+        let j = json::from_str("{\"status\": \"ok\", \"outputs\": [\"0x10\", \"12\"]}").unwrap();
+        assert_eq!(decode_eval(&j).unwrap_err().path, ~"eval.outputs[1]");
+
+        let j = json::from_str("{\"status\": \"error\", \"message\": \"too late\"}").unwrap();
+        assert_eq!(decode_eval(&j), Ok(Err(~"too late")));
+    }
+
+    fn replay(exchanges: ~[(&str, &str, ~str, &str)]) -> Conn {
+        let exchanges = do exchanges.map |&(method, path, ref body, response)| {
+            Exchange {
+                method: method.to_owned(),
+                path: path.to_owned(),
+                body: body.clone(),
+                status: 200,
+                response: response.to_owned(),
+            }
+        };
+        Conn::new(Replaying(Replay::new(exchanges)))
+    }
+
+    #[test]
+    fn test_dispatch_replayed() {
+        let (_, chan) = comm::stream();
+        let train_body = Train(3, Empty, chan).to_json_str();
+        let mut conn = replay(~[
+            ("GET", "myproblems", ~"",
+             "[{\"id\": \"a\", \"size\": 5, \"operators\": [\"not\"]}]"),
+            ("POST", "train", train_body.clone(),
+             "{\"id\": \"t\", \"size\": 3, \"operators\": [],
+               \"challenge\": \"(lambda (x) x)\"}"),
+            ("POST", "train", train_body,
+             "{\"id\": \"u\", \"size\": 3, \"operators\": [],
+               \"challenge\": \"(lambda (x) x)\"}"),
+        ]);
+
+        let (port, chan) = comm::stream();
+        dispatch(Problems(chan), &mut conn);
+        let probs = port.recv().unwrap();
+        assert_eq!(probs.len(), 1);
+        assert_eq!(probs[0].problem.id, ~"a");
+
+        let (port, chan) = comm::stream();
+        dispatch(Train(3, Empty, chan), &mut conn);
+        assert_eq!(port.recv().unwrap().challenge, ~"(lambda (x) x)");
+
+        // a different request to the same endpoint isn't answered with
+        // what was recorded for another
+        let (port, chan) = comm::stream();
+        dispatch(Train(4, Empty, chan), &mut conn);
+        match port.recv() {
+            Err(Refused(_)) => {}
+            _ => fail!("expected a refusal"),
+        }
+
+        // nothing left to answer with
+        let (port, chan) = comm::stream();
+        dispatch(Problems(chan), &mut conn);
+        match port.recv() {
+            Err(Refused(_)) => {}
+            _ => fail!("expected a refusal"),
+        }
+    }
+
+    #[test]
+    fn test_random_testcases_replayed() {
+        // the inputs come from the recording, not the rng, or the
+        // recorded answer couldn't be found
+        let mut conn = replay(~[
+            ("POST", "eval", eval_body("a", [1, 0xff]),
+             "{\"status\": \"ok\", \"outputs\": [\"0x2\", \"0x100\"]}"),
+        ]);
+        let mut spares = ~[];
+        assert_eq!(random_testcases(problem("a"), 1, &mut spares, &mut conn).unwrap(),
+                   ~[(1, 2)]);
+        // more than were recorded gets what there is
+        assert_eq!(random_testcases(problem("a"), 5, &mut spares, &mut conn).unwrap(),
+                   ~[(0xff, 0x100)]);
+        match random_testcases(problem("a"), 1, &mut spares, &mut conn) {
+            Err(Refused(_)) => {}
+            _ => fail!("expected a refusal"),
+        }
+    }
+
+    static SEC: u64 = 1_000_000_000;
+
+    fn fake(progs: &[&str]) -> FakeApi {
+        let progs = progs.map(|&s| parse_program(s).unwrap());
+        FakeApi::with_clock(progs, ~FakeClock::new() as ~Clock)
+    }
+
+    fn refusal<T>(result: ApiResult<T>) -> ~str {
+        match result {
+            Err(Refused(msg)) => msg,
+            _ => fail!("expected a refusal"),
+        }
+    }
+
+    #[test]
+    fn test_fake_deadline() {
+        let mut api = fake(["(lambda (x) (not x))"]);
+        let probs = api.get_problems_blocking().unwrap();
+        assert_eq!(probs[0].time_left, None);
+        let p = probs[0].problem.clone();
+
+        // the clock starts at the first eval
+        assert_eq!(api.eval_blocking(p.clone(), ~[0]).unwrap(), ~[!0]);
+        api.advance_ns(100 * SEC);
+        let probs = api.get_problems_blocking().unwrap();
+        assert_eq!(probs[0].time_left, Some(200f));
+        assert!(!probs[0].solved);
+
+        api.advance_ns(201 * SEC);
+        assert!(refusal(api.eval_blocking(p.clone(), ~[0])).starts_with("HTTP 410"));
+        assert!(api.eval_blocking(p.clone(), ~[0]).unwrap_err().is_expired());
+        assert_eq!(api.get_problems_blocking().unwrap()[0].time_left, Some(0f));
+        assert!(refusal(api.eval_blocking(problem("nope"), ~[0])).starts_with("HTTP 404"));
+    }
+
+    #[test]
+    fn test_fake_throttled() {
+        let mut api = fake(["(lambda (x) x)"]);
+        let p = api.get_problems_blocking().unwrap()[0].problem.clone();
+        for _ in range(0, 4) {
+            api.eval_blocking(p.clone(), ~[1]).unwrap();
+        }
+        assert_eq!(api.now_ns(), 0);
+
+        // the sixth request waits for the window to reset
+        api.eval_blocking(p.clone(), ~[1]).unwrap();
+        assert_eq!(api.now_ns(), 20 * SEC);
+        assert_eq!(api.stats().throttled_ns, 20 * SEC);
+
+        // two requests' worth
+        assert_eq!(api.random_testcases_blocking(p.clone(), MAX_EVAL_ARGS + 1).unwrap().len(),
+                   MAX_EVAL_ARGS + 1);
+        assert_eq!(api.stats().requests, 8);
+
+        // a fresh window has room for five more straight away
+        api.fresh_window();
+        for _ in range(0, 5) {
+            api.eval_blocking(p.clone(), ~[1]).unwrap();
+        }
+        assert_eq!(api.now_ns(), 20 * SEC);
+        assert!(refusal(api.eval_blocking(p, vec::from_elem(MAX_EVAL_ARGS + 1, 0u64)))
+                .starts_with("HTTP 400"));
+    }
+
+    #[test]
+    fn test_fake_guess() {
+        let mut api = fake(["(lambda (x) (plus x x))"]);
+        let p = api.get_problems_blocking().unwrap()[0].problem.clone();
+
+        match api.guess_blocking(p.clone(), ~"(lambda (x) (shl1 x))") {
+            Ok(Error(msg)) => assert!(msg.contains("shl1")),
+            _ => fail!("shl1 isn't in the problem"),
+        }
+        match api.guess_blocking(p.clone(), ~"(lambda (x) (plus x 1))") {
+            Ok(Mismatch(x, expected, ours)) => {
+                assert_eq!(expected, x + x);
+                assert_eq!(ours, x + 1);
+            }
+            _ => fail!("expected a mismatch"),
+        }
+        match api.guess_blocking(p.clone(), ~"(lambda (x) (plus (plus x 0) x))") {
+            Ok(Win) => {}
+            _ => fail!("expected a win"),
+        }
+
+        assert!(api.get_problems_blocking().unwrap()[0].solved);
+        assert!(refusal(api.eval_blocking(p, ~[0])).starts_with("HTTP 412"));
+        let stats = api.stats();
+        assert_eq!((stats.guesses, stats.wins, stats.mismatches), (3, 1, 1));
+    }
 }